@@ -0,0 +1,6 @@
+DROP TABLE role_grants;
+
+ALTER TABLE instructor_locations
+    DROP CONSTRAINT assignment_window,
+    DROP COLUMN valid_until,
+    DROP COLUMN valid_from;
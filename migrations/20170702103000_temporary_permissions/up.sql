@@ -0,0 +1,17 @@
+ALTER TABLE instructor_locations
+    ADD COLUMN valid_from  TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN valid_until TIMESTAMPTZ,
+    ADD CONSTRAINT assignment_window CHECK (valid_until IS NULL OR valid_from < valid_until);
+
+CREATE TABLE role_grants (
+    id BIGSERIAL PRIMARY KEY,
+
+    user_id     BIGINT      NOT NULL REFERENCES users ON UPDATE CASCADE ON DELETE CASCADE,
+    role        BIGINT      NOT NULL,
+    valid_from  TIMESTAMPTZ NOT NULL DEFAULT now(),
+    valid_until TIMESTAMPTZ NOT NULL,
+
+    CONSTRAINT grant_window CHECK (valid_from < valid_until)
+);
+
+CREATE INDEX role_grants_user_id ON role_grants (user_id);
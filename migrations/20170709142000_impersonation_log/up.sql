@@ -0,0 +1,8 @@
+CREATE TABLE impersonation_log (
+    id BIGSERIAL PRIMARY KEY,
+
+    admin_id   BIGINT      NOT NULL REFERENCES users ON UPDATE CASCADE ON DELETE CASCADE,
+    user_id    BIGINT      NOT NULL REFERENCES users ON UPDATE CASCADE ON DELETE CASCADE,
+    query      TEXT        NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
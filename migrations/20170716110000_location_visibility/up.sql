@@ -0,0 +1 @@
+ALTER TABLE locations ADD COLUMN public BOOLEAN NOT NULL DEFAULT TRUE;
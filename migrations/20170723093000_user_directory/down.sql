@@ -0,0 +1,7 @@
+DROP INDEX users_registered;
+DROP INDEX users_training_location;
+
+ALTER TABLE users
+    DROP CONSTRAINT belt_range,
+    DROP COLUMN registered,
+    DROP COLUMN belt;
@@ -0,0 +1,7 @@
+ALTER TABLE users
+    ADD COLUMN belt       BIGINT      NOT NULL DEFAULT 0,
+    ADD COLUMN registered TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD CONSTRAINT belt_range CHECK (belt BETWEEN 0 AND 5);
+
+CREATE INDEX users_training_location ON users (training_location);
+CREATE INDEX users_registered ON users (registered);
@@ -0,0 +1 @@
+DROP TABLE persisted_queries;
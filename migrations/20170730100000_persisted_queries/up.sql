@@ -0,0 +1,5 @@
+CREATE TABLE persisted_queries (
+    hash       TEXT        PRIMARY KEY CHECK (hash ~ '^[0-9a-f]{64}$'),
+    query      TEXT        NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
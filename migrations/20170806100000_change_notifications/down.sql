@@ -0,0 +1,4 @@
+DROP TRIGGER instructor_locations_changed ON instructor_locations;
+DROP TRIGGER locations_changed ON locations;
+DROP TRIGGER users_changed ON users;
+DROP FUNCTION notify_change();
@@ -0,0 +1,38 @@
+CREATE OR REPLACE FUNCTION notify_change() RETURNS trigger AS $$
+DECLARE
+    new_row JSONB := CASE WHEN TG_OP = 'DELETE' THEN NULL ELSE to_jsonb(NEW) END;
+    old_row JSONB := CASE WHEN TG_OP = 'INSERT' THEN NULL ELSE to_jsonb(OLD) END;
+    changed JSONB := COALESCE(new_row, old_row);
+    payload JSONB;
+BEGIN
+    payload := jsonb_build_object(
+        'table', TG_TABLE_NAME,
+        'operation', TG_OP,
+        'id', changed->'id'
+    );
+
+    IF TG_TABLE_NAME = 'users' THEN
+        payload := payload || jsonb_build_object(
+            'user', changed->'id',
+            'location', new_row->'training_location',
+            'old_location', old_row->'training_location'
+        );
+    ELSIF TG_TABLE_NAME = 'locations' THEN
+        payload := payload || jsonb_build_object(
+            'location', changed->'id',
+            'public', changed->'public'
+        );
+    ELSIF TG_TABLE_NAME = 'instructor_locations' THEN
+        payload := payload || jsonb_build_object(
+            'user', changed->'instructor_id',
+            'location', changed->'location_id'
+        );
+    END IF;
+
+    PERFORM pg_notify('changes', payload::TEXT);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE locations DROP COLUMN uuid;
+ALTER TABLE users DROP COLUMN uuid;
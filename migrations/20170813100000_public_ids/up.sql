@@ -0,0 +1,48 @@
+-- Users and locations are known to the outside world by random UUIDs, so
+-- their IDs don't give away how many there are. The serial IDs stay as the
+-- keys, so nothing that refers to them has to change.
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+ALTER TABLE users ADD COLUMN uuid UUID NOT NULL UNIQUE DEFAULT gen_random_uuid();
+ALTER TABLE locations ADD COLUMN uuid UUID NOT NULL UNIQUE DEFAULT gen_random_uuid();
+
+-- Changes carry the UUIDs too, since deleted rows can't be looked up.
+CREATE OR REPLACE FUNCTION notify_change() RETURNS trigger AS $$
+DECLARE
+    new_row JSONB := CASE WHEN TG_OP = 'DELETE' THEN NULL ELSE to_jsonb(NEW) END;
+    old_row JSONB := CASE WHEN TG_OP = 'INSERT' THEN NULL ELSE to_jsonb(OLD) END;
+    changed JSONB := COALESCE(new_row, old_row);
+    payload JSONB;
+BEGIN
+    payload := jsonb_build_object(
+        'table', TG_TABLE_NAME,
+        'operation', TG_OP,
+        'id', changed->'id'
+    );
+
+    IF TG_TABLE_NAME = 'users' THEN
+        payload := payload || jsonb_build_object(
+            'user', changed->'id',
+            'user_uuid', changed->'uuid',
+            'location', new_row->'training_location',
+            'old_location', old_row->'training_location'
+        );
+    ELSIF TG_TABLE_NAME = 'locations' THEN
+        payload := payload || jsonb_build_object(
+            'location', changed->'id',
+            'location_uuid', changed->'uuid',
+            'public', changed->'public'
+        );
+    ELSIF TG_TABLE_NAME = 'instructor_locations' THEN
+        payload := payload || jsonb_build_object(
+            'user', changed->'instructor_id',
+            'user_uuid', (SELECT uuid FROM users WHERE id = (changed->>'instructor_id')::BIGINT),
+            'location', changed->'location_id',
+            'location_uuid', (SELECT uuid FROM locations WHERE id = (changed->>'location_id')::BIGINT)
+        );
+    END IF;
+
+    PERFORM pg_notify('changes', payload::TEXT);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,20 @@
+-- Emails waiting to be sent, or that were sent or given up on. They're
+-- filled in before they get here, so sending them doesn't need templates.
+CREATE TABLE outbox (
+    id BIGSERIAL PRIMARY KEY,
+
+    recipient    TEXT        NOT NULL,
+    subject      TEXT        NOT NULL,
+    html         TEXT        NOT NULL,
+    text         TEXT        NOT NULL,
+    status       TEXT        NOT NULL DEFAULT 'pending'
+                             CHECK (status IN ('pending', 'sent', 'failed')),
+    attempts     INTEGER     NOT NULL DEFAULT 0,
+    last_error   TEXT,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT now(),
+    next_attempt TIMESTAMPTZ NOT NULL DEFAULT now(),
+    sent_at      TIMESTAMPTZ
+);
+
+CREATE INDEX outbox_due ON outbox (next_attempt) WHERE status = 'pending';
+CREATE INDEX outbox_failed ON outbox (created_at) WHERE status = 'failed';
@@ -0,0 +1,16 @@
+-- Messages for a location's students, everyone with at least a role, or
+-- everybody if neither is given.
+CREATE TABLE announcements (
+    id BIGSERIAL PRIMARY KEY,
+
+    author_id   BIGINT      REFERENCES users ON UPDATE CASCADE ON DELETE SET NULL,
+    title       TEXT        NOT NULL CHECK (title <> ''),
+    body        TEXT        NOT NULL CHECK (body <> ''),
+    location_id BIGINT      REFERENCES locations ON UPDATE CASCADE ON DELETE CASCADE,
+    role        BIGINT,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT announcement_audience CHECK (location_id IS NULL OR role IS NULL)
+);
+
+CREATE INDEX announcements_created_at ON announcements (created_at);
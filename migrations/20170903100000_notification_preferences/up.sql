@@ -0,0 +1,11 @@
+-- Which kinds of optional emails people want. Without a row, they do.
+CREATE TABLE notification_preferences (
+    user_id  BIGINT  NOT NULL REFERENCES users ON UPDATE CASCADE ON DELETE CASCADE,
+    category TEXT    NOT NULL,
+    enabled  BOOLEAN NOT NULL,
+
+    PRIMARY KEY (user_id, category)
+);
+
+-- Where to turn off emails like this one, if they're optional.
+ALTER TABLE outbox ADD COLUMN unsubscribe TEXT;
@@ -0,0 +1,3 @@
+-- The language people would like emails and messages in.
+ALTER TABLE users ADD COLUMN locale TEXT NOT NULL DEFAULT 'en'
+    CHECK (locale IN ('en', 'es', 'ko'));
@@ -0,0 +1,2 @@
+DROP TABLE class_cancellations;
+DROP TABLE classes;
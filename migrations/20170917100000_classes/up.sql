@@ -0,0 +1,28 @@
+-- Classes that happen every week at a location, from one day until
+-- (optionally) another. Sessions aren't stored, just worked out from these.
+CREATE TABLE classes (
+    id BIGSERIAL PRIMARY KEY,
+
+    location_id   BIGINT  NOT NULL REFERENCES locations ON UPDATE CASCADE ON DELETE CASCADE,
+    instructor_id BIGINT  REFERENCES users ON UPDATE CASCADE ON DELETE SET NULL,
+    title         TEXT    NOT NULL CHECK (title <> ''),
+    level         TEXT    NOT NULL CHECK (level IN ('all', 'beginner', 'intermediate', 'advanced')),
+    weekday       INTEGER NOT NULL CHECK (weekday BETWEEN 1 AND 7), -- Monday is 1.
+    starts_at     TIME    NOT NULL,
+    duration      INTEGER NOT NULL CHECK (duration > 0), -- In minutes.
+    valid_from    DATE    NOT NULL DEFAULT CURRENT_DATE,
+    valid_until   DATE,
+
+    CONSTRAINT class_window CHECK (valid_until IS NULL OR valid_until >= valid_from)
+);
+
+CREATE INDEX classes_location_id ON classes (location_id);
+
+-- Days a class isn't happening, like holidays.
+CREATE TABLE class_cancellations (
+    class_id BIGINT NOT NULL REFERENCES classes ON UPDATE CASCADE ON DELETE CASCADE,
+    date     DATE   NOT NULL,
+    reason   TEXT,
+
+    PRIMARY KEY (class_id, date)
+);
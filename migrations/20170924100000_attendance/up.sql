@@ -0,0 +1,12 @@
+-- Who came to each session of a class.
+CREATE TABLE attendance (
+    class_id  BIGINT      NOT NULL REFERENCES classes ON UPDATE CASCADE ON DELETE CASCADE,
+    date      DATE        NOT NULL,
+    user_id   BIGINT      NOT NULL REFERENCES users ON UPDATE CASCADE ON DELETE CASCADE,
+    marked_by BIGINT      REFERENCES users ON UPDATE CASCADE ON DELETE SET NULL, -- NULL if they checked in.
+    marked_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    PRIMARY KEY (class_id, date, user_id)
+);
+
+CREATE INDEX attendance_user_id ON attendance (user_id, date);
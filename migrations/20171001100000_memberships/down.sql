@@ -0,0 +1,5 @@
+DROP TABLE payments;
+DROP TABLE invoices;
+DROP TABLE membership_members;
+DROP TABLE memberships;
+DROP TABLE membership_plans;
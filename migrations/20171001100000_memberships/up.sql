@@ -0,0 +1,70 @@
+-- What people can pay for. Prices are in cents, charged every period.
+CREATE TABLE membership_plans (
+    id BIGSERIAL PRIMARY KEY,
+
+    name        TEXT    NOT NULL CHECK (name <> ''),
+    kind        TEXT    NOT NULL CHECK (kind IN ('monthly', 'term', 'family')),
+    price       BIGINT  NOT NULL CHECK (price >= 0),
+    currency    TEXT    NOT NULL DEFAULT 'AUD' CHECK (currency ~ '^[A-Z]{3}$'),
+    months      INTEGER NOT NULL CHECK (months > 0), -- How long a period is.
+    max_members INTEGER NOT NULL DEFAULT 1 CHECK (max_members > 0),
+    active      BOOLEAN NOT NULL DEFAULT TRUE -- Whether people can still join.
+);
+
+-- Someone paying for a plan, for themselves or their family.
+-- (Not called subscriptions, which are already something else.)
+CREATE TABLE memberships (
+    id BIGSERIAL PRIMARY KEY,
+
+    plan_id   BIGINT NOT NULL REFERENCES membership_plans ON UPDATE CASCADE ON DELETE RESTRICT,
+    payer_id  BIGINT NOT NULL REFERENCES users ON UPDATE CASCADE ON DELETE CASCADE,
+    starts_on DATE   NOT NULL DEFAULT CURRENT_DATE,
+    ends_on   DATE,
+
+    CONSTRAINT membership_window CHECK (ends_on IS NULL OR ends_on >= starts_on)
+);
+
+CREATE INDEX memberships_payer_id ON memberships (payer_id);
+
+-- Who trains under a membership.
+CREATE TABLE membership_members (
+    membership_id BIGINT NOT NULL REFERENCES memberships ON UPDATE CASCADE ON DELETE CASCADE,
+    user_id       BIGINT NOT NULL REFERENCES users ON UPDATE CASCADE ON DELETE CASCADE,
+
+    PRIMARY KEY (membership_id, user_id)
+);
+
+CREATE INDEX membership_members_user_id ON membership_members (user_id);
+
+-- What's owed for one period of a membership. Paid once its payments add up.
+CREATE TABLE invoices (
+    id BIGSERIAL PRIMARY KEY,
+
+    membership_id BIGINT      NOT NULL REFERENCES memberships ON UPDATE CASCADE ON DELETE CASCADE,
+    amount        BIGINT      NOT NULL CHECK (amount >= 0),
+    currency      TEXT        NOT NULL,
+    period_start  DATE        NOT NULL,
+    period_end    DATE        NOT NULL, -- Exclusive.
+    due_on        DATE        NOT NULL,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT now(),
+    paid_at       TIMESTAMPTZ,
+
+    CONSTRAINT invoice_period CHECK (period_end > period_start),
+    CONSTRAINT duplicate_invoices UNIQUE (membership_id, period_start)
+);
+
+CREATE INDEX invoices_unpaid ON invoices (due_on) WHERE paid_at IS NULL;
+
+-- Money received for an invoice, in person or through a provider.
+CREATE TABLE payments (
+    id BIGSERIAL PRIMARY KEY,
+
+    invoice_id  BIGINT      NOT NULL REFERENCES invoices ON UPDATE CASCADE ON DELETE CASCADE,
+    amount      BIGINT      NOT NULL CHECK (amount > 0),
+    provider    TEXT, -- NULL if it was recorded by hand.
+    reference   TEXT,
+    recorded_by BIGINT      REFERENCES users ON UPDATE CASCADE ON DELETE SET NULL,
+    paid_at     TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT duplicate_payments UNIQUE (provider, reference)
+);
@@ -0,0 +1,8 @@
+-- The secrets in the URLs of users' calendar feeds. Made the first time
+-- they're asked for, and replaced when a user wants to shut out whoever
+-- has the old link.
+CREATE TABLE calendar_tokens (
+    user_id    BIGINT      PRIMARY KEY REFERENCES users ON UPDATE CASCADE ON DELETE CASCADE,
+    token      BYTEA       NOT NULL CHECK (length(token) = 32),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,2 @@
+DROP FUNCTION in_time_zone(TEXT, TIMESTAMP[]);
+ALTER TABLE locations DROP COLUMN time_zone;
@@ -0,0 +1,13 @@
+-- Classes' start times are in their location's time zone, so they stay at
+-- the same time of day when the clocks change. Locations that were already
+-- around keep UTC, which their classes' times were entered in.
+ALTER TABLE locations ADD COLUMN time_zone TEXT NOT NULL DEFAULT 'UTC';
+
+-- When each of the local times in the zone happens, in the same order.
+CREATE FUNCTION in_time_zone(zone TEXT, times TIMESTAMP[]) RETURNS TIMESTAMPTZ[] AS $$
+    SELECT ARRAY(
+        SELECT t AT TIME ZONE zone
+        FROM unnest(times) WITH ORDINALITY AS x(t, n)
+        ORDER BY n
+    )
+$$ LANGUAGE SQL STABLE;
@@ -0,0 +1,7 @@
+ALTER TABLE outbox DROP COLUMN uuid;
+ALTER TABLE announcements DROP COLUMN uuid;
+ALTER TABLE payments DROP COLUMN uuid;
+ALTER TABLE invoices DROP COLUMN uuid;
+ALTER TABLE memberships DROP COLUMN uuid;
+ALTER TABLE membership_plans DROP COLUMN uuid;
+ALTER TABLE classes DROP COLUMN uuid;
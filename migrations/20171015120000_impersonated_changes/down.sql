@@ -0,0 +1,17 @@
+DROP TRIGGER calendar_tokens_impersonated ON calendar_tokens;
+DROP TRIGGER payments_impersonated ON payments;
+DROP TRIGGER invoices_impersonated ON invoices;
+DROP TRIGGER membership_members_impersonated ON membership_members;
+DROP TRIGGER memberships_impersonated ON memberships;
+DROP TRIGGER membership_plans_impersonated ON membership_plans;
+DROP TRIGGER attendance_impersonated ON attendance;
+DROP TRIGGER class_cancellations_impersonated ON class_cancellations;
+DROP TRIGGER classes_impersonated ON classes;
+DROP TRIGGER notification_preferences_impersonated ON notification_preferences;
+DROP TRIGGER announcements_impersonated ON announcements;
+DROP TRIGGER role_grants_impersonated ON role_grants;
+DROP TRIGGER instructor_locations_impersonated ON instructor_locations;
+DROP TRIGGER locations_impersonated ON locations;
+DROP TRIGGER users_impersonated ON users;
+DROP FUNCTION record_impersonation();
+DROP TABLE impersonated_changes;
@@ -0,0 +1,94 @@
+-- The rows an admin changed while viewing the site as somebody else, since
+-- the query text alone doesn't say what it touched. The server sets
+-- ttkkdd.impersonation to the query's log entry while running it, and
+-- every change made meanwhile is put down to it.
+CREATE TABLE impersonated_changes (
+    id BIGSERIAL PRIMARY KEY,
+
+    log_id     BIGINT      NOT NULL REFERENCES impersonation_log ON UPDATE CASCADE ON DELETE CASCADE,
+    table_name TEXT        NOT NULL,
+    operation  TEXT        NOT NULL,
+    row_key    JSONB       NOT NULL, -- The ID, or the whole row if it hasn't got one.
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE FUNCTION record_impersonation() RETURNS trigger AS $$
+DECLARE
+    entry TEXT := current_setting('ttkkdd.impersonation', true);
+    changed JSONB := to_jsonb(CASE WHEN TG_OP = 'DELETE' THEN OLD ELSE NEW END);
+BEGIN
+    IF entry IS NULL OR entry = '' THEN
+        RETURN NULL;
+    END IF;
+
+    INSERT INTO impersonated_changes (log_id, table_name, operation, row_key)
+    VALUES (
+        entry::BIGINT,
+        TG_TABLE_NAME,
+        TG_OP,
+        CASE WHEN changed ? 'id' THEN jsonb_build_object('id', changed->'id')
+             ELSE changed - 'token' END
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER users_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON users
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER locations_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON locations
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER instructor_locations_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON instructor_locations
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER role_grants_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON role_grants
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER announcements_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON announcements
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER notification_preferences_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON notification_preferences
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER classes_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON classes
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER class_cancellations_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON class_cancellations
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER attendance_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON attendance
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER membership_plans_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON membership_plans
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER memberships_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON memberships
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER membership_members_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON membership_members
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER invoices_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON invoices
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER payments_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON payments
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
+
+CREATE TRIGGER calendar_tokens_impersonated
+    AFTER INSERT OR UPDATE OR DELETE ON calendar_tokens
+    FOR EACH ROW EXECUTE PROCEDURE record_impersonation();
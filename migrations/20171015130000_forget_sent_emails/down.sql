@@ -0,0 +1,2 @@
+-- What was cleared can't come back.
+SELECT 1;
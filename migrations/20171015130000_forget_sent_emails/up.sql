@@ -0,0 +1,3 @@
+-- Sent emails only keep their headers, since their bodies can have reset
+-- links in them. The server clears them from now on as it sends them.
+UPDATE outbox SET html = '', text = '' WHERE status = 'sent';
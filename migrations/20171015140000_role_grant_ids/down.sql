@@ -0,0 +1 @@
+ALTER TABLE role_grants DROP COLUMN uuid;
@@ -0,0 +1,2 @@
+-- Role grants were the last thing still handed out by serial ID.
+ALTER TABLE role_grants ADD COLUMN uuid UUID NOT NULL UNIQUE DEFAULT gen_random_uuid();
@@ -12,10 +12,13 @@ permissions! {
 // - has_role(ROLE): The user's rank needs to meet or exceed the given rank.
 // - own: The thing the user's trying to affect belongs to them.
 // - own_student: The thing the user's trying to affect belongs to one of their students.
+// - own_location: The location the user's trying to affect is where they train or instruct.
+// - instructs: The location the user's trying to affect is where they instruct.
 // - any(CONDITION, ...): One or more of the given conditions needs to be met.
 // - all(CONDITION, ...): All of the given conditions need to be met.
 // - anyone: Use this if there are no conditions to be met.
 // Note: `own` and `own_student` only make sense in the context of modifying user data. 
+// Whenever you change something here, update `permissions.test` to match.
 
 [ create_user has_role(admin) ]
 [ delete_user has_role(admin) ]
@@ -32,9 +35,22 @@ permissions! {
 [ edit_password any(own, has_role(admin)) ]
 [ read_role any(own, has_role(admin)) ]
 [ edit_role has_role(admin) ]
+[ impersonate has_role(admin) ]
+[ run_ad_hoc_query has_role(admin) ]
+[ read_email_outbox has_role(admin) ]
+[ read_belt anyone ]
+[ edit_belt any(own_student, has_role(admin)) ]
+[ read_registration_date any(own, has_role(admin)) ]
+[ read_notification_preferences any(own, has_role(admin)) ]
+[ edit_notification_preferences any(own, has_role(admin)) ]
+[ read_locale any(own, has_role(admin)) ]
+[ edit_locale any(own, has_role(admin)) ]
 
 [ create_location has_role(admin) ]
 [ read_location_info anyone ]
+[ read_location_address anyone ]
+[ read_location_coordinates anyone ]
+[ read_private_location any(own_location, has_role(admin)) ]
 [ edit_location_info has_role(admin) ]
 [ delete_location has_role(admin) ]
 
@@ -42,4 +58,24 @@ permissions! {
 [ edit_students any(own_student, has_role(admin)) ]
 [ read_instructors anyone ]
 [ edit_instructors has_role(admin) ]
+
+[ send_announcement any(instructs, has_role(admin)) ]
+
+[ read_timetable anyone ]
+[ edit_timetable any(instructs, has_role(admin)) ]
+[ read_attendance any(own, own_student, has_role(admin)) ]
+[ edit_attendance any(own_student, has_role(admin)) ]
+[ read_class_attendance any(instructs, has_role(admin)) ]
+[ read_check_in_code any(instructs, has_role(admin)) ]
+[ check_in has_role(member) ]
+[ read_calendar_url any(own, has_role(admin)) ]
+[ edit_calendar_url any(own, has_role(admin)) ]
+
+[ read_membership_plans anyone ]
+[ edit_membership_plans has_role(admin) ]
+[ read_memberships any(own, has_role(admin)) ]
+[ edit_memberships has_role(admin) ]
+[ record_payment has_role(admin) ]
+[ pay_invoice any(own, has_role(admin)) ]
+[ read_overdue_members any(instructs, has_role(admin)) ]
 }
@@ -1,18 +1,22 @@
 use base64;
 use byteorder::{ByteOrder, LittleEndian};
-use chrono::{Datelike, Date, DateTime, Duration, UTC};
+use chrono::{Datelike, Date, DateTime, Duration, NaiveDate, UTC};
 use diesel;
 use diesel::pg::PgConnection;
+use diesel::pg::upsert::*;
 use diesel::prelude::*;
 use iron::headers::{Header, HeaderFormat};
 use iron::error::HttpError;
 use bincode;
 use sodiumoxide::crypto::auth::hmacsha256 as auth;
 use sodiumoxide::crypto::secretbox;
+use sodiumoxide::randombytes;
+use sodiumoxide::utils::memcmp;
 use sodiumoxide::crypto::pwhash::{
     self,
     HashedPassword
 };
+use schema::calendar_tokens;
 use std::{fmt, str};
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -24,9 +28,18 @@ pub struct Credentials {
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub struct Cookie {
     pub id: i64,
+    pub impersonator: Option<i64>, // The admin acting as this user, if any.
     pub expiry: DateTime<UTC>
 }
 
+/// How cookies were laid out before impersonation, so that people who
+/// logged in before then stay logged in.
+#[derive(Deserialize)]
+struct OldCookie {
+    id: i64,
+    expiry: DateTime<UTC>
+}
+
 const SCHEME: &'static str = "HELLO";
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -67,18 +80,14 @@ impl Header for SealedCookie {
 
 impl HeaderFormat for SealedCookie {
     fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let bytes = bincode::serialize(
-            &self,
-            bincode::Infinite
-        ).unwrap();
-
-        write!(f, "{} {}", SCHEME, base64::encode(&*bytes))
+        write!(f, "{} {}", SCHEME, self.encode())
     }
 }
 
 pub struct ResetInfo {
     pub id: i64,
     pub username: String,
+    pub locale: String,
     pub date: Date<UTC>,
     pub mac: [u8; 32]
 }
@@ -99,6 +108,15 @@ impl Cookie {
     pub fn fresh(id: i64, duration: Duration) -> Cookie {
         Cookie {
             id: id,
+            impersonator: None,
+            expiry: UTC::now() + duration
+        }
+    }
+
+    pub fn impersonating(admin: i64, id: i64, duration: Duration) -> Cookie {
+        Cookie {
+            id: id,
+            impersonator: Some(admin),
             expiry: UTC::now() + duration
         }
     }
@@ -129,6 +147,16 @@ impl Cookie {
 }
 
 impl SealedCookie {
+    /// The token as handed to clients, minus the authorization scheme.
+    pub fn encode(&self) -> String {
+        let bytes = bincode::serialize(
+            self,
+            bincode::Infinite
+        ).unwrap();
+
+        base64::encode(&*bytes)
+    }
+
     pub fn unseal(
         &self,
         key: [u8; 32]
@@ -139,11 +167,18 @@ impl SealedCookie {
             &secretbox::Key(key)
         );
 
-        if let Ok(plain) = plain {
-            bincode::deserialize(&plain).map_err(Some)
-        } else {
-            Err(None)
-        }
+        let plain = match plain {
+            Ok(plain) => plain,
+            Err(()) => return Err(None)
+        };
+
+        // An old cookie's expiry starts where the impersonator would be,
+        // with a length that's never 0 or 1, so it can't pass for a new one.
+        bincode::deserialize(&plain).or_else(|e| {
+            bincode::deserialize::<OldCookie>(&plain)
+                .map(|old| Cookie { id: old.id, impersonator: None, expiry: old.expiry })
+                .map_err(|_| Some(e))
+        })
     }
 }
 
@@ -182,10 +217,10 @@ pub fn forgot(
     use schema::users::dsl::*;
     use schema::lower;
 
-    let (uid, pwhash, uname) = users
+    let (uid, pwhash, uname, ulocale) = users
         .filter(lower(email).eq(lower(user_email)))
-        .select((id, password, username))
-        .first::<(i64, Vec<u8>, String)>(conn)?;
+        .select((id, password, username, locale))
+        .first::<(i64, Vec<u8>, String, String)>(conn)?;
 
     let today = UTC::today();
     let mut data = vec![0; 12];
@@ -201,6 +236,7 @@ pub fn forgot(
     Ok(ResetInfo {
         id: uid,
         username: uname,
+        locale: ulocale,
         date: today,
         mac: mac
     })
@@ -243,6 +279,137 @@ pub fn reset(
     }
 }
 
+/// A code showing that a link to turn off a category of emails for a user
+/// came from us. Unlike reset codes, these don't expire.
+pub fn unsubscribe_code(key: [u8; 32], user: i64, category: &str) -> [u8; 32] {
+    let auth::Tag(mac) = auth::authenticate(
+        &unsubscribe_data(user, category),
+        &auth::Key(key)
+    );
+    mac
+}
+
+pub fn verify_unsubscribe(key: [u8; 32], user: i64, category: &str, code: [u8; 32]) -> bool {
+    auth::verify(
+        &auth::Tag(code),
+        &unsubscribe_data(user, category),
+        &auth::Key(key)
+    )
+}
+
+fn unsubscribe_data(user: i64, category: &str) -> Vec<u8> {
+    // Starts differently from reset codes, so one can't pass for the other.
+    let mut data = b"unsubscribe".to_vec();
+    let mut id = [0; 8];
+    LittleEndian::write_i64(&mut id, user);
+    data.extend(&id);
+    data.extend(category.as_bytes());
+    data
+}
+
+/// The code a session's QR code holds during one window of a few minutes,
+/// which members scan to check themselves in to it.
+pub fn check_in_code(key: [u8; 32], class: i64, date: NaiveDate, window: i64) -> [u8; 32] {
+    let auth::Tag(mac) = auth::authenticate(
+        &check_in_data(class, date, window),
+        &auth::Key(key)
+    );
+    mac
+}
+
+pub fn verify_check_in(
+    key: [u8; 32],
+    class: i64,
+    date: NaiveDate,
+    window: i64,
+    code: [u8; 32]
+) -> bool {
+    auth::verify(
+        &auth::Tag(code),
+        &check_in_data(class, date, window),
+        &auth::Key(key)
+    )
+}
+
+fn check_in_data(class: i64, date: NaiveDate, window: i64) -> Vec<u8> {
+    let mut data = b"check-in".to_vec();
+    let mut id = [0; 20];
+    LittleEndian::write_i64(&mut id[0..8], class);
+    LittleEndian::write_i32(&mut id[8..12], date.num_days_from_ce());
+    LittleEndian::write_i64(&mut id[12..20], window);
+    data.extend(&id);
+    data
+}
+
+/// Encrypt a cursor into a list, so clients can't read it or make their
+/// own. It's under a key of its own, so it can't pass for a cookie.
+pub fn seal_cursor(key: [u8; 32], plain: &[u8]) -> Vec<u8> {
+    let nonce = secretbox::gen_nonce();
+    let mut sealed = nonce.0.to_vec();
+    sealed.extend(secretbox::seal(plain, &nonce, &cursor_key(key)));
+    sealed
+}
+
+pub fn open_cursor(key: [u8; 32], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < secretbox::NONCEBYTES {
+        return None;
+    }
+
+    let (nonce, cipher) = sealed.split_at(secretbox::NONCEBYTES);
+    let nonce = secretbox::Nonce::from_slice(nonce)?;
+    secretbox::open(cipher, &nonce, &cursor_key(key)).ok()
+}
+
+fn cursor_key(key: [u8; 32]) -> secretbox::Key {
+    let auth::Tag(derived) = auth::authenticate(b"cursor", &auth::Key(key));
+    secretbox::Key(derived)
+}
+
+/// The secret in the URL of a user's calendar feed, which calendar apps
+/// can't log in to get. It's made the first time it's asked for.
+pub fn calendar_token(conn: &PgConnection, user: i64) -> QueryResult<Vec<u8>> {
+    use schema::calendar_tokens::dsl::*;
+
+    diesel::insert(&NewCalendarToken {
+        user_id: user,
+        token: &randombytes::randombytes(32)
+    }.on_conflict_do_nothing())
+    .into(calendar_tokens)
+    .execute(conn)?;
+
+    calendar_tokens.find(user)
+        .select(token)
+        .first(conn)
+}
+
+/// Replace the user's calendar token, so old links stop working.
+pub fn reset_calendar_token(conn: &PgConnection, user: i64) -> QueryResult<Vec<u8>> {
+    use schema::calendar_tokens::dsl::*;
+
+    conn.transaction(|| {
+        diesel::delete(calendar_tokens.find(user)).execute(conn)?;
+        calendar_token(conn, user)
+    })
+}
+
+pub fn verify_calendar_token(conn: &PgConnection, user: i64, given: &[u8]) -> QueryResult<bool> {
+    use schema::calendar_tokens::dsl::*;
+
+    let actual = calendar_tokens.find(user)
+        .select(token)
+        .first::<Vec<u8>>(conn)
+        .optional()?;
+
+    Ok(actual.map_or(false, |actual| memcmp(&actual, given)))
+}
+
+#[derive(Insertable)]
+#[table_name="calendar_tokens"]
+struct NewCalendarToken<'a> {
+    user_id: i64,
+    token: &'a [u8]
+}
+
 pub fn hash(password: &[u8]) -> pwhash::HashedPassword {
     // If we can't hash a password, there are bigger problems.
     pwhash::pwhash(
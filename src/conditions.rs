@@ -1,18 +1,18 @@
 #![allow(non_upper_case_globals, non_camel_case_types, dead_code)]
 
-use database::Database;
+use database::DbPointer;
 use diesel::expression::any;
-use diesel::{self, select};
+use diesel::select;
 use diesel::prelude::*;
 use diesel::expression::exists;
-use r2d2;
-use r2d2_diesel;
-use schema::{users, instructor_locations};
+use diesel::expression::sql_literal::{sql, SqlLiteral};
+use diesel::types::Bool;
+use schema::{users, instructor_locations, role_grants};
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::Mutex;
 pub use self::user_defined::*;
 
-type DbPointer = r2d2::PooledConnection
-    <r2d2_diesel::ConnectionManager<diesel::pg::PgConnection>>;
-
 #[derive(Debug)]
 pub enum Conditions {
     anyone,
@@ -20,40 +20,165 @@ pub enum Conditions {
     any(&'static [Conditions]),
     has_role(Role), // That one is sufficiently privileged.
     own_student, // Affecting a property that belongs to one's student.
-    own // Affecting a property that belongs to oneself.
+    own, // Affecting a property that belongs to oneself.
+    own_location, // Affecting a location where one trains or instructs.
+    instructs // Affecting a location where one instructs.
+}
+
+/// Whether an instructor assignment is in effect right now.
+pub fn active_assignment() -> SqlLiteral<Bool> {
+    sql("(instructor_locations.valid_from <= now() AND \
+        (instructor_locations.valid_until IS NULL OR \
+        now() < instructor_locations.valid_until))")
+}
+
+/// Whether a temporary role grant is in effect right now.
+pub fn active_grant() -> SqlLiteral<Bool> {
+    sql("(role_grants.valid_from <= now() AND now() < role_grants.valid_until)")
+}
+
+/// Lookups shared by every permission check made during one request.
+#[derive(Default)]
+pub struct Memo {
+    role: Mutex<Option<i64>>,
+    students: Mutex<HashMap<i64, bool>>,
+    locations: Mutex<Option<Vec<i64>>>,
+    instructing: Mutex<Option<Vec<i64>>>
+}
+
+impl Memo {
+    /// Record whether the targets are students of the current user.
+    pub fn remember_students<I>(&self, targets: I, student: bool)
+    where I: IntoIterator<Item=i64> {
+        let mut students = self.students.lock().unwrap();
+        for target in targets {
+            students.insert(target, student);
+        }
+    }
+
+    /// Look the user's role up again, after it or their grants changed.
+    pub fn forget_role(&self) {
+        *self.role.lock().unwrap() = None;
+    }
+
+    /// Look up again who the user's students are and where they train and
+    /// instruct, after people moved or instructors were assigned.
+    pub fn forget_places(&self) {
+        self.students.lock().unwrap().clear();
+        *self.locations.lock().unwrap() = None;
+        *self.instructing.lock().unwrap() = None;
+    }
 }
 
+/// What a permission check needs to know about the user and their target.
+pub trait Facts {
+    fn same(&self) -> bool;
+    fn role(&self) -> Result<i64, &'static str>;
+    fn student(&self) -> Result<bool, &'static str>;
+    fn own_location(&self) -> Result<bool, &'static str>;
+    fn instructs(&self) -> Result<bool, &'static str>;
+}
+
+#[derive(Clone, Copy)]
 pub struct Cache<'a> {
     pub user: Option<i64>,
     pub target: Option<i64>,
-    pub pool: &'a Database,
-    pub database: Option<DbPointer>,
-    pub role: Option<i64>,
-    pub student: Option<bool>
+    pub student: Option<bool>, // Overrides the lookup when already known.
+    pub location: Option<i64>,
+    pub database: &'a DbPointer,
+    pub memo: &'a Memo
 }
 
 impl<'a> Cache<'a> {
-    pub fn empty(
+    pub fn new(
         user: Option<i64>,
         target: Option<i64>,
-        pool: &'a Database
+        database: &'a DbPointer,
+        memo: &'a Memo
     ) -> Cache<'a> {
         Cache {
             user: user,
             target: target,
-            pool: pool,
-            database: None,
-            role: None,
-            student: None
+            student: None,
+            location: None,
+            database: database,
+            memo: memo
         }
     }
 
-    pub fn same(&mut self) -> bool {
+    pub fn retarget(&self, target: Option<i64>) -> Cache<'a> {
+        Cache { target: target, student: None, ..*self }
+    }
+
+    pub fn at(&self, location: Option<i64>) -> Cache<'a> {
+        Cache { location: location, ..*self }
+    }
+
+    /// Work out which of the targets are one's students in a single query.
+    pub fn prefetch_students(&self, targets: &[i64]) -> Result<(), &'static str> {
+        let user = match self.user {
+            Some(user) => user,
+            None => return Ok(()) // Anonymous users have no students.
+        };
+
+        let missing = {
+            let students = self.memo.students.lock().unwrap();
+            targets.iter()
+                .cloned()
+                .filter(|target| !students.contains_key(target))
+                .collect::<Vec<_>>()
+        };
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let found = users::table
+            .filter(users::id.eq_any(missing.clone()))
+            .filter(users::training_location.eq_any(
+                instructor_locations::table
+                    .filter(instructor_locations::instructor_id.eq(user))
+                    .filter(active_assignment())
+                    .select(instructor_locations::location_id.nullable())))
+            .select(users::id)
+            .get_results::<i64>(&**self.database)
+            .map_err(|_| "server error")?;
+
+        let (yes, no) = missing.into_iter()
+            .partition::<Vec<_>, _>(|target| found.contains(target));
+        self.memo.remember_students(yes, true);
+        self.memo.remember_students(no, false);
+        Ok(())
+    }
+
+    pub fn database(&self) -> &'a DbPointer {
+        self.database
+    }
+
+    /// The locations where the user instructs right now.
+    fn instructing(&self, user: i64) -> Result<Vec<i64>, &'static str> {
+        let mut instructing = self.memo.instructing.lock().unwrap();
+
+        if instructing.is_none() {
+            *instructing = Some(instructor_locations::table
+                .filter(instructor_locations::instructor_id.eq(user))
+                .filter(active_assignment())
+                .select(instructor_locations::location_id)
+                .get_results::<i64>(&**self.database)
+                .map_err(|_| "server error")?);
+        }
+
+        Ok(instructing.clone().unwrap_or_default())
+    }
+}
+
+impl<'a> Facts for Cache<'a> {
+    fn same(&self) -> bool {
         self.user.is_some() && self.user == self.target
     }
 
-    pub fn role(&mut self) -> Result<i64, &'static str> {
-        if let Some(role) = self.role {
+    fn role(&self) -> Result<i64, &'static str> {
+        if let Some(role) = *self.memo.role.lock().unwrap() {
             return Ok(role);
         }
 
@@ -63,17 +188,26 @@ impl<'a> Cache<'a> {
             _ => return Err("unauthorized")
         };
 
-        let role = users::table
+        let base = users::table
             .find(user)
             .select(users::role)
-            .get_result(&**self.database())
+            .get_result::<i64>(&**self.database)
             .map_err(|_| "server error")?;
 
-        self.role = Some(role);
+        // Temporary grants can only raise a role, never lower it.
+        let role = role_grants::table
+            .filter(role_grants::user_id.eq(user).and(active_grant()))
+            .select(role_grants::role)
+            .get_results::<i64>(&**self.database)
+            .map_err(|_| "server error")?
+            .into_iter()
+            .fold(base, cmp::max);
+
+        *self.memo.role.lock().unwrap() = Some(role);
         Ok(role)
     }
 
-    pub fn student(&mut self) -> Result<bool, &'static str> {
+    fn student(&self) -> Result<bool, &'static str> {
         if let Some(student) = self.student {
             return Ok(student);
         }
@@ -84,6 +218,10 @@ impl<'a> Cache<'a> {
             (None, Some(_)) => return Err("unauthorized")
         };
 
+        if let Some(&student) = self.memo.students.lock().unwrap().get(&target) {
+            return Ok(student);
+        }
+
         let exists = select(exists(
                 instructor_locations::table.filter(
                     instructor_locations::location_id.nullable()
@@ -92,28 +230,51 @@ impl<'a> Cache<'a> {
                             .find(target)
                             .select(users::training_location)))
                     .and(instructor_locations::instructor_id.eq(user))
+                    .and(active_assignment())
                 )
             ))
-            .get_result::<bool>(&**self.database())
+            .get_result::<bool>(&**self.database)
             .map_err(|_| "server error")?;
 
+        self.memo.remember_students(Some(target), exists);
         Ok(exists)
     }
 
-    pub fn database(&mut self) -> &DbPointer {
-        if let Some(ref db) = self.database {
-            return db;
+    fn own_location(&self) -> Result<bool, &'static str> {
+        let (user, location) = match (self.user, self.location) {
+            (Some(user), Some(location)) => (user, location),
+            (_, None) => return Ok(false), // Doesn't make sense without location.
+            (None, Some(_)) => return Err("unauthorized")
+        };
+
+        let mut locations = self.memo.locations.lock().unwrap();
+
+        if locations.is_none() {
+            let mut ids = self.instructing(user)?;
+            let training = users::table
+                .find(user)
+                .select(users::training_location)
+                .get_result::<Option<i64>>(&**self.database)
+                .map_err(|_| "server error")?;
+
+            ids.extend(training);
+            *locations = Some(ids);
         }
 
-        let db = self.pool.get().unwrap();
-        self.database = Some(db);
-        //LONG: No need to unwrap?
-        self.database.as_ref().unwrap()
+        Ok(locations.as_ref().map_or(false, |ids| ids.contains(&location)))
+    }
+
+    fn instructs(&self) -> Result<bool, &'static str> {
+        match (self.user, self.location) {
+            (Some(user), Some(location)) => Ok(self.instructing(user)?.contains(&location)),
+            (_, None) => Ok(false), // Doesn't make sense without location.
+            (None, Some(_)) => Err("unauthorized")
+        }
     }
 }
 
 impl Conditions {
-    pub fn check(&self, cache: &mut Cache) -> Result<bool, &'static str> {
+    pub fn check<F: Facts>(&self, cache: &F) -> Result<bool, &'static str> {
         use self::Conditions::*;
 
         match self {
@@ -128,7 +289,11 @@ impl Conditions {
             &own_student =>
                 cache.student(),
             &own =>
-                Ok(cache.same())
+                Ok(cache.same()),
+            &own_location =>
+                cache.own_location(),
+            &instructs =>
+                cache.instructs()
         }
     }
 }
@@ -180,6 +345,10 @@ macro_rules! permissions {
 
         pub static ROLES: &'static [Role] = &[$($a),*];
 
+        #[cfg(test)]
+        pub static PERMISSIONS: &'static [(&'static str, Conditions)] =
+            &[$( (stringify!($x), $x) ),*];
+
         impl Role {
             pub fn to_string(self) -> &'static str {
                 match self {
@@ -204,3 +373,203 @@ mod user_defined {
     #![forbid(dead_code)]
     include!(concat!(env!("CARGO_MANIFEST_DIR"), "/permissions.rs"));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Conditions, Facts, Memo, Role, ROLES, PERMISSIONS};
+
+    const FIXTURES: &'static str =
+        include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/permissions.test"));
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Relationship { Myself, Student, Location, Instructing, Other, Nobody }
+
+    /// Answers permission checks from a fixture line instead of the database.
+    struct Stub {
+        role: Option<i64>, // None if anonymous.
+        relationship: Relationship
+    }
+
+    impl Facts for Stub {
+        fn same(&self) -> bool {
+            self.role.is_some() && self.relationship == Relationship::Myself
+        }
+
+        fn role(&self) -> Result<i64, &'static str> {
+            self.role.ok_or("unauthorized")
+        }
+
+        fn student(&self) -> Result<bool, &'static str> {
+            match (self.role, self.relationship) {
+                (_, Relationship::Nobody) => Ok(false),
+                (None, _) => Err("unauthorized"),
+                (Some(_), relationship) => Ok(relationship == Relationship::Student)
+            }
+        }
+
+        fn own_location(&self) -> Result<bool, &'static str> {
+            match (self.role, self.relationship) {
+                (_, Relationship::Nobody) => Ok(false),
+                (None, _) => Err("unauthorized"),
+                (Some(_), relationship) => Ok(
+                    relationship == Relationship::Location ||
+                    relationship == Relationship::Instructing
+                )
+            }
+        }
+
+        fn instructs(&self) -> Result<bool, &'static str> {
+            match (self.role, self.relationship) {
+                (_, Relationship::Nobody) => Ok(false),
+                (None, _) => Err("unauthorized"),
+                (Some(_), relationship) => Ok(relationship == Relationship::Instructing)
+            }
+        }
+    }
+
+    fn parse(line: &str) -> Result<(Stub, &str, bool), String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        if words.len() != 4 {
+            return Err("expected four columns".to_owned());
+        }
+
+        let role = if words[0] == "anonymous" {
+            None
+        } else {
+            let role = ROLES.iter().find(|role| role.to_string() == words[0]);
+            match role {
+                Some(&role) => Some(role as i64),
+                None => return Err(format!("unknown role `{}`", words[0]))
+            }
+        };
+
+        let relationship = match words[1] {
+            "self" => Relationship::Myself,
+            "student" => Relationship::Student,
+            "location" => Relationship::Location,
+            "instructing" => Relationship::Instructing,
+            "other" => Relationship::Other,
+            "none" => Relationship::Nobody,
+            x => return Err(format!("unknown relationship `{}`", x))
+        };
+
+        let expected = match words[3] {
+            "allow" => true,
+            "deny" => false,
+            x => return Err(format!("expected `allow` or `deny`, not `{}`", x))
+        };
+
+        let stub = Stub { role: role, relationship: relationship };
+        Ok((stub, words[2], expected))
+    }
+
+    fn fixtures() -> Vec<(usize, &'static str)> {
+        FIXTURES.lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+            .filter(|&(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .collect()
+    }
+
+    #[test]
+    fn fixtures_hold() {
+        let mut failures = Vec::new();
+
+        for (number, line) in fixtures() {
+            let (stub, name, expected) = match parse(line) {
+                Ok(x) => x,
+                Err(e) => {
+                    failures.push(format!("line {}: {}", number, e));
+                    continue;
+                }
+            };
+
+            let perm = PERMISSIONS.iter().find(|&&(x, _)| x == name);
+            let perm = match perm {
+                Some(&(_, ref perm)) => perm,
+                None => {
+                    failures.push(format!("line {}: unknown permission `{}`", number, name));
+                    continue;
+                }
+            };
+
+            let actual = perm.check(&stub) == Ok(true);
+            if actual != expected {
+                failures.push(format!("line {}: `{}` was {}", number, line,
+                    if actual { "allowed" } else { "denied" }));
+            }
+        }
+
+        assert!(failures.is_empty(), "\n{}\n", failures.join("\n"));
+    }
+
+    #[test]
+    fn fixtures_cover_every_permission() {
+        let lines = fixtures();
+        let missing = PERMISSIONS.iter()
+            .map(|&(name, _)| name)
+            .filter(|name| !lines.iter().any(|&(_, line)| {
+                line.split_whitespace().nth(2) == Some(*name)
+            }))
+            .collect::<Vec<_>>();
+
+        assert!(missing.is_empty(), "no fixtures for {}", missing.join(", "));
+    }
+
+    /// Answers permission checks only from what a request has remembered,
+    /// failing wherever the database would have to be asked.
+    struct Remembered<'a> {
+        memo: &'a Memo,
+        target: i64 // Also the location, for location checks.
+    }
+
+    impl<'a> Facts for Remembered<'a> {
+        fn same(&self) -> bool {
+            false
+        }
+
+        fn role(&self) -> Result<i64, &'static str> {
+            self.memo.role.lock().unwrap().ok_or("not remembered")
+        }
+
+        fn student(&self) -> Result<bool, &'static str> {
+            self.memo.students.lock().unwrap().get(&self.target).cloned()
+                .ok_or("not remembered")
+        }
+
+        fn own_location(&self) -> Result<bool, &'static str> {
+            self.memo.locations.lock().unwrap().as_ref()
+                .map(|ids| ids.contains(&self.target))
+                .ok_or("not remembered")
+        }
+
+        fn instructs(&self) -> Result<bool, &'static str> {
+            self.memo.instructing.lock().unwrap().as_ref()
+                .map(|ids| ids.contains(&self.target))
+                .ok_or("not remembered")
+        }
+    }
+
+    #[test]
+    fn checks_after_changes_look_again() {
+        let memo = Memo::default();
+        *memo.role.lock().unwrap() = Some(Role::admin as i64);
+        memo.remember_students(Some(1), true);
+        *memo.locations.lock().unwrap() = Some(vec![1]);
+        *memo.instructing.lock().unwrap() = Some(vec![1]);
+
+        let facts = Remembered { memo: &memo, target: 1 };
+        assert_eq!(super::edit_role.check(&facts), Ok(true));
+        assert_eq!(Conditions::own_student.check(&facts), Ok(true));
+        assert_eq!(Conditions::own_location.check(&facts), Ok(true));
+        assert_eq!(Conditions::instructs.check(&facts), Ok(true));
+
+        // As after grantRole or unassignInstructor.
+        memo.forget_role();
+        memo.forget_places();
+        assert!(super::edit_role.check(&facts).is_err());
+        assert!(Conditions::own_student.check(&facts).is_err());
+        assert!(Conditions::own_location.check(&facts).is_err());
+        assert!(Conditions::instructs.check(&facts).is_err());
+    }
+}
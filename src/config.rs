@@ -3,7 +3,8 @@ use iron::typemap::Key;
 use sodiumoxide::randombytes;
 use std::{env, process, u8};
 use std::borrow::Cow;
-use std::fmt::Write;
+use std::fmt::{Display, Write};
+use std::str::FromStr;
 
 //LONG: Move into a nice TOML (or other) file.
 
@@ -11,14 +12,44 @@ pub struct Config {
     pub port: u16,
     pub secret: [u8; 32],
     pub session_length: Duration,
+    pub impersonation_length: Duration,
+
+    pub max_query_depth: usize,
+    pub max_query_complexity: u64,
+    pub max_list_size: i64,
+
+    pub persisted_queries: Option<String>,
+    pub query_allowlist: bool,
+    pub graphiql: bool,
+
+    pub threads: usize,
+    pub max_subscribers: usize,
+    pub max_subscribers_per_user: usize,
 
     pub database_url: String,
     pub frontend_url: String,
-    pub email_url: String,
+    pub public_url: String,
 
+    pub email_templates: String,
     pub email_address: String,
-    pub email_username: String,
-    pub email_password: String
+    pub mail_transport: MailTransport,
+
+    pub payment_provider: PaymentProvider
+}
+
+/// How emails go out.
+pub enum MailTransport {
+    Smtp { url: String, username: String, password: String },
+    File(String), // The directory to write them to.
+    Log
+}
+
+/// Who takes payments.
+pub enum PaymentProvider {
+    /// Payments can only be recorded by hand.
+    None,
+    #[cfg(any(test, feature = "fake-payments"))]
+    Fake
 }
 
 impl Config {
@@ -51,39 +82,52 @@ impl Config {
                 Duration::minutes(30)
             });
 
-        let envars = [
-            "DATABASE_URL",
-            "FRONTEND_URL",
-            "EMAIL_URL",
-            "EMAIL_ADDRESS",
-            "EMAIL_USERNAME",
-            "EMAIL_PASSWORD"
-        ];
-        
-        let mut strings = envars.iter()
-            .map(|envar| {
-                env::var(envar)
-                    .unwrap_or_else(|_| {
-                        error!("{} unspecified.", envar);
-                        process::exit(1);
-                    })
+        let impersonation_length = env::var("IMPERSONATION_LENGTH")
+            .map_err(|_| "unspecified")
+            .and_then(|n| {
+                let n = n.parse();
+
+                if let Ok(n) = n {
+                    if n > 0 {
+                        return Ok(Duration::minutes(n));
+                    }
+                }
+
+                Err("invalid")
+            })
+            .unwrap_or_else(|e| {
+                warn!("IMPERSONATION_LENGTH {}, defaulting to 10 minutes.", e);
+                Duration::minutes(10)
             });
 
-        let (
-            database_url,
-            frontend_url,
-            email_url,
-            email_address,
-            email_username,
-            email_password
-        ) = (
-            strings.next().unwrap(),
-            strings.next().unwrap(),
-            strings.next().unwrap(),
-            strings.next().unwrap(),
-            strings.next().unwrap(),
-            strings.next().unwrap()
-        );
+        let max_query_depth = positive("MAX_QUERY_DEPTH", 10);
+        let max_query_complexity = positive("MAX_QUERY_COMPLEXITY", 10000);
+        let max_list_size = positive("MAX_LIST_SIZE", 100);
+
+        let persisted_queries = env::var("PERSISTED_QUERIES").ok();
+        let query_allowlist = flag("QUERY_ALLOWLIST", false);
+        let graphiql = flag("GRAPHIQL", !query_allowlist);
+
+        // Every subscriber holds on to a thread, so some have to be left
+        // over for everything else.
+        let threads = positive("THREADS", 64);
+        let max_subscribers = positive("MAX_SUBSCRIBERS", 32);
+        let max_subscribers_per_user = positive("MAX_SUBSCRIBERS_PER_USER", 4);
+        if max_subscribers >= threads {
+            error!("MAX_SUBSCRIBERS has to be less than THREADS.");
+            process::exit(1);
+        }
+
+        let email_templates = env::var("EMAIL_TEMPLATES")
+            .unwrap_or_else(|_| "templates/email".to_owned());
+        let email_address = required("EMAIL_ADDRESS");
+        let mail_transport = mail_transport();
+        let payment_provider = payment_provider();
+
+        let database_url = required("DATABASE_URL");
+        let frontend_url = required("FRONTEND_URL");
+        let public_url = env::var("PUBLIC_URL")
+            .unwrap_or_else(|_| format!("http://localhost:{}", port));
 
         let secret = env::var("SECRET")
             .map_err(|_| "unspecified".into())
@@ -131,18 +175,117 @@ impl Config {
             port: port,
             secret: secret,
             session_length: session_length,
+            impersonation_length: impersonation_length,
+
+            max_query_depth: max_query_depth,
+            max_query_complexity: max_query_complexity,
+            max_list_size: max_list_size,
+
+            persisted_queries: persisted_queries,
+            query_allowlist: query_allowlist,
+            graphiql: graphiql,
+
+            threads: threads,
+            max_subscribers: max_subscribers,
+            max_subscribers_per_user: max_subscribers_per_user,
 
             database_url: database_url,
             frontend_url: frontend_url,
-            email_url: email_url,
+            public_url: public_url,
 
+            email_templates: email_templates,
             email_address: email_address,
-            email_username: email_username,
-            email_password: email_password
+            mail_transport: mail_transport,
+
+            payment_provider: payment_provider
+        }
+    }
+}
+
+/// Read a string from the environment, or exit if it isn't there.
+fn required(envar: &str) -> String {
+    env::var(envar).unwrap_or_else(|_| {
+        error!("{} unspecified.", envar);
+        process::exit(1);
+    })
+}
+
+/// Work out how to send emails. It's SMTP unless something else is asked
+/// for, since the others don't send anything and could leak the links in
+/// emails to whoever can read the logs or files.
+fn mail_transport() -> MailTransport {
+    match env::var("MAIL_TRANSPORT").as_ref().map(|x| x.as_str()).unwrap_or("smtp") {
+        "smtp" => MailTransport::Smtp {
+            url: required("EMAIL_URL"),
+            username: required("EMAIL_USERNAME"),
+            password: required("EMAIL_PASSWORD")
+        },
+        "file" => {
+            warn!("MAIL_TRANSPORT is file, so emails are only written to disk.");
+            MailTransport::File(
+                env::var("MAIL_DIRECTORY").unwrap_or_else(|_| "mail".to_owned())
+            )
+        },
+        "log" => {
+            warn!("MAIL_TRANSPORT is log, so emails are only logged, links and all.");
+            MailTransport::Log
+        },
+        _ => {
+            error!("MAIL_TRANSPORT invalid, expected smtp, file or log.");
+            process::exit(1);
+        }
+    }
+}
+
+/// Work out who takes payments. It's nobody unless something else is asked
+/// for, and the fake only exists in builds that are made to have it.
+fn payment_provider() -> PaymentProvider {
+    match env::var("PAYMENT_PROVIDER").as_ref().map(|x| x.as_str()).unwrap_or("none") {
+        "none" => PaymentProvider::None,
+        #[cfg(any(test, feature = "fake-payments"))]
+        "fake" => {
+            warn!("PAYMENT_PROVIDER is fake, so payments go through without any money.");
+            PaymentProvider::Fake
+        },
+        _ => {
+            if cfg!(any(test, feature = "fake-payments")) {
+                error!("PAYMENT_PROVIDER invalid, expected none or fake.");
+            } else {
+                error!("PAYMENT_PROVIDER invalid, expected none.");
+            }
+            process::exit(1);
         }
     }
 }
 
+/// Read a positive number from the environment, or use the default.
+fn positive<T>(envar: &str, default: T) -> T
+where T: FromStr + PartialOrd + Default + Display {
+    env::var(envar)
+        .map_err(|_| "unspecified")
+        .and_then(|n| match n.parse() {
+            Ok(n) if n > T::default() => Ok(n),
+            _ => Err("invalid")
+        })
+        .unwrap_or_else(|e| {
+            warn!("{} {}, defaulting to {}.", envar, e, default);
+            default
+        })
+}
+
+/// Read a yes or no from the environment, or use the default.
+fn flag(envar: &str, default: bool) -> bool {
+    match env::var(envar).as_ref().map(|x| x.as_str()) {
+        Ok("1") | Ok("true") | Ok("yes") => true,
+        Ok("0") | Ok("false") | Ok("no") => false,
+        Ok(_) => {
+            warn!("{} invalid, defaulting to {}.", envar, default);
+            default
+        },
+        Err(_) => default
+    }
+}
+
 impl Key for Config {
     type Value = Config;
 }
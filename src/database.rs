@@ -5,8 +5,11 @@ use r2d2_diesel::ConnectionManager;
 use iron::prelude::*;
 use iron::typemap::Key;
 use iron::BeforeMiddleware;
+use iron::status;
 use std::ops::{Deref, DerefMut};
 
+pub type DbPointer = r2d2::PooledConnection<ConnectionManager<PgConnection>>;
+
 #[derive(Clone)]
 pub struct Database(pub r2d2::Pool<ConnectionManager<PgConnection>>);
 
@@ -19,6 +22,18 @@ impl Database {
     }
 }
 
+/// A connection for handling the request, or a 503 if the pool hasn't got
+/// one to spare.
+pub fn connection(req: &Request) -> Result<DbPointer, Response> {
+    match req.extensions.get::<Database>().unwrap().get() {
+        Ok(db) => Ok(db),
+        Err(e) => {
+            error!("Could not get a database connection: {}.", e);
+            Err(Response::with(status::ServiceUnavailable))
+        }
+    }
+}
+
 impl BeforeMiddleware for Database {
     fn before(&self, req: &mut Request) -> IronResult<()> {
         req.extensions
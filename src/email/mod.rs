@@ -1,24 +1,52 @@
 use auth::ResetInfo;
 use base64;
 use config::Config;
-use lettre::transport::smtp::{
-    SmtpTransportBuilder,
-    SecurityLevel,
-    SUBMISSION_PORT
-};
-use lettre::transport::smtp::authentication::Mechanism;
+use diesel::pg::PgConnection;
+use diesel::result::Error as DieselError;
+use diesel::prelude::*;
 use lettre::email::EmailBuilder;
-use lettre::transport::EmailTransport;
-use lettre::transport::smtp::error::SmtpResult;
+use schema::users;
+use std::fmt::{self, Display};
+
+pub use self::preferences::Category;
+pub use self::templates::{Templates, Vars};
+use self::templates::Message;
+use self::transport::Mailer;
+
+pub mod outbox;
+pub mod preferences;
+mod templates;
+mod transport;
 
-//LONG: Runtime email templates and subjects.
 //TODO: Are equal signs valid in query parameter values?
 
+pub enum Error {
+    Template(String),
+    Database(DieselError)
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Template(ref e) => write!(f, "couldn't render the template: {}", e),
+            Error::Database(ref e) => write!(f, "couldn't queue the email: {}", e)
+        }
+    }
+}
+
+impl From<DieselError> for Error {
+    fn from(e: DieselError) -> Error {
+        Error::Database(e)
+    }
+}
+
 pub fn reset_password(
     config: &Config,
+    templates: &Templates,
+    db: &PgConnection,
     info: ResetInfo,
     to: &str
-) -> SmtpResult {
+) -> Result<(), Error> {
     let code = base64::encode_config(&info.mac, base64::URL_SAFE);
 
     //LONG: Don't just make up URLs as you go!
@@ -29,30 +57,77 @@ pub fn reset_password(
         code
     );
 
-    let email = EmailBuilder::new()
+    let mut vars = Vars::new();
+    vars.insert("name", info.username);
+    vars.insert("link", link);
+    send(templates, db, to, &info.locale, "reset_password", &vars)
+}
+
+/// Fill in a template in the recipient's locale and queue it to be sent,
+/// whatever their preferences. Only for emails people can't do without.
+pub fn send(
+    templates: &Templates,
+    db: &PgConnection,
+    to: &str,
+    locale: &str,
+    template: &str,
+    vars: &Vars
+) -> Result<(), Error> {
+    let message = templates.render(template, locale, vars).map_err(Error::Template)?;
+    outbox::enqueue(db, to, &message, None)?;
+    Ok(())
+}
+
+/// Fill in a template in the user's locale and queue it for them, unless
+/// they've turned off the category. The template can use `{{unsubscribe}}`
+/// for a link that turns it off. Returns whether it was queued.
+pub fn notify(
+    config: &Config,
+    templates: &Templates,
+    db: &PgConnection,
+    user: i64,
+    category: Category,
+    template: &str,
+    vars: &Vars
+) -> Result<bool, Error> {
+    if !preferences::enabled(db, user, category)? {
+        return Ok(false);
+    }
+
+    let (to, locale) = users::table.find(user)
+        .select((users::email, users::locale))
+        .first::<(String, String)>(db)?;
+
+    let link = preferences::unsubscribe_link(config, user, category);
+    let mut vars = vars.clone();
+    vars.insert("unsubscribe", link.clone());
+
+    let message = templates.render(template, &locale, &vars).map_err(Error::Template)?;
+    outbox::enqueue(db, &to, &message, Some(&link))?;
+    Ok(true)
+}
+
+/// Actually send an email, which only the outbox should do.
+fn deliver(
+    config: &Config,
+    mailer: &Mailer,
+    to: &str,
+    message: &Message,
+    unsubscribe: Option<&str>
+) -> Result<(), transport::Error> {
+    let mut email = EmailBuilder::new()
         .to(to)
         .from(config.email_address.as_str())
-        .subject("Password Reset")
-        .html(&format!(
-            include_str!("reset_password.html"),
-            name = info.username,
-            link = link
-        ))
-        .build()
-        .unwrap(); // All fields guaranteed to be filled.
-
-    //LONG: SMTP connection reuse.
-    let server = (config.email_url.as_str(), SUBMISSION_PORT);
-    let mut mailer = SmtpTransportBuilder::new(server)?
-        .credentials(
-            config.email_username.as_str(),
-            config.email_password.as_str()
-        )
-        .security_level(SecurityLevel::AlwaysEncrypt)
-        .smtp_utf8(true)
-        .authentication_mechanism(Mechanism::Plain)
-        .connection_reuse(false)
-        .build();
+        .subject(&message.subject)
+        .alternative(&message.html, &message.text);
+
+    if let Some(link) = unsubscribe {
+        // Lets mail clients offer to unsubscribe in one click (RFC 8058).
+        email.add_header(("List-Unsubscribe", format!("<{}>", link).as_str()));
+        email.add_header(("List-Unsubscribe-Post", "List-Unsubscribe=One-Click"));
+    }
+
+    let email = email.build().unwrap(); // All fields guaranteed to be filled.
 
     mailer.send(email)
 }
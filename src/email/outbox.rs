@@ -0,0 +1,177 @@
+//! Emails are put in the `outbox` table and sent from there in the
+//! background, so that nobody has to wait on the mail server, and so that
+//! they're tried again if they can't be sent.
+
+use chrono::{DateTime, Duration, UTC};
+use config::Config;
+use database::Database;
+use diesel;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use schema::outbox;
+use std::sync::Arc;
+use std::thread;
+use std::time;
+use super::templates::Message;
+use super::transport::{self, Mailer};
+use uuid::Uuid;
+
+/// How long to wait when there's nothing to send, in seconds.
+const POLL: u64 = 5;
+/// How many emails to send at a time.
+const BATCH: i64 = 10;
+/// How many times to try an email before giving up on it.
+const MAX_ATTEMPTS: i32 = 8;
+/// How long an email is left alone while it's being sent, in minutes, in
+/// case more than one server is sending.
+const LEASE: i64 = 10;
+
+pub const PENDING: &'static str = "pending";
+pub const SENT: &'static str = "sent";
+pub const FAILED: &'static str = "failed";
+
+#[derive(Queryable)]
+pub struct Queued {
+    pub id: i64,
+    pub recipient: String,
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<UTC>,
+    pub next_attempt: DateTime<UTC>,
+    pub sent_at: Option<DateTime<UTC>>,
+    pub unsubscribe: Option<String>,
+    pub uuid: Uuid
+}
+
+#[derive(Insertable)]
+#[table_name="outbox"]
+struct NewEmail<'a> {
+    recipient: &'a str,
+    subject: &'a str,
+    html: &'a str,
+    text: &'a str,
+    unsubscribe: Option<&'a str>
+}
+
+/// Queue an email to be sent as soon as possible, along with where to
+/// unsubscribe from emails like it, if anywhere.
+pub fn enqueue(
+    db: &PgConnection,
+    to: &str,
+    message: &Message,
+    unsubscribe: Option<&str>
+) -> QueryResult<()> {
+    let email = NewEmail {
+        recipient: to,
+        subject: &message.subject,
+        html: &message.html,
+        text: &message.text,
+        unsubscribe: unsubscribe
+    };
+
+    diesel::insert(&email)
+        .into(outbox::table)
+        .execute(db)
+        .map(|_| ())
+}
+
+/// Start sending queued emails in the background.
+pub fn start(config: Arc<Config>, db: Database) {
+    let mailer = transport::new(&config);
+    thread::spawn(move || loop {
+        match send_due(&config, &*mailer, &db) {
+            Ok(0) => thread::sleep(time::Duration::from_secs(POLL)),
+            Ok(_) => {},
+            Err(e) => {
+                error!("Could not send queued emails: {}.", e);
+                thread::sleep(time::Duration::from_secs(POLL));
+            }
+        }
+    });
+}
+
+/// Send whatever's due, and return how many were tried.
+fn send_due(config: &Config, mailer: &Mailer, db: &Database) -> Result<usize, String> {
+    let conn = db.get().map_err(|e| e.to_string())?;
+    let now = UTC::now();
+
+    let due = outbox::table
+        .filter(outbox::status.eq(PENDING))
+        .filter(outbox::next_attempt.le(now))
+        .order(outbox::next_attempt)
+        .limit(BATCH)
+        .select(outbox::id);
+
+    // The conditions are checked again once the rows are locked, so only
+    // one server gets each email.
+    let claimed = diesel::update(
+            outbox::table
+                .filter(outbox::id.eq_any(due))
+                .filter(outbox::status.eq(PENDING))
+                .filter(outbox::next_attempt.le(now))
+        )
+        .set(outbox::next_attempt.eq(now + Duration::minutes(LEASE)))
+        .get_results::<Queued>(&*conn)
+        .map_err(|e| e.to_string())?;
+
+    for email in &claimed {
+        let result = super::deliver(
+            config,
+            mailer,
+            &email.recipient,
+            &Message {
+                subject: email.subject.clone(),
+                html: email.html.clone(),
+                text: email.text.clone()
+            },
+            email.unsubscribe.as_ref().map(|x| x.as_str())
+        );
+
+        let attempts = email.attempts + 1;
+        let target = outbox::table.find(email.id);
+        let updated = match result {
+            Ok(()) => diesel::update(target)
+                .set((
+                    outbox::status.eq(SENT),
+                    outbox::attempts.eq(attempts),
+                    outbox::last_error.eq(None::<String>),
+                    outbox::sent_at.eq(Some(UTC::now())),
+                    // They can have reset links in them, which shouldn't
+                    // outlive being sent.
+                    outbox::html.eq(""),
+                    outbox::text.eq("")
+                ))
+                .execute(&*conn),
+            Err(e) => {
+                let permanent = match e { transport::Error::Permanent(_) => true, _ => false };
+                let status = if permanent || attempts >= MAX_ATTEMPTS { FAILED } else { PENDING };
+                warn!("Could not send email {} (attempt {}): {}.", email.id, attempts, e);
+
+                diesel::update(target)
+                    .set((
+                        outbox::status.eq(status),
+                        outbox::attempts.eq(attempts),
+                        outbox::last_error.eq(Some(e.to_string())),
+                        outbox::next_attempt.eq(UTC::now() + backoff(attempts))
+                    ))
+                    .execute(&*conn)
+            }
+        };
+
+        if let Err(e) = updated {
+            error!("Could not record the delivery of email {}: {}.", email.id, e);
+        }
+    }
+
+    Ok(claimed.len())
+}
+
+/// How long to wait before trying again, doubling each time from two
+/// minutes.
+fn backoff(attempts: i32) -> Duration {
+    Duration::minutes(1i64 << attempts.min(MAX_ATTEMPTS))
+}
@@ -0,0 +1,92 @@
+//! Which kinds of optional emails people want. Transactional emails, like
+//! password resets, are always sent.
+
+use auth;
+use config::Config;
+use diesel;
+use diesel::pg::PgConnection;
+use diesel::pg::upsert::*;
+use diesel::prelude::*;
+use schema::notification_preferences;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Category {
+    Announcements
+}
+
+pub static CATEGORIES: &'static [Category] = &[Category::Announcements];
+
+impl Category {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Category::Announcements => "announcements"
+        }
+    }
+
+    pub fn from_str(category: &str) -> Option<Category> {
+        CATEGORIES.iter().cloned().find(|x| x.as_str() == category)
+    }
+}
+
+#[derive(Insertable)]
+#[table_name="notification_preferences"]
+struct Preference<'a> {
+    user_id: i64,
+    category: &'a str,
+    enabled: bool
+}
+
+/// Whether the user wants emails in the category.
+pub fn enabled(db: &PgConnection, user: i64, category: Category) -> QueryResult<bool> {
+    notification_preferences::table
+        .find((user, category.as_str()))
+        .select(notification_preferences::enabled)
+        .first(db)
+        .optional()
+        .map(|enabled| enabled.unwrap_or(true))
+}
+
+/// Whether the user wants emails in each category.
+pub fn all(db: &PgConnection, user: i64) -> QueryResult<Vec<(Category, bool)>> {
+    let saved = notification_preferences::table
+        .filter(notification_preferences::user_id.eq(user))
+        .select((notification_preferences::category, notification_preferences::enabled))
+        .get_results::<(String, bool)>(db)?;
+
+    Ok(CATEGORIES.iter().map(|&category| {
+        let enabled = saved.iter()
+            .find(|x| x.0 == category.as_str())
+            .map_or(true, |x| x.1);
+        (category, enabled)
+    }).collect())
+}
+
+pub fn set(db: &PgConnection, user: i64, category: Category, enabled: bool) -> QueryResult<()> {
+    let preference = Preference {
+        user_id: user,
+        category: category.as_str(),
+        enabled: enabled
+    };
+
+    diesel::insert(&preference.on_conflict(
+        (notification_preferences::user_id, notification_preferences::category),
+        do_update().set(notification_preferences::enabled.eq(enabled))
+    ))
+    .into(notification_preferences::table)
+    .execute(db)
+    .map(|_| ())
+}
+
+/// A link to a page that turns off the category for the user, which mail
+/// clients can also post to directly to do it in one click.
+pub fn unsubscribe_link(config: &Config, user: i64, category: Category) -> String {
+    let code = auth::unsubscribe_code(config.secret, user, category.as_str());
+    let code = code.iter().map(|n| format!("{:02x}", n)).collect::<String>();
+    format!(
+        "{}/unsubscribe?user={}&category={}&code={}",
+        config.public_url,
+        user,
+        category.as_str(),
+        code
+    )
+}
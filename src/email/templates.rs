@@ -0,0 +1,169 @@
+//! Email templates, loaded from a directory when the server starts.
+//!
+//! Each template `name` is made up of `name.subject`, `name.html` and
+//! `name.txt`, and its bodies are put into `layout.html` and `layout.txt`
+//! wherever they say `{{content}}`. Anything else in double braces is
+//! replaced by the variable with that name, escaped in HTML.
+//!
+//! Translations go in a subdirectory named after the locale, like `es/`,
+//! and anything not translated there, layouts included, falls back to the
+//! English in the directory itself.
+
+use i18n;
+use iron::typemap::Key;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The values to fill a template in with, by name.
+pub type Vars = HashMap<&'static str, String>;
+
+pub struct Templates {
+    templates: HashMap<&'static str, HashMap<String, Template>>, // By locale, then name.
+    layouts: HashMap<&'static str, Template> // By locale.
+}
+
+struct Template {
+    subject: String,
+    html: String,
+    text: String
+}
+
+/// A filled-in template, ready to be sent.
+pub struct Message {
+    pub subject: String,
+    pub html: String,
+    pub text: String
+}
+
+impl Templates {
+    /// Load the layouts, and every template with a subject in the directory
+    /// and each locale's subdirectory.
+    pub fn load(dir: &str) -> io::Result<Templates> {
+        let dir = Path::new(dir);
+        let mut res = Templates { templates: HashMap::new(), layouts: HashMap::new() };
+        res.load_locale(dir, i18n::DEFAULT)?;
+
+        for &locale in i18n::LOCALES.iter().filter(|&&x| x != i18n::DEFAULT) {
+            let translated = dir.join(locale);
+            if translated.is_dir() {
+                res.load_locale(&translated, locale)?;
+            }
+        }
+
+        let count = res.templates.values().map(HashMap::len).sum::<usize>();
+        info!("Loaded {} email templates from {}.", count, dir.display());
+        Ok(res)
+    }
+
+    fn load_locale(&mut self, dir: &Path, locale: &'static str) -> io::Result<()> {
+        let html = dir.join("layout.html");
+        if locale == i18n::DEFAULT || html.exists() {
+            self.layouts.insert(locale, Template {
+                subject: String::new(),
+                html: read(&html)?,
+                text: read(&dir.join("layout.txt"))?
+            });
+        }
+
+        let mut templates = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "subject") {
+                continue;
+            }
+
+            let name = match path.file_stem().and_then(|x| x.to_str()) {
+                Some(name) => name.to_owned(),
+                None => continue
+            };
+
+            templates.insert(name, Template {
+                subject: read(&path)?.trim().to_owned(),
+                html: read(&path.with_extension("html"))?,
+                text: read(&path.with_extension("txt"))?
+            });
+        }
+
+        self.templates.insert(locale, templates);
+        Ok(())
+    }
+
+    /// Fill in the template and put it in the layout, in the locale if
+    /// they've been translated.
+    pub fn render(&self, name: &str, locale: &str, vars: &Vars) -> Result<Message, String> {
+        let find = |locale: &str| self.templates.get(locale).and_then(|x| x.get(name));
+        let template = find(locale)
+            .or_else(|| find(i18n::DEFAULT))
+            .ok_or_else(|| format!("there's no {} template", name))?;
+        let layout = self.layouts.get(locale)
+            .or_else(|| self.layouts.get(i18n::DEFAULT))
+            .ok_or_else(|| "there's no layout".to_owned())?;
+
+        let subject = fill(&template.subject, |var| vars.get(var).cloned())?;
+        let html = fill(&template.html, |var| vars.get(var).map(|x| escape(x)))?;
+        let text = fill(&template.text, |var| vars.get(var).cloned())?;
+
+        let html = fill(&layout.html, |var| match var {
+            "content" => Some(html.clone()),
+            "subject" => Some(escape(&subject)),
+            _ => vars.get(var).map(|x| escape(x))
+        })?;
+        let text = fill(&layout.text, |var| match var {
+            "content" => Some(text.clone()),
+            "subject" => Some(subject.clone()),
+            _ => vars.get(var).cloned()
+        })?;
+
+        Ok(Message { subject: subject, html: html, text: text })
+    }
+}
+
+impl Key for Templates {
+    type Value = Templates;
+}
+
+fn read(path: &Path) -> io::Result<String> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    Ok(contents)
+}
+
+/// Replace each `{{name}}` with its value, which must exist.
+fn fill<F>(template: &str, value: F) -> Result<String, String>
+where F: Fn(&str) -> Option<String> {
+    let mut res = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}")
+            .ok_or_else(|| "a placeholder is never closed".to_owned())?;
+        let name = rest[start + 2..start + end].trim();
+        let value = value(name).ok_or_else(|| format!("{} wasn't given", name))?;
+
+        res.push_str(&rest[..start]);
+        res.push_str(&value);
+        rest = &rest[start + end + 2..];
+    }
+
+    res.push_str(rest);
+    Ok(res)
+}
+
+fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&#39;"),
+            c => res.push(c)
+        }
+    }
+    res
+}
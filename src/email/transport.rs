@@ -0,0 +1,158 @@
+//! The ways emails can actually go out, chosen by `MAIL_TRANSPORT`.
+
+use config::{Config, MailTransport};
+use lettre::email::{Email, SendableEmail};
+use lettre::transport::EmailTransport;
+use lettre::transport::smtp::{
+    SmtpTransport,
+    SmtpTransportBuilder,
+    SecurityLevel,
+    SUBMISSION_PORT
+};
+use lettre::transport::smtp::authentication::Mechanism;
+use lettre::transport::smtp::error::Error as SmtpError;
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// How many idle SMTP connections to keep around.
+const POOL_SIZE: usize = 4;
+
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: Email) -> Result<(), Error>;
+}
+
+pub enum Error {
+    /// Trying again later might work.
+    Transient(String),
+    /// Trying again won't help.
+    Permanent(String)
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Transient(ref e) | Error::Permanent(ref e) => f.write_str(e)
+        }
+    }
+}
+
+impl From<SmtpError> for Error {
+    fn from(e: SmtpError) -> Error {
+        match e {
+            SmtpError::Permanent(_) => Error::Permanent(e.to_string()),
+            _ => Error::Transient(e.to_string())
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Transient(e.to_string())
+    }
+}
+
+/// The mailer the config asks for.
+pub fn new(config: &Config) -> Box<Mailer> {
+    match config.mail_transport {
+        MailTransport::Smtp { ref url, ref username, ref password } => Box::new(Smtp {
+            url: url.clone(),
+            username: username.clone(),
+            password: password.clone(),
+            idle: Mutex::new(Vec::new())
+        }),
+        MailTransport::File(ref dir) => Box::new(Files { dir: PathBuf::from(dir) }),
+        MailTransport::Log => Box::new(Log)
+    }
+}
+
+/// Sends over SMTP, keeping connections open to be used again.
+struct Smtp {
+    url: String,
+    username: String,
+    password: String,
+    idle: Mutex<Vec<SmtpTransport>>
+}
+
+impl Smtp {
+    fn connect(&self) -> Result<SmtpTransport, Error> {
+        let server = (self.url.as_str(), SUBMISSION_PORT);
+        Ok(SmtpTransportBuilder::new(server)?
+            .credentials(self.username.as_str(), self.password.as_str())
+            .security_level(SecurityLevel::AlwaysEncrypt)
+            .smtp_utf8(true)
+            .authentication_mechanism(Mechanism::Plain)
+            .connection_reuse(true)
+            .build())
+    }
+}
+
+impl Mailer for Smtp {
+    fn send(&self, email: Email) -> Result<(), Error> {
+        let idle = self.idle.lock().unwrap().pop();
+        let reused = idle.is_some();
+        let mut transport = match idle {
+            Some(transport) => transport,
+            None => self.connect()?
+        };
+
+        let mut result = transport.send(email.clone());
+        let retry = match result {
+            Ok(_) | Err(SmtpError::Permanent(_)) => false,
+            Err(_) => reused
+        };
+
+        if retry {
+            // The server might have hung up on it while it was idle, in
+            // which case it's been reset and will connect again.
+            result = transport.send(email);
+        }
+
+        match result {
+            Ok(_) => {
+                let mut idle = self.idle.lock().unwrap();
+                if idle.len() < POOL_SIZE {
+                    idle.push(transport);
+                } else {
+                    transport.close();
+                }
+                Ok(())
+            },
+            Err(e) => {
+                transport.close();
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Writes each email to a `.eml` file in a directory, for development.
+struct Files {
+    dir: PathBuf
+}
+
+impl Mailer for Files {
+    fn send(&self, email: Email) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!("{}.eml", email.message_id()));
+        File::create(&path)?.write_all(email.message().as_bytes())?;
+        info!("Wrote an email to {}.", path.display());
+        Ok(())
+    }
+}
+
+/// Only logs each email, for development.
+struct Log;
+
+impl Mailer for Log {
+    fn send(&self, email: Email) -> Result<(), Error> {
+        info!(
+            "Not sending an email to {}:\n{}",
+            email.to_addresses().join(", "),
+            email.message()
+        );
+        Ok(())
+    }
+}
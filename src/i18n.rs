@@ -0,0 +1,109 @@
+//! Translations of the API's own words, like error messages and schema
+//! descriptions. Emails have their own translated templates.
+//!
+//! Each catalog in `locales/` maps the English text to its translation,
+//! and anything missing is left in English.
+
+use iron::headers::AcceptLanguage;
+use juniper::Value;
+use serde_json;
+use std::collections::HashMap;
+
+pub const DEFAULT: &'static str = "en";
+
+/// Every locale people can choose, which `users_locale_check` agrees with.
+pub static LOCALES: &'static [&'static str] = &["en", "es", "ko"];
+
+lazy_static! {
+    /// Every locale's catalog, parsed the first time any is needed.
+    static ref CATALOGS: HashMap<&'static str, Catalog> =
+        LOCALES.iter().map(|&locale| (locale, Catalog::parse(locale))).collect();
+}
+
+#[derive(Default, Deserialize)]
+pub struct Catalog {
+    /// Error messages, by the English message.
+    #[serde(default)]
+    errors: HashMap<String, String>,
+    /// Error messages for when the English one isn't known exactly, such as
+    /// when it has numbers in it, by code.
+    #[serde(default)]
+    codes: HashMap<String, String>,
+    /// Descriptions of types, fields, arguments and enum values.
+    #[serde(default)]
+    schema: HashMap<String, String>
+}
+
+impl Catalog {
+    /// The catalog for a locale, or the English one if it isn't supported.
+    pub fn get(locale: &str) -> &'static Catalog {
+        CATALOGS.get(locale).unwrap_or_else(|| &CATALOGS[DEFAULT])
+    }
+
+    fn parse(locale: &str) -> Catalog {
+        let source = match locale {
+            "es" => include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/locales/es.json")),
+            "ko" => include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/locales/ko.json")),
+            _ => return Catalog::default()
+        };
+
+        serde_json::from_str(source).unwrap_or_else(|e| {
+            error!("The {} catalog is invalid: {}.", locale, e);
+            Catalog::default()
+        })
+    }
+
+    /// An error message, going by the code if the message isn't known.
+    pub fn error<'a>(&'a self, code: &str, message: &'a str) -> &'a str {
+        self.errors.get(message)
+            .or_else(|| self.codes.get(code))
+            .map_or(message, |x| x.as_str())
+    }
+
+    /// Translate the descriptions in an introspection result.
+    pub fn describe(&self, value: &mut Value) {
+        if self.schema.is_empty() {
+            return;
+        }
+
+        match *value {
+            Value::Object(ref mut fields) => for (name, field) in fields.iter_mut() {
+                let translated = match (name.as_str(), &*field) {
+                    ("description", &Value::String(ref text)) |
+                    ("deprecationReason", &Value::String(ref text)) =>
+                        self.schema.get(text).cloned(),
+                    _ => None
+                };
+
+                match translated {
+                    Some(text) => *field = Value::String(text),
+                    None => self.describe(field)
+                }
+            },
+            Value::List(ref mut items) => for item in items {
+                self.describe(item);
+            },
+            _ => ()
+        }
+    }
+}
+
+/// The supported locale for a language tag like `es-MX`, if there is one.
+pub fn supported(tag: &str) -> Option<&'static str> {
+    let language = tag.split(|c| c == '-' || c == '_').next().unwrap_or("").to_lowercase();
+    LOCALES.iter().cloned().find(|&locale| locale == language)
+}
+
+/// The supported locale the client likes most, if it likes any.
+pub fn negotiate(accept: &AcceptLanguage) -> Option<&'static str> {
+    let mut languages = accept.0.iter()
+        .filter(|x| x.quality.0 > 0)
+        .collect::<Vec<_>>();
+
+    // Stable, so ties keep the client's order.
+    languages.sort_by(|a, b| b.quality.cmp(&a.quality));
+    languages.iter()
+        .filter_map(|x| x.item.language.as_ref())
+        .filter_map(|x| supported(x))
+        .next()
+}
@@ -0,0 +1,240 @@
+use config::Config;
+use libc;
+use pq_sys::*;
+use serde_json;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+/// The channel the database triggers notify on.
+const CHANNEL: &'static str = "changes";
+
+/// How many changes can wait for a subscriber before they're dropped for
+/// falling behind.
+const BACKLOG: usize = 64;
+
+/// A row that was inserted, updated or deleted, along with whatever's
+/// needed to work out who's allowed to hear about it.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Change {
+    pub table: String,
+    pub operation: String, // INSERT, UPDATE or DELETE.
+    pub id: i64,
+    pub user: Option<i64>,
+    pub user_uuid: Option<String>,
+    pub location: Option<i64>,
+    pub location_uuid: Option<String>,
+    pub old_location: Option<i64>, // A user's training location before.
+    pub public: Option<bool> // Whether a location is public.
+}
+
+/// Passes changes along to everyone who's subscribed to them.
+#[derive(Clone)]
+pub struct Listener {
+    subscribers: Arc<Mutex<Subscribers>>,
+    max: usize,
+    max_per_user: usize
+}
+
+struct Subscribers {
+    senders: Vec<SyncSender<Change>>,
+    open: HashMap<i64, usize> // How many subscriptions each user has open.
+}
+
+/// Changes for one subscriber. Dropping it frees up their place.
+pub struct Subscription {
+    pub changes: Receiver<Change>,
+    listener: Listener,
+    user: i64
+}
+
+/// Why someone couldn't subscribe.
+pub enum Full {
+    Everyone,
+    User
+}
+
+impl Listener {
+    /// Connect to the database and start listening in the background.
+    pub fn start(config: &Config) -> Result<Listener, String> {
+        let conn = Connection::open(&config.database_url)?;
+        let listener = Listener {
+            subscribers: Arc::new(Mutex::new(Subscribers {
+                senders: Vec::new(),
+                open: HashMap::new()
+            })),
+            max: config.max_subscribers,
+            max_per_user: config.max_subscribers_per_user
+        };
+
+        let background = listener.clone();
+        thread::spawn(move || background.run(conn));
+
+        Ok(listener)
+    }
+
+    /// Every change from now on for the user, unless there are already too
+    /// many subscribers.
+    pub fn subscribe(&self, user: i64) -> Result<Subscription, Full> {
+        let mut guard = self.subscribers.lock().unwrap();
+        let subscribers = &mut *guard;
+        if subscribers.open.values().sum::<usize>() >= self.max {
+            return Err(Full::Everyone);
+        }
+
+        let open = subscribers.open.entry(user).or_insert(0);
+        if *open >= self.max_per_user {
+            return Err(Full::User);
+        }
+        *open += 1;
+
+        let (sender, receiver) = mpsc::sync_channel(BACKLOG);
+        subscribers.senders.push(sender);
+        Ok(Subscription { changes: receiver, listener: self.clone(), user: user })
+    }
+
+    fn run(&self, conn: Connection) {
+        loop {
+            match conn.wait() {
+                Ok(changes) => self.publish(changes),
+                Err(e) => {
+                    error!("Lost the connection for changes: {}.", e);
+                    thread::sleep(Duration::from_secs(5));
+                    if let Err(e) = conn.reset() {
+                        error!("Could not reconnect for changes: {}.", e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn publish(&self, changes: Vec<Change>) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for change in changes {
+            // Anyone who's gone away has dropped their receiver, and anyone
+            // who's fallen behind gets cut off and has to subscribe again.
+            subscribers.senders.retain(|sender| sender.try_send(change.clone()).is_ok());
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut subscribers = self.listener.subscribers.lock().unwrap();
+        let gone = match subscribers.open.get_mut(&self.user) {
+            Some(open) => {
+                *open -= 1;
+                *open == 0
+            },
+            None => false
+        };
+
+        if gone {
+            subscribers.open.remove(&self.user);
+        }
+    }
+}
+
+/// A bare libpq connection, since diesel doesn't let on about notifications.
+struct Connection(*mut PGconn);
+
+// Connections can move between threads, so long as only one uses them.
+unsafe impl Send for Connection {}
+
+impl Connection {
+    fn open(url: &str) -> Result<Connection, String> {
+        let url = CString::new(url).map_err(|_| "invalid database URL".to_owned())?;
+        let conn = Connection(unsafe { PQconnectdb(url.as_ptr()) });
+        conn.check()?;
+        conn.listen()?;
+        Ok(conn)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if unsafe { PQstatus(self.0) } == CONNECTION_OK {
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn error(&self) -> String {
+        let message = unsafe { CStr::from_ptr(PQerrorMessage(self.0)) };
+        message.to_string_lossy().trim().to_owned()
+    }
+
+    fn listen(&self) -> Result<(), String> {
+        let command = CString::new(format!("LISTEN {}", CHANNEL)).unwrap();
+        let ok = unsafe {
+            let res = PQexec(self.0, command.as_ptr());
+            let ok = PQresultStatus(res) == PGRES_COMMAND_OK;
+            PQclear(res);
+            ok
+        };
+
+        if ok { Ok(()) } else { Err(self.error()) }
+    }
+
+    fn reset(&self) -> Result<(), String> {
+        unsafe { PQreset(self.0) };
+        self.check()?;
+        self.listen()
+    }
+
+    /// Block until something arrives, and return any changes that did.
+    fn wait(&self) -> Result<Vec<Change>, String> {
+        let mut fd = libc::pollfd {
+            fd: unsafe { PQsocket(self.0) },
+            events: libc::POLLIN,
+            revents: 0
+        };
+
+        if fd.fd < 0 {
+            return Err(self.error());
+        }
+
+        if unsafe { libc::poll(&mut fd, 1, -1) } < 0 {
+            let e = io::Error::last_os_error();
+            return if e.kind() == io::ErrorKind::Interrupted {
+                Ok(Vec::new())
+            } else {
+                Err(e.to_string())
+            };
+        }
+
+        if unsafe { PQconsumeInput(self.0) } == 0 {
+            return Err(self.error());
+        }
+
+        let mut changes = Vec::new();
+        loop {
+            let notify = unsafe { PQnotifies(self.0) };
+            if notify.is_null() {
+                break;
+            }
+
+            let payload = unsafe {
+                let payload = CStr::from_ptr((*notify).extra).to_string_lossy().into_owned();
+                PQfreemem(notify as *mut _);
+                payload
+            };
+
+            match serde_json::from_str(&payload) {
+                Ok(change) => changes.push(change),
+                Err(e) => warn!("Ignoring a malformed change ({}): {}.", e, payload)
+            }
+        }
+
+        Ok(changes)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { PQfinish(self.0) };
+    }
+}
@@ -6,7 +6,7 @@ extern crate r2d2_diesel;
 
 // Serialization libraries.
 #[macro_use] extern crate serde_derive;
-extern crate serde_json;
+#[macro_use] extern crate serde_json;
 extern crate serde;
 extern crate bincode;
 extern crate base64;
@@ -23,15 +23,22 @@ extern crate router; // Routing.
 
 // Other libraries.
 #[macro_use] extern crate juniper; // Query.
+#[macro_use] extern crate lazy_static; // Globals.
 extern crate byteorder; // Numbers <-> bytes.
 extern crate chrono; // Time.
 extern crate lettre; // Email.
+extern crate libc; // Waiting on sockets.
+extern crate pq_sys; // Postgres notifications.
 extern crate regex; // Regular expressions.
 extern crate sodiumoxide; // Cryptography.
 extern crate unicase; // Case-insensitivity.
+extern crate uuid; // Public IDs.
 
 use config::Config;
 use database::Database;
+use email::Templates;
+use listener::Listener;
+use payments::Payments;
 use iron::headers::*;
 use iron::prelude::*;
 use iron::method::Method;
@@ -40,21 +47,23 @@ use logger::Logger;
 use persistent::Read;
 use unicase::UniCase;
 use std::net::Ipv4Addr;
+use std::sync::Arc;
 
 mod auth;
 mod conditions;
 mod config;
 mod database;
 mod email;
+mod i18n;
+mod listener;
+mod payments;
 mod routes;
 mod schema;
 
-//LONG: i18n
 //LONG: Some kind of init script.
 //TODO: birthday + weight + height
 //TODO: gradings + belt
 //LONG: public profiles
-//LONG: SWITCH TO UUIDS BECAUSE SERIALS ARE A HUGE SECURITY ISSUE HERE.
 //TODO: registration
 //TODO: home address, phone, mobile
 //TODO: emergency contacts
@@ -63,7 +72,7 @@ fn main() {
     // Initialize the environment.
     badlog::init_from_env("LOG_LEVEL");
     sodiumoxide::init();
-    let config = Config::get();
+    let config = Arc::new(Config::get());
 
     // Set up the database.
     let db = match Database::new(&config) {
@@ -74,6 +83,30 @@ fn main() {
         }
     };
 
+    // Load the email templates.
+    let templates = match Templates::load(&config.email_templates) {
+        Ok(templates) => templates,
+        Err(e) => {
+            error!("Could not load the email templates: {}.", e);
+            return;
+        }
+    };
+
+    // Choose who takes payments.
+    let payments = Payments::new(&config);
+
+    // Start sending emails.
+    email::outbox::start(config.clone(), db.clone());
+
+    // Start listening for changes.
+    let listener = match Listener::start(&config) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not listen for changes: {}.", e);
+            return;
+        }
+    };
+
     // Register some post-processing.
     let access = AccessControlAllowOrigin::Value(config.frontend_url.clone());
     let headers = AccessControlAllowHeaders(vec![
@@ -95,14 +128,19 @@ fn main() {
 
     // Start the server.
     let addr = (Ipv4Addr::new(0, 0, 0, 0), config.port);
+    let threads = config.threads;
     
-    let mut chain = Chain::new(routes::build());
+    let mut chain = Chain::new(routes::build(&config, listener));
     chain.link_before(db);
     chain.link_before(Read::<Config>::one(config));
+    chain.link_before(Read::<Templates>::one(templates));
+    chain.link_before(Read::<Payments>::one(payments));
     chain.link_after(process);
     chain.link(Logger::new(None));
 
-    match Iron::new(chain).http(addr) { //LONG: HTTPS/2
+    let mut iron = Iron::new(chain);
+    iron.threads = threads;
+    match iron.http(addr) { //LONG: HTTPS/2
         Ok(x) => info!("Listening on {}!", x.socket),
         Err(e) => error!("Could not initialize server: {}.", e)
     }
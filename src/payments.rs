@@ -0,0 +1,147 @@
+//! Taking payments through a provider, chosen by `PAYMENT_PROVIDER`.
+//! Payments made in person are recorded without one.
+
+use config::{Config, PaymentProvider};
+use iron::typemap::Key;
+use std::fmt::{self, Display};
+#[cfg(any(test, feature = "fake-payments"))]
+use chrono::UTC;
+#[cfg(any(test, feature = "fake-payments"))]
+use std::collections::HashMap;
+#[cfg(any(test, feature = "fake-payments"))]
+use std::sync::Mutex;
+
+pub trait Provider: Send + Sync {
+    /// What payments through it are recorded as coming from.
+    fn name(&self) -> &'static str;
+
+    /// Take money from a source, such as a card token from the provider's
+    /// own checkout form, returning the provider's reference for it.
+    /// Charging again with the same idempotency key mustn't take any more
+    /// money, and returns the first charge's reference.
+    fn charge(&self, charge: &Charge) -> Result<String, Error>;
+}
+
+pub struct Charge<'a> {
+    pub amount: i64, // In cents.
+    pub currency: &'a str,
+    pub source: &'a str,
+    pub description: &'a str,
+    pub idempotency_key: &'a str
+}
+
+pub enum Error {
+    /// The payment was refused, so there's no point trying again.
+    Declined(String),
+    /// The provider couldn't be reached or had a problem of its own.
+    Unavailable(String)
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Declined(ref e) => write!(f, "declined: {}", e),
+            Error::Unavailable(ref e) => write!(f, "unavailable: {}", e)
+        }
+    }
+}
+
+/// The provider the config asks for, if any, shared between requests.
+pub struct Payments {
+    provider: Option<Box<Provider>>
+}
+
+impl Payments {
+    pub fn new(config: &Config) -> Payments {
+        let provider: Option<Box<Provider>> = match config.payment_provider {
+            PaymentProvider::None => None,
+            #[cfg(any(test, feature = "fake-payments"))]
+            PaymentProvider::Fake => Some(Box::new(Fake::default()))
+        };
+
+        Payments { provider: provider }
+    }
+
+    pub fn provider(&self) -> Option<&Provider> {
+        self.provider.as_ref().map(|x| &**x)
+    }
+}
+
+impl Key for Payments {
+    type Value = Payments;
+}
+
+/// Approves every charge, except from sources starting with `decline`,
+/// without moving any money. For development and tests.
+#[cfg(any(test, feature = "fake-payments"))]
+#[derive(Default)]
+pub struct Fake {
+    charges: Mutex<HashMap<String, String>> // Idempotency keys to references.
+}
+
+#[cfg(any(test, feature = "fake-payments"))]
+impl Provider for Fake {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    fn charge(&self, charge: &Charge) -> Result<String, Error> {
+        if charge.source.starts_with("decline") {
+            return Err(Error::Declined("the fake declines these".to_owned()));
+        }
+
+        let mut charges = self.charges.lock().unwrap();
+        if let Some(reference) = charges.get(charge.idempotency_key) {
+            return Ok(reference.clone());
+        }
+
+        info!(
+            "Pretending to charge {} {} for {}.",
+            charge.amount,
+            charge.currency,
+            charge.description
+        );
+        let reference = format!("fake_{}_{}", UTC::now().timestamp(), charges.len());
+        charges.insert(charge.idempotency_key.to_owned(), reference.clone());
+        Ok(reference)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Charge, Error, Fake, Provider};
+
+    fn charge<'a>(source: &'a str, key: &'a str) -> Charge<'a> {
+        Charge {
+            amount: 5000,
+            currency: "AUD",
+            source: source,
+            description: "a test",
+            idempotency_key: key
+        }
+    }
+
+    #[test]
+    fn fake_approves_with_unique_references() {
+        let fake = Fake::default();
+        let first = fake.charge(&charge("card", "a")).ok().unwrap();
+        let second = fake.charge(&charge("card", "b")).ok().unwrap();
+        assert!(first != second);
+    }
+
+    #[test]
+    fn fake_charges_once_per_key() {
+        let fake = Fake::default();
+        let first = fake.charge(&charge("card", "a")).ok().unwrap();
+        let again = fake.charge(&charge("card", "a")).ok().unwrap();
+        assert_eq!(first, again);
+    }
+
+    #[test]
+    fn fake_declines_on_request() {
+        match Fake::default().charge(&charge("decline_card", "a")) {
+            Err(Error::Declined(_)) => (),
+            _ => panic!("expected the charge to be declined")
+        }
+    }
+}
@@ -1,9 +1,8 @@
 use auth::{self, Credentials, ResetConfirmation};
 use config::Config;
 use base64;
-use bincode;
-use database::Database;
-use email;
+use database;
+use email::{self, Templates};
 use iron::prelude::*;
 use iron::status;
 use persistent::Read;
@@ -25,9 +24,13 @@ pub struct RawResetRequest {
 /// Status Codes:
 ///     200: Login successful.
 ///     422: Incorrect username or password.
+///     503: There wasn't a database connection to spare.
 pub fn login(req: &mut Request) -> IronResult<Response> {
     let config = req.extensions.get::<Read<Config>>().unwrap();
-    let db = req.extensions.get::<Database>().unwrap().get().unwrap();
+    let db = match database::connection(req) {
+        Ok(db) => db,
+        Err(res) => return Ok(res)
+    };
 
     let creds = serde_json::from_reader::<_, Credentials>(&mut req.body);
     let creds = match creds {
@@ -38,12 +41,7 @@ pub fn login(req: &mut Request) -> IronResult<Response> {
     match auth::login(&db, creds, config.session_length) {
         Ok(cookie) => {
             let sealed = cookie.seal(config.secret);
-            let encoded = bincode::serialize(
-                &sealed,
-                bincode::Infinite
-            ).unwrap();
-            let strified = base64::encode(&*encoded);
-            Ok(Response::with((status::Ok, strified)))
+            Ok(Response::with((status::Ok, sealed.encode())))
         },
         Err(()) => Ok(Response::with(status::UnprocessableEntity))
     }
@@ -53,13 +51,18 @@ pub fn login(req: &mut Request) -> IronResult<Response> {
 /// Body:
 ///     The user's email.
 /// Status Codes:
-///     200: If the user exists, the email was sent.
+///     200: If the user exists, the email was queued to be sent.
 ///     400: Bad message body.
 ///     422: Invalid email address.
+///     503: There wasn't a database connection to spare.
 pub fn forgot(req: &mut Request) -> IronResult<Response> {
     //LONG: Something along the lines of RECAPTCHA.
     let config = req.extensions.get::<Read<Config>>().unwrap();
-    let db = req.extensions.get::<Database>().unwrap().get().unwrap();
+    let templates = req.extensions.get::<Read<Templates>>().unwrap();
+    let db = match database::connection(req) {
+        Ok(db) => db,
+        Err(res) => return Ok(res)
+    };
 
     let email = match serde_json::from_reader::<_, String>(&mut req.body) {
         Err(_)
@@ -71,8 +74,8 @@ pub fn forgot(req: &mut Request) -> IronResult<Response> {
     };
 
     if let Ok(info) = auth::forgot(&db, config.secret, &email) {
-        if let Err(e) = email::reset_password(&config, info, &email) {
-            error!("Failed to send reset email: {}.", e);
+        if let Err(e) = email::reset_password(&config, &templates, &db, info, &email) {
+            error!("Failed to queue reset email: {}.", e);
         }
     }
 
@@ -89,9 +92,13 @@ pub fn forgot(req: &mut Request) -> IronResult<Response> {
 ///     400: Bad message body.
 ///     422: The code has expired or was invalid.
 ///     500: The server couldn't update the password.
+///     503: There wasn't a database connection to spare.
 pub fn reset(req: &mut Request) -> IronResult<Response> {
     let config = req.extensions.get::<Read<Config>>().unwrap();
-    let db = req.extensions.get::<Database>().unwrap().get().unwrap();
+    let db = match database::connection(req) {
+        Ok(db) => db,
+        Err(res) => return Ok(res)
+    };
 
     let de = serde_json::from_reader::<_, RawResetRequest>(&mut req.body);
     let conf = match de {
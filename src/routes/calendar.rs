@@ -0,0 +1,294 @@
+//! iCalendar (RFC 5545) feeds of class sessions, for calendar apps to
+//! subscribe to. Apps can't log in, so private feeds are found by a secret
+//! token in their URL instead.
+
+use auth;
+use base64;
+use chrono::{DateTime, Duration, UTC};
+use conditions::{self, Cache, Memo};
+use config::Config;
+use database;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use iron::prelude::*;
+use iron::mime::Mime;
+use iron::status;
+use juniper::ID;
+use persistent::Read;
+use router::Router;
+use schema::{users, locations, instructor_locations};
+use std::collections::HashMap;
+use super::query::node;
+use super::query::timetable::{self, Session};
+use uuid::Uuid;
+
+//TODO: Gradings and tournaments, once they're scheduled anywhere.
+
+/// How far back feeds go, in days.
+const PAST_DAYS: i64 = 30;
+/// How far ahead feeds go, in days.
+const FUTURE_DAYS: i64 = 60;
+
+/// GET /calendar/locations/:id.ics
+/// Response:
+///     The location's classes from a month ago to two months ahead, as an
+///     iCalendar feed. Cancelled sessions are marked as such.
+/// Status Codes:
+///     200: Here's the feed.
+///     404: There's no public location with that ID.
+///     500: The sessions couldn't be looked up.
+///     503: There wasn't a database connection to spare.
+pub fn location(req: &mut Request) -> IronResult<Response> {
+    let config = req.extensions.get::<Read<Config>>().unwrap().clone();
+    let db = match database::connection(req) {
+        Ok(db) => db,
+        Err(res) => return Ok(res)
+    };
+
+    let location = match feed_id(req).and_then(|id| node::location(&*db, &id, "id").ok()) {
+        Some(location) => location,
+        None => return Ok(Response::with(status::NotFound))
+    };
+
+    let found = locations::table.find(location)
+        .filter(locations::public.eq(true))
+        .select(locations::name)
+        .first::<String>(&*db)
+        .optional();
+
+    let name = match found {
+        Ok(Some(name)) => name,
+        Ok(None) => return Ok(Response::with(status::NotFound)),
+        Err(e) => {
+            error!("Could not look up a location for its calendar: {}.", e);
+            return Ok(Response::with(status::InternalServerError));
+        }
+    };
+
+    let memo = Memo::default();
+    let cache = Cache::new(None, None, &db, &memo);
+    feed(&config, cache, &name, &[location])
+}
+
+/// GET /calendar/users/:id.ics
+/// Query:
+///     token: The secret from the user's `calendarUrl`.
+/// Response:
+///     The classes where the user trains or instructs, from a month ago to
+///     two months ahead, as an iCalendar feed.
+/// Status Codes:
+///     200: Here's the feed.
+///     404: There's no such user, or the token is wrong or was reset.
+///     500: The token or the sessions couldn't be looked up.
+///     503: There wasn't a database connection to spare.
+pub fn user(req: &mut Request) -> IronResult<Response> {
+    let config = req.extensions.get::<Read<Config>>().unwrap().clone();
+    let db = match database::connection(req) {
+        Ok(db) => db,
+        Err(res) => return Ok(res)
+    };
+
+    let user = match feed_id(req).and_then(|id| node::user(&*db, &id, "id").ok()) {
+        Some(user) => user,
+        None => return Ok(Response::with(status::NotFound))
+    };
+
+    // Nothing here ever needs decoding.
+    let token = req.url.query().unwrap_or("")
+        .split('&')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("token"), Some(value)) => Some(value),
+                _ => None
+            }
+        })
+        .next()
+        .and_then(|token| base64::decode_config(token, base64::URL_SAFE).ok());
+
+    let valid = match token {
+        Some(token) => auth::verify_calendar_token(&*db, user, &token),
+        None => Ok(false)
+    };
+
+    match valid {
+        Ok(true) => (),
+        Ok(false) => return Ok(Response::with(status::NotFound)),
+        Err(e) => {
+            error!("Could not check a calendar token for user {}: {}.", user, e);
+            return Ok(Response::with(status::InternalServerError));
+        }
+    }
+
+    let locations = match places(&*db, user) {
+        Ok(locations) => locations,
+        Err(e) => {
+            error!("Could not look up where user {} trains for their calendar: {}.", user, e);
+            return Ok(Response::with(status::InternalServerError));
+        }
+    };
+
+    let memo = Memo::default();
+    let cache = Cache::new(Some(user), None, &db, &memo);
+    feed(&config, cache, "TTKKDD", &locations)
+}
+
+/// The address of the user's private feed, with the given token.
+pub fn user_url(config: &Config, uuid: Uuid, token: &[u8]) -> String {
+    format!(
+        "{}/calendar/users/{}.ics?token={}",
+        config.public_url,
+        &*node::encode(node::USER, uuid),
+        base64::encode_config(token, base64::URL_SAFE)
+    )
+}
+
+/// The address of a location's public feed.
+pub fn location_url(config: &Config, uuid: Uuid) -> String {
+    format!(
+        "{}/calendar/locations/{}.ics",
+        config.public_url,
+        &*node::encode(node::LOCATION, uuid)
+    )
+}
+
+/// The ID in the `:id.ics` at the end of the path.
+fn feed_id(req: &Request) -> Option<ID> {
+    let file = req.extensions.get::<Router>().unwrap().find("file")?;
+    if file.ends_with(".ics") {
+        Some(ID::from(file[..file.len() - 4].to_owned()))
+    } else {
+        None
+    }
+}
+
+/// Where the user trains and instructs.
+fn places(db: &PgConnection, user: i64) -> QueryResult<Vec<i64>> {
+    let mut places = instructor_locations::table
+        .filter(instructor_locations::instructor_id.eq(user))
+        .filter(conditions::active_assignment())
+        .select(instructor_locations::location_id)
+        .get_results::<i64>(db)?;
+
+    let training = users::table.find(user)
+        .select(users::training_location)
+        .first::<Option<i64>>(db)?;
+
+    places.extend(training);
+    places.sort();
+    places.dedup();
+    Ok(places)
+}
+
+/// Respond with the sessions at the locations as a calendar.
+fn feed(config: &Config, cache: Cache, name: &str, places: &[i64]) -> IronResult<Response> {
+    let now = UTC::now();
+    let from = now - Duration::days(PAST_DAYS);
+    let to = now + Duration::days(FUTURE_DAYS);
+
+    let mut sessions = Vec::new();
+    for &place in places {
+        match timetable::timetable(cache, place, from, to) {
+            Ok(found) => sessions.extend(found),
+            Err(e) => {
+                error!("Could not work out the sessions for a calendar: {:?}.", e);
+                return Ok(Response::with(status::InternalServerError));
+            }
+        }
+    }
+
+    let found = locations::table
+        .filter(locations::id.eq_any(places))
+        .select((locations::id, locations::name, locations::address))
+        .get_results::<(i64, String, String)>(&**cache.database());
+
+    let addresses = match found {
+        Ok(found) => found.into_iter()
+            .map(|(id, name, address)| (id, format!("{}, {}", name, address)))
+            .collect::<HashMap<_, _>>(),
+        Err(e) => {
+            error!("Could not look up the locations for a calendar: {}.", e);
+            return Ok(Response::with(status::InternalServerError));
+        }
+    };
+
+    let mime = "text/calendar; charset=utf-8".parse::<Mime>().unwrap();
+    let body = calendar(config, name, &sessions, &addresses, now);
+    Ok(Response::with((mime, status::Ok, body)))
+}
+
+fn calendar(
+    config: &Config,
+    name: &str,
+    sessions: &[Session],
+    addresses: &HashMap<i64, String>,
+    now: DateTime<UTC>
+) -> String {
+    // UIDs have to be unique everywhere, so they end with our domain.
+    let host = config.public_url.split("://").last().unwrap_or("").trim_right_matches('/');
+
+    let mut ics = String::new();
+    line(&mut ics, "BEGIN:VCALENDAR");
+    line(&mut ics, "VERSION:2.0");
+    line(&mut ics, "PRODID:-//TTKKDD//Timetable//EN");
+    line(&mut ics, "CALSCALE:GREGORIAN");
+    line(&mut ics, "METHOD:PUBLISH");
+    line(&mut ics, &format!("X-WR-CALNAME:{}", escape(name)));
+
+    for session in sessions {
+        let class = session.class();
+        let date = session.date();
+        line(&mut ics, "BEGIN:VEVENT");
+        line(&mut ics, &format!("UID:class-{}-{}@{}", class.id(), date.format("%Y%m%d"), host));
+        line(&mut ics, &format!("DTSTAMP:{}", stamp(now)));
+        line(&mut ics, &format!("DTSTART:{}", stamp(session.start())));
+        line(&mut ics, &format!("DTEND:{}", stamp(session.end())));
+        line(&mut ics, &format!("SUMMARY:{}", escape(class.title())));
+        if let Some(address) = addresses.get(&class.location()) {
+            line(&mut ics, &format!("LOCATION:{}", escape(address)));
+        }
+        if session.cancelled() {
+            line(&mut ics, "STATUS:CANCELLED");
+        }
+        if let Some(reason) = session.reason() {
+            line(&mut ics, &format!("DESCRIPTION:{}", escape(reason)));
+        }
+        line(&mut ics, "END:VEVENT");
+    }
+
+    line(&mut ics, "END:VCALENDAR");
+    ics
+}
+
+fn stamp(time: DateTime<UTC>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Add a content line, folded so no line is longer than 75 bytes.
+fn line(ics: &mut String, content: &str) {
+    let mut width = 0;
+    for c in content.chars() {
+        if width + c.len_utf8() > 75 {
+            ics.push_str("\r\n ");
+            width = 1;
+        }
+        ics.push(c);
+        width += c.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+fn escape(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => res.push_str("\\\\"),
+            ';' => res.push_str("\\;"),
+            ',' => res.push_str("\\,"),
+            '\n' => res.push_str("\\n"),
+            '\r' => (),
+            c => res.push(c)
+        }
+    }
+    res
+}
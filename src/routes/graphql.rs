@@ -0,0 +1,217 @@
+use conditions;
+use config::Config;
+use diesel::Connection;
+use diesel::result::Error as DieselError;
+use i18n::Catalog;
+use iron::prelude::*;
+use iron::middleware::Handler;
+use iron::mime::Mime;
+use iron::status;
+use juniper::{self, InputValue, RootNode};
+use persistent::Read;
+use serde::Serialize;
+use serde_json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use super::query::{Context, Mutate, Query};
+use super::limits;
+use super::persisted::PersistedQueries;
+use super::query::error::{self, Code, Error};
+
+#[derive(Deserialize)]
+pub struct GraphQLRequest {
+    pub id: Option<String>,
+    pub query: Option<String>,
+    #[serde(rename = "operationName")]
+    pub operation_name: Option<String>,
+    pub variables: Option<HashMap<String, InputValue>>,
+    pub transaction: Option<bool>
+}
+
+/// POST /
+/// Body:
+///     query: The GraphQL query document.
+///     id: Instead of the query, the SHA-256 hash of a persisted one.
+///     operationName: The operation to run, if there are several.
+///     variables: An object of variable values.
+///     transaction: If true, the operation runs in a single transaction, and
+///                  if any field fails, nothing it did is kept and the data
+///                  is null.
+/// Status Codes:
+///     200: The query was run (individual fields may still have failed, in
+///          which case their errors have a `code` and maybe a `field`).
+///     400: Bad message body, or the query couldn't be parsed or validated,
+///          or it has no operation with the given name.
+///     403: Only persisted queries are allowed, except for admins.
+///     404: There's no persisted query with that id.
+///     413: The query is too deep, too complex, or asks for too long a list.
+///     500: The query couldn't be looked up or recorded, so it wasn't run.
+///     503: There wasn't a database connection to spare.
+pub struct GraphQLHandler {
+    root: RootNode<'static, Query, Mutate>,
+    persisted: Arc<PersistedQueries>
+}
+
+impl GraphQLHandler {
+    pub fn new(persisted: Arc<PersistedQueries>) -> GraphQLHandler {
+        GraphQLHandler {
+            root: RootNode::new(Query, Mutate),
+            persisted: persisted
+        }
+    }
+}
+
+impl Handler for GraphQLHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let body = match serde_json::from_reader::<_, GraphQLRequest>(&mut req.body) {
+            Ok(body) => body,
+            Err(_) => return Ok(Response::with(status::BadRequest))
+        };
+
+        let config = req.extensions.get::<Read<Config>>().unwrap().clone();
+        let context = match Context::from_request(req) {
+            Ok(context) => context,
+            Err(res) => return Ok(res)
+        };
+
+        let query = match document(&self.persisted, &config, &context, body.id, body.query) {
+            Ok(query) => query,
+            Err(res) => return Ok(res)
+        };
+
+        let variables = body.variables.unwrap_or_default();
+        let operation_name = body.operation_name.as_ref().map(|x| x.as_str());
+        if let Err(e) = limits::check(
+            &self.root,
+            &config,
+            &query,
+            operation_name,
+            &variables
+        ) {
+            return Ok(refuse(limits::status(&e), e, context.locale()));
+        }
+
+        let entry = match context.record(&query) {
+            Ok(entry) => entry,
+            Err(e) => {
+                error!("Failed to record an impersonated query: {}.", e);
+                return Ok(Response::with(status::InternalServerError));
+            }
+        };
+
+        let execute = || context.attributed(entry, || juniper::execute(
+            &query,
+            operation_name,
+            &self.root,
+            &variables,
+            &context
+        ));
+
+        let mut rolled_back = false;
+        let result = if body.transaction.unwrap_or(false) {
+            let mut result = None;
+            let outcome = context.database().transaction(|| {
+                let attempt = execute();
+                let failed = match attempt {
+                    Ok(Ok((_, ref errors))) => !errors.is_empty(),
+                    _ => true
+                };
+
+                result = Some(attempt);
+                if failed { Err(DieselError::RollbackTransaction) } else { Ok(()) }
+            });
+
+            match outcome {
+                Ok(()) => result.unwrap(),
+                Err(DieselError::RollbackTransaction) => {
+                    rolled_back = true;
+                    result.unwrap()
+                },
+                Err(e) => {
+                    error!("Failed to run a query in a transaction: {}.", e);
+                    return Ok(Response::with(status::InternalServerError));
+                }
+            }
+        } else {
+            execute()
+        };
+
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Failed to attribute an impersonated query: {}.", e);
+                return Ok(Response::with(status::InternalServerError));
+            }
+        };
+
+        let result = result.map(|(mut data, errors)| {
+            if introspects(&query) {
+                Catalog::get(context.locale()).describe(&mut data);
+            }
+            (data, errors)
+        });
+
+        Ok(match result {
+            Ok((data, errors)) => if errors.is_empty() {
+                respond(status::Ok, &json!({ "data": data }))
+            } else if rolled_back {
+                let errors = error::describe(&errors, context.locale());
+                respond(status::Ok, &json!({ "data": null, "errors": errors }))
+            } else {
+                let errors = error::describe(&errors, context.locale());
+                respond(status::Ok, &json!({ "data": data, "errors": errors }))
+            },
+            Err(e) => respond(status::BadRequest, &json!({ "errors": e }))
+        })
+    }
+}
+
+/// The query to run, either as given or from the persisted queries, or
+/// the response if it can't be run.
+pub fn document(
+    persisted: &PersistedQueries,
+    config: &Config,
+    context: &Context,
+    id: Option<String>,
+    query: Option<String>
+) -> Result<String, Response> {
+    match (id, query) {
+        (Some(id), None) => match persisted.get(&id, context.database()) {
+            Ok(Some(query)) => Ok(query),
+            Ok(None) => {
+                let e = Error::new(Code::UnknownQuery, "no such persisted query");
+                Err(refuse(status::NotFound, e.on("id"), context.locale()))
+            },
+            Err(e) => {
+                error!("Failed to look up a persisted query: {}.", e);
+                Err(Response::with(status::InternalServerError))
+            }
+        },
+        (None, Some(query)) => {
+            if config.query_allowlist && !context.may(conditions::run_ad_hoc_query) {
+                let e = Error::new(Code::Unauthorized, "only persisted queries are allowed");
+                Err(refuse(status::Forbidden, e.on("query"), context.locale()))
+            } else {
+                Ok(query)
+            }
+        },
+        _ => Err(Response::with(status::BadRequest))
+    }
+}
+
+/// Refuse to run the query at all.
+pub fn refuse(code: status::Status, err: Error, locale: &str) -> Response {
+    respond(code, &json!({ "errors": [err.describe(locale)] }))
+}
+
+/// Whether the query asks about the schema, whose descriptions should be
+/// translated. Other data is left alone in case it happens to match.
+fn introspects(query: &str) -> bool {
+    query.contains("__schema") || query.contains("__type")
+}
+
+pub fn respond<T: Serialize>(code: status::Status, body: &T) -> Response {
+    let mime = "application/json".parse::<Mime>().unwrap();
+    let json = serde_json::to_string(body).unwrap();
+    Response::with((mime, code, json))
+}
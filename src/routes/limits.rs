@@ -0,0 +1,575 @@
+//! Rejects queries that would be too expensive to run, before running them.
+//!
+//! Juniper doesn't expose its syntax tree, so this works off its lexer and
+//! only understands enough of the grammar to find the selections. Anything
+//! it can't make sense of is refused, since there's no telling what it
+//! would cost.
+
+use config::Config;
+use iron::status::{self, Status};
+use juniper::{RootNode, Type, Variables};
+use juniper::meta::{Field, MetaType};
+use juniper::parser::{Lexer, Token};
+use std::collections::HashMap;
+use super::query::error::{Code, Error};
+
+enum Selection<'a> {
+    Field {
+        name: &'a str,
+        arguments: Vec<(&'a str, Argument<'a>)>,
+        children: Vec<Selection<'a>>
+    },
+    Spread(&'a str),
+    Inline(Option<&'a str>, Vec<Selection<'a>>)
+}
+
+enum Argument<'a> {
+    Int(i64),
+    Null,
+    Variable(&'a str),
+    Other
+}
+
+struct Operation<'a> {
+    name: Option<&'a str>,
+    mutation: bool,
+    defaults: HashMap<&'a str, Argument<'a>>, // For variables that have one.
+    selections: Vec<Selection<'a>>
+}
+
+struct Document<'a> {
+    operations: Vec<Operation<'a>>,
+    fragments: HashMap<&'a str, (&'a str, Vec<Selection<'a>>)>
+}
+
+/// Check the operation that's going to run against the configured limits.
+pub fn check<Q, M>(
+    root: &RootNode<'static, Q, M>,
+    config: &Config,
+    query: &str,
+    operation_name: Option<&str>,
+    variables: &Variables
+) -> Result<(), Error> {
+    let document = match parse(query) {
+        Some(document) => document,
+        None => return Err(Error::invalid("query", "couldn't parse the query"))
+    };
+
+    let operation = find(&document, operation_name)?;
+
+    let root_type = if operation.mutation {
+        root.schema.concrete_mutation_type()
+    } else {
+        Some(root.schema.concrete_query_type())
+    };
+
+    let analysis = Analysis {
+        lookup: &|name| root.schema.concrete_type_by_name(name),
+        fragments: &document.fragments,
+        variables: variables,
+        defaults: &operation.defaults,
+        config: config
+    };
+
+    let complexity = analysis.cost(
+        &operation.selections,
+        root_type,
+        1,
+        &mut Vec::new()
+    )?;
+
+    if complexity > config.max_query_complexity {
+        return Err(Error::new(Code::QueryTooComplex, format!(
+            "query complexity {} is over the limit of {}",
+            complexity,
+            config.max_query_complexity
+        )));
+    }
+
+    Ok(())
+}
+
+/// What to respond with when a query's refused.
+pub fn status(err: &Error) -> Status {
+    if err.code() == Code::InvalidArgument {
+        status::BadRequest
+    } else {
+        status::PayloadTooLarge
+    }
+}
+
+/// The operation that's going to run.
+fn find<'a>(
+    document: &'a Document<'a>,
+    operation_name: Option<&str>
+) -> Result<&'a Operation<'a>, Error> {
+    let operation = document.operations.iter().find(|op| {
+        operation_name.is_none() || op.name == operation_name
+    });
+
+    operation.ok_or_else(|| Error::invalid("operationName", "no such operation"))
+}
+
+struct Analysis<'a, 'b: 'a> {
+    lookup: &'a Fn(&str) -> Option<&'b MetaType<'b>>,
+    fragments: &'a HashMap<&'a str, (&'a str, Vec<Selection<'a>>)>,
+    variables: &'a Variables,
+    defaults: &'a HashMap<&'a str, Argument<'a>>,
+    config: &'a Config
+}
+
+impl<'a, 'b> Analysis<'a, 'b> {
+    /// Roughly how many values the selections would resolve, counting every
+    /// item of a list as many times as it could be there.
+    fn cost(
+        &self,
+        selections: &'a [Selection<'a>],
+        parent: Option<&'b MetaType<'b>>,
+        depth: usize,
+        spreads: &mut Vec<&'a str>
+    ) -> Result<u64, Error> {
+        if depth > self.config.max_query_depth {
+            return Err(Error::new(Code::QueryTooDeep, format!(
+                "query is nested deeper than the limit of {}",
+                self.config.max_query_depth
+            )));
+        }
+
+        let mut total: u64 = 0;
+
+        for selection in selections {
+            let cost = match *selection {
+                // Introspection only goes as deep as the schema does.
+                Selection::Field { name, .. } if name.starts_with("__") => 1,
+
+                Selection::Field { name, ref arguments, ref children } => {
+                    let field = parent.and_then(|p| p.field_by_name(name));
+                    let child = field.and_then(|f| {
+                        (self.lookup)(f.field_type.innermost_name())
+                    });
+
+                    let multiplier = self.multiplier(parent, field, arguments)?;
+                    let children = if children.is_empty() {
+                        0
+                    } else {
+                        self.cost(children, child, depth + 1, spreads)?
+                    };
+
+                    multiplier.saturating_mul(children).saturating_add(1)
+                },
+
+                Selection::Spread(name) => {
+                    // Cycles are for juniper to complain about.
+                    if spreads.contains(&name) {
+                        continue;
+                    }
+
+                    match self.fragments.get(name) {
+                        Some(&(condition, ref children)) => {
+                            spreads.push(name);
+                            let cost = self.cost(
+                                children,
+                                (self.lookup)(condition),
+                                depth,
+                                spreads
+                            );
+                            spreads.pop();
+                            cost?
+                        },
+                        None => 0
+                    }
+                },
+
+                Selection::Inline(condition, ref children) => {
+                    let parent = condition.and_then(|c| (self.lookup)(c)).or(parent);
+                    self.cost(children, parent, depth, spreads)?
+                }
+            };
+
+            total = total.saturating_add(cost);
+        }
+
+        Ok(total)
+    }
+
+    /// How many times a field's selections could be resolved. Paged fields
+    /// count as many as they ask for (or the most they could get), and
+    /// other lists as the most anything can return, except for the edges of
+    /// a connection, which were already counted by the connection.
+    fn multiplier(
+        &self,
+        parent: Option<&MetaType>,
+        field: Option<&Field>,
+        arguments: &[(&str, Argument)]
+    ) -> Result<u64, Error> {
+        let max = self.config.max_list_size;
+
+        let mut size = None;
+        for &(name, ref value) in arguments {
+            if name != "first" && name != "last" && name != "limit" {
+                continue;
+            }
+
+            let field = match name {
+                "first" => "first",
+                "last" => "last",
+                _ => "limit"
+            };
+
+            let value = match *value {
+                Argument::Int(n) => Some(n),
+                Argument::Variable(v) => self.variable(field, v)?,
+                Argument::Null | Argument::Other => None
+            };
+
+            if let Some(n) = value {
+                if n > max {
+                    return Err(Error::new(Code::ListTooLong, format!(
+                        "{} is over the limit of {}", name, max
+                    )).on(field));
+                }
+                size = Some(n);
+            }
+        }
+
+        let field = match field {
+            Some(field) => field,
+            None => return Ok(1)
+        };
+
+        let paged = field.arguments.as_ref().map_or(false, |args| {
+            args.iter().any(|arg| {
+                arg.name == "first" || arg.name == "last" || arg.name == "limit"
+            })
+        });
+
+        let list = match field.field_type {
+            Type::List(_) | Type::NonNullList(_) => true,
+            _ => false
+        };
+
+        let edges = parent.and_then(|p| p.name())
+            .map_or(false, |name| name.ends_with("Connection"));
+
+        Ok(if paged {
+            size.unwrap_or(max).max(0) as u64
+        } else if list && !edges {
+            max as u64
+        } else {
+            1
+        })
+    }
+
+    /// The number a variable passed as a page size stands for, going by
+    /// its default if it wasn't given. Anything that isn't a number or null
+    /// is refused, since the size it'd run with isn't known.
+    fn variable(&self, field: &'static str, name: &str) -> Result<Option<i64>, Error> {
+        let unknown = || Error::invalid(field, "page size must be a number");
+
+        match self.variables.get(name) {
+            Some(value) if value.is_null() => Ok(None),
+            Some(value) => value.as_int_value().map(Some).ok_or_else(unknown),
+            None => match self.defaults.get(name) {
+                Some(&Argument::Int(n)) => Ok(Some(n)),
+                Some(&Argument::Null) | None => Ok(None),
+                Some(_) => Err(unknown())
+            }
+        }
+    }
+}
+
+/// Pull the operations and fragments out of a query, or None if it's not
+/// well-formed enough.
+fn parse(query: &str) -> Option<Document> {
+    let mut tokens = Vec::new();
+    for token in Lexer::new(query) {
+        match token {
+            Ok(token) => if token.item == Token::EndOfFile {
+                break;
+            } else {
+                tokens.push(token.item);
+            },
+            Err(_) => return None
+        }
+    }
+
+    let mut parser = Parser { tokens: tokens, position: 0 };
+    let mut document = Document {
+        operations: Vec::new(),
+        fragments: HashMap::new()
+    };
+
+    while parser.peek().is_some() {
+        let keyword = match *parser.peek()? {
+            Token::CurlyOpen => None,
+            Token::Name(keyword) => Some(keyword),
+            _ => return None
+        };
+
+        match keyword {
+            None => {
+                document.operations.push(Operation {
+                    name: None,
+                    mutation: false,
+                    defaults: HashMap::new(),
+                    selections: parser.selection_set()?
+                });
+            },
+            Some("fragment") => {
+                parser.position += 1;
+                let name = parser.name()?;
+                if parser.name()? != "on" {
+                    return None;
+                }
+                let condition = parser.name()?;
+                parser.directives()?;
+                let selections = parser.selection_set()?;
+                document.fragments.insert(name, (condition, selections));
+            },
+            Some(kind) => {
+                parser.position += 1;
+                let name = match parser.peek() {
+                    Some(&Token::Name(name)) => Some(name),
+                    _ => None
+                };
+                if name.is_some() {
+                    parser.position += 1;
+                }
+                let defaults = parser.variable_definitions()?;
+                parser.directives()?;
+                document.operations.push(Operation {
+                    name: name,
+                    mutation: kind == "mutation",
+                    defaults: defaults,
+                    selections: parser.selection_set()?
+                });
+            }
+        }
+    }
+
+    Some(document)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    position: usize
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&Token<'a>> {
+        self.position += 1;
+        self.tokens.get(self.position - 1)
+    }
+
+    fn name(&mut self) -> Option<&'a str> {
+        match self.next() {
+            Some(&Token::Name(name)) => Some(name),
+            _ => None
+        }
+    }
+
+    fn expect(&mut self, token: &Token) -> Option<()> {
+        if self.next() == Some(token) { Some(()) } else { None }
+    }
+
+    /// Skip past a bracketed group, including anything nested in it.
+    fn skip(&mut self, open: &Token, close: &Token) -> Option<()> {
+        self.expect(open)?;
+        let mut level = 1;
+        while level > 0 {
+            let token = self.next()?;
+            if token == open {
+                level += 1;
+            } else if token == close {
+                level -= 1;
+            }
+        }
+        Some(())
+    }
+
+    fn directives(&mut self) -> Option<()> {
+        while self.peek() == Some(&Token::At) {
+            self.position += 1;
+            self.name()?;
+            if self.peek() == Some(&Token::ParenOpen) {
+                self.skip(&Token::ParenOpen, &Token::ParenClose)?;
+            }
+        }
+        Some(())
+    }
+
+    fn value(&mut self) -> Option<Argument<'a>> {
+        match self.peek()? {
+            &Token::BracketOpen => {
+                self.skip(&Token::BracketOpen, &Token::BracketClose)?;
+                return Some(Argument::Other);
+            },
+            &Token::CurlyOpen => {
+                self.skip(&Token::CurlyOpen, &Token::CurlyClose)?;
+                return Some(Argument::Other);
+            },
+            _ => {}
+        }
+
+        let variable = match *self.next()? {
+            Token::Int(n) => return Some(Argument::Int(n)),
+            Token::Name("null") => return Some(Argument::Null),
+            Token::Dollar => true,
+            _ => false
+        };
+
+        Some(if variable {
+            Argument::Variable(self.name()?)
+        } else {
+            Argument::Other
+        })
+    }
+
+    /// The default values of an operation's variables, skipping their types.
+    fn variable_definitions(&mut self) -> Option<HashMap<&'a str, Argument<'a>>> {
+        let mut defaults = HashMap::new();
+        if self.peek() != Some(&Token::ParenOpen) {
+            return Some(defaults);
+        }
+
+        self.position += 1;
+        while self.peek()? != &Token::ParenClose {
+            self.expect(&Token::Dollar)?;
+            let name = self.name()?;
+            self.expect(&Token::Colon)?;
+
+            if self.peek()? == &Token::BracketOpen {
+                self.skip(&Token::BracketOpen, &Token::BracketClose)?;
+            } else {
+                self.name()?;
+            }
+            if self.peek()? == &Token::ExclamationMark {
+                self.position += 1;
+            }
+
+            if self.peek()? == &Token::Equals {
+                self.position += 1;
+                defaults.insert(name, self.value()?);
+            }
+        }
+        self.position += 1;
+
+        Some(defaults)
+    }
+
+    fn arguments(&mut self) -> Option<Vec<(&'a str, Argument<'a>)>> {
+        let mut arguments = Vec::new();
+        if self.peek() != Some(&Token::ParenOpen) {
+            return Some(arguments);
+        }
+
+        self.position += 1;
+        while self.peek()? != &Token::ParenClose {
+            let name = self.name()?;
+            self.expect(&Token::Colon)?;
+            arguments.push((name, self.value()?));
+        }
+        self.position += 1;
+
+        Some(arguments)
+    }
+
+    fn selection_set(&mut self) -> Option<Vec<Selection<'a>>> {
+        self.expect(&Token::CurlyOpen)?;
+        let mut selections = Vec::new();
+        while self.peek()? != &Token::CurlyClose {
+            selections.push(self.selection()?);
+        }
+        self.position += 1;
+        Some(selections)
+    }
+
+    fn selection(&mut self) -> Option<Selection<'a>> {
+        if self.peek()? == &Token::Ellipsis {
+            self.position += 1;
+            let name = match *self.peek()? {
+                Token::Name(name) => Some(name),
+                _ => None
+            };
+
+            return match name {
+                Some("on") => {
+                    self.position += 1;
+                    let condition = self.name()?;
+                    self.directives()?;
+                    Some(Selection::Inline(Some(condition), self.selection_set()?))
+                },
+                Some(name) => {
+                    self.position += 1;
+                    self.directives()?;
+                    Some(Selection::Spread(name))
+                },
+                None => {
+                    self.directives()?;
+                    Some(Selection::Inline(None, self.selection_set()?))
+                }
+            };
+        }
+
+        let mut name = self.name()?;
+        if self.peek() == Some(&Token::Colon) {
+            self.position += 1;
+            name = self.name()?;
+        }
+
+        let arguments = self.arguments()?;
+        self.directives()?;
+
+        let children = if self.peek() == Some(&Token::CurlyOpen) {
+            self.selection_set()?
+        } else {
+            Vec::new()
+        };
+
+        Some(Selection::Field {
+            name: name,
+            arguments: arguments,
+            children: children
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find, parse, Argument};
+
+    #[test]
+    fn malformed_queries_are_refused() {
+        assert!(parse("{ roles { id }").is_none());
+        assert!(parse("query ($id: ID!) { user(id: $id) { id } }").is_some());
+    }
+
+    #[test]
+    fn unknown_operations_are_refused() {
+        let document = parse("query A { roles { id } } query B { roles { name } }").unwrap();
+        assert!(find(&document, Some("B")).is_ok());
+        assert!(find(&document, Some("C")).is_err());
+    }
+
+    #[test]
+    fn variable_defaults_are_found() {
+        let query = "query ($n: Int = 1000000000, $ids: [ID!]!, $m: Int = null) {
+            announcements(first: $n) { id }
+        }";
+        let document = parse(query).unwrap();
+        let defaults = &document.operations[0].defaults;
+        match defaults.get("n") {
+            Some(&Argument::Int(1000000000)) => (),
+            _ => panic!("the default for $n wasn't found")
+        }
+        match defaults.get("m") {
+            Some(&Argument::Null) => (),
+            _ => panic!("the default for $m wasn't found")
+        }
+        assert!(defaults.get("ids").is_none());
+    }
+}
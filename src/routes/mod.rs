@@ -1,26 +1,49 @@
-use iron::prelude::*;
+use config::Config;
+use listener::Listener;
 use router::Router;
-use juniper::iron_handlers::{GraphQLHandler, GraphiQLHandler};
-use self::query::{Mutate, Query, Context};
+use juniper::iron_handlers::GraphiQLHandler;
+use self::graphql::GraphQLHandler;
+use self::persisted::PersistedQueries;
+use self::subscriptions::SubscriptionHandler;
+use std::process;
+use std::sync::Arc;
 
 mod auth;
+mod calendar;
+mod graphql;
+mod limits;
+mod persisted;
 mod query;
+mod subscriptions;
+mod unsubscribe;
 
-pub fn build() -> Router {
+pub fn build(config: &Config, listener: Listener) -> Router {
     let mut router = Router::new();
 
     router.post("/auth/login", auth::login, "auth/login");
     router.post("/auth/forgot", auth::forgot, "auth/forgot");
     router.post("/auth/reset", auth::reset, "auth/reset");
+    router.get("/unsubscribe", unsubscribe::confirm, "unsubscribe");
+    router.post("/unsubscribe", unsubscribe::unsubscribe, "unsubscribe/post");
+    router.get("/calendar/locations/:file", calendar::location, "calendar/location");
+    router.get("/calendar/users/:file", calendar::user, "calendar/user");
 
-    let graphql = GraphQLHandler::new(ctxfactory, Query, Mutate);
-    let graphiql = GraphiQLHandler::new("/");
+    let dir = config.persisted_queries.as_ref().map(|x| x.as_str());
+    let persisted = PersistedQueries::load(dir).unwrap_or_else(|e| {
+        error!("Could not load the persisted queries: {}.", e);
+        process::exit(1);
+    });
+    let persisted = Arc::new(persisted);
+
+    let graphql = GraphQLHandler::new(persisted.clone());
+    let subscriptions = SubscriptionHandler::new(persisted, listener);
     router.post("/", graphql, "graphql");
-    router.get("/graphiql", graphiql, "graphiql");
+    router.post("/subscriptions", subscriptions, "subscriptions");
 
-    router
-}
+    if config.graphiql {
+        let graphiql = GraphiQLHandler::new("/");
+        router.get("/graphiql", graphiql, "graphiql");
+    }
 
-fn ctxfactory(req: &mut Request) -> Context {
-    Context::from(req)
+    router
 }
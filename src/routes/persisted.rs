@@ -0,0 +1,57 @@
+//! Queries stored ahead of time, so that clients can send the hash of one
+//! instead of the whole thing.
+
+use database::DbPointer;
+use diesel::prelude::*;
+use schema::persisted_queries;
+use sodiumoxide::crypto::hash::sha256;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+
+pub struct PersistedQueries {
+    documents: HashMap<String, String> // By hash.
+}
+
+impl PersistedQueries {
+    /// Load every `.graphql` file in the directory, if there is one.
+    pub fn load(dir: Option<&str>) -> io::Result<PersistedQueries> {
+        let mut documents = HashMap::new();
+
+        if let Some(dir) = dir {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().map_or(true, |ext| ext != "graphql") {
+                    continue;
+                }
+
+                let mut query = String::new();
+                File::open(&path)?.read_to_string(&mut query)?;
+                documents.insert(hash(&query), query);
+            }
+
+            info!("Loaded {} persisted queries from {}.", documents.len(), dir);
+        }
+
+        Ok(PersistedQueries { documents: documents })
+    }
+
+    /// The query with the given hash, from the directory or else the table.
+    pub fn get(&self, hash: &str, db: &DbPointer) -> QueryResult<Option<String>> {
+        if let Some(query) = self.documents.get(hash) {
+            return Ok(Some(query.clone()));
+        }
+
+        persisted_queries::table
+            .find(hash)
+            .select(persisted_queries::query)
+            .get_result(&**db)
+            .optional()
+    }
+}
+
+/// The lowercase hexadecimal SHA-256 hash of a query.
+pub fn hash(query: &str) -> String {
+    let sha256::Digest(digest) = sha256::hash(query.as_bytes());
+    digest.iter().map(|n| format!("{:02x}", n)).collect()
+}
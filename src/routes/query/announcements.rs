@@ -0,0 +1,231 @@
+use chrono::{DateTime, UTC};
+use conditions::{self, Cache, Facts};
+use config::Config;
+use diesel;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use email::{self, Category, Templates, Vars};
+use juniper::ID;
+use schema::{users, locations, role_grants, announcements};
+use std::rc::Rc;
+use super::{
+    Context,
+    Location,
+    LocationWrapper,
+    Role,
+    Timestamp,
+    User,
+    UserWrapper,
+    stringify_error,
+    wrap_location
+};
+use super::error::Error;
+use super::node;
+use uuid::Uuid;
+
+#[derive(Queryable)]
+pub struct Announcement {
+    id: i64,
+    author_id: Option<i64>,
+    title: String,
+    body: String,
+    location_id: Option<i64>,
+    role: Option<i64>,
+    created_at: DateTime<UTC>,
+    uuid: Uuid
+}
+
+pub struct AnnouncementWrapper<'a> {
+    announcement: Announcement,
+    cache: Cache<'a>
+}
+
+#[derive(Insertable)]
+#[table_name="announcements"]
+struct NewAnnouncement<'a> {
+    author_id: Option<i64>,
+    title: &'a str,
+    body: &'a str,
+    location_id: Option<i64>,
+    role: Option<i64>
+}
+
+graphql_object!(<'a> AnnouncementWrapper<'a>: Context as "Announcement" |&self| {
+    description: "A message for a location's students, everyone with at \
+                  least some role, or everybody."
+
+    field id() -> ID
+    as "A unique ID for the announcement." {
+        node::encode(node::ANNOUNCEMENT, self.announcement.uuid)
+    }
+
+    field title() -> &str
+    as "What it's about." {
+        &self.announcement.title
+    }
+
+    field body() -> &str
+    as "What it says." {
+        &self.announcement.body
+    }
+
+    field author() -> Result<Option<UserWrapper>, String>
+    as "Who sent it, unless they've been removed." {
+        let author = match self.announcement.author_id {
+            Some(author) => author,
+            None => return Ok(None)
+        };
+
+        users::table.find(author)
+            .first::<User>(&**self.cache.database())
+            .optional()
+            .map(|user| user.map(|user| UserWrapper {
+                batch: Rc::new(vec![user.id]),
+                user: user,
+                cache: self.cache.retarget(Some(author))
+            }))
+            .map_err(stringify_error)
+    }
+
+    field location() -> Result<Option<LocationWrapper>, String>
+    as "The location whose students it was sent to, if it was." {
+        let location = match self.announcement.location_id {
+            Some(location) => location,
+            None => return Ok(None)
+        };
+
+        locations::table.find(location)
+            .first::<Location>(&**self.cache.database())
+            .optional()
+            .map(|found| found.map(|found| wrap_location(self.cache, found)))
+            .map_err(stringify_error)
+    }
+
+    field role() -> Option<Role>
+    as "The least role it was sent to, if it was." {
+        self.announcement.role
+            .and_then(conditions::Role::from_int)
+            .map(Role)
+    }
+
+    field createdAt() -> Timestamp
+    as "When it was sent." {
+        Timestamp(self.announcement.created_at)
+    }
+});
+
+/// Save an announcement and queue an email to everyone it's for, unless
+/// they'd rather not get them.
+pub fn send<'a>(
+    cache: Cache<'a>,
+    config: &Config,
+    templates: &Templates,
+    title: &str,
+    body: &str,
+    location: Option<i64>,
+    role: Option<i64>
+) -> Result<AnnouncementWrapper<'a>, Error> {
+    let db = &**cache.database();
+    let new_announcement = NewAnnouncement {
+        author_id: cache.user,
+        title: title,
+        body: body,
+        location_id: location,
+        role: role
+    };
+
+    let author = match cache.user {
+        Some(user) => users::table.find(user)
+            .select((users::first_name, users::last_name))
+            .first::<(String, String)>(db)
+            .map(|(first, last)| format!("{} {}", first, last))?,
+        None => return Err(Error::unauthorized())
+    };
+
+    let announcement = db.transaction::<_, Error, _>(|| {
+        let announcement: Announcement = diesel::insert(&new_announcement)
+            .into(announcements::table)
+            .get_result(db)?;
+
+        for (user, name) in recipients(db, location, role)? {
+            let mut vars = Vars::new();
+            vars.insert("name", name);
+            vars.insert("author", author.clone());
+            vars.insert("title", title.to_owned());
+            vars.insert("body", body.to_owned());
+            email::notify(
+                config,
+                templates,
+                db,
+                user,
+                Category::Announcements,
+                "announcement",
+                &vars
+            )?;
+        }
+
+        Ok(announcement)
+    })?;
+
+    Ok(AnnouncementWrapper { announcement: announcement, cache: cache })
+}
+
+/// The newest announcements for the user.
+pub fn feed<'a>(cache: Cache<'a>, first: i64) -> Result<Vec<AnnouncementWrapper<'a>>, Error> {
+    let user = match cache.user {
+        Some(user) => user,
+        None => return Err(Error::unauthorized())
+    };
+
+    let db = &**cache.database();
+    let role = cache.role().map_err(|_| Error::unauthorized())?;
+    let training = users::table.find(user)
+        .select(users::training_location)
+        .first::<Option<i64>>(db)?;
+
+    let everybody = announcements::location_id.is_null()
+        .and(announcements::role.is_null());
+
+    let found = announcements::table
+        .filter(everybody
+            .or(announcements::location_id.eq(training))
+            .or(announcements::role.le(role)))
+        .order(announcements::created_at.desc())
+        .limit(first)
+        .get_results::<Announcement>(db)?;
+
+    Ok(found.into_iter()
+        .map(|announcement| AnnouncementWrapper { announcement: announcement, cache: cache })
+        .collect())
+}
+
+/// The ID and first name of everyone an announcement is for.
+fn recipients(
+    db: &PgConnection,
+    location: Option<i64>,
+    role: Option<i64>
+) -> QueryResult<Vec<(i64, String)>> {
+    let columns = (users::id, users::first_name);
+
+    if let Some(location) = location {
+        users::table
+            .filter(users::training_location.eq(location))
+            .select(columns)
+            .get_results(db)
+    } else if let Some(role) = role {
+        // Temporary grants count as well.
+        let granted = role_grants::table
+            .filter(role_grants::role.ge(role))
+            .filter(conditions::active_grant())
+            .select(role_grants::user_id);
+
+        users::table
+            .filter(users::role.ge(role).or(users::id.eq_any(granted)))
+            .select(columns)
+            .get_results(db)
+    } else {
+        users::table
+            .select(columns)
+            .get_results(db)
+    }
+}
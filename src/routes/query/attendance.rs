@@ -0,0 +1,244 @@
+use auth;
+use base64;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, UTC};
+use conditions::Cache;
+use config::Config;
+use diesel;
+use diesel::pg::PgConnection;
+use diesel::pg::upsert::*;
+use diesel::prelude::*;
+use schema::{users, attendance};
+use super::{Context, Day, User};
+use super::error::{Code, Error};
+use super::timetable::{self, Class, Session};
+
+/// How long before a class starts members can check in to it.
+const EARLY_CHECK_IN: i64 = 30; // Minutes.
+
+/// How long each check-in code lasts, so a photo of one soon stops working.
+const CHECK_IN_WINDOW: i64 = 5; // Minutes.
+
+/// How many windows either side of the current one a code is still taken
+/// from, for clocks that are a little off and codes scanned as they change.
+const CHECK_IN_SKEW: i64 = 1;
+
+/// How much someone's been training, for seeing whether they're ready to
+/// grade.
+pub struct AttendanceStats {
+    total: i64,
+    this_month: i64,
+    current_streak: i64,
+    longest_streak: i64,
+    last_attended: Option<NaiveDate>
+}
+
+#[derive(Insertable)]
+#[table_name="attendance"]
+struct Mark {
+    class_id: i64,
+    date: NaiveDate,
+    user_id: i64,
+    marked_by: Option<i64>
+}
+
+graphql_object!(AttendanceStats: Context as "AttendanceStats" |&self| {
+    description: "How often a user comes to class. Streaks are counted in \
+                  weeks with at least one class in them."
+
+    field total() -> i64
+    as "How many classes they've been to." {
+        self.total
+    }
+
+    field thisMonth() -> i64
+    as "How many classes they've been to this calendar month." {
+        self.this_month
+    }
+
+    field currentStreak() -> i64
+    as "How many weeks in a row they've trained, up to this week or last." {
+        self.current_streak
+    }
+
+    field longestStreak() -> i64
+    as "The most weeks in a row they've ever trained." {
+        self.longest_streak
+    }
+
+    field lastAttended() -> Option<Day>
+    as "The last day they came, if they ever have." {
+        self.last_attended.map(Day)
+    }
+});
+
+pub fn stats(db: &PgConnection, user: i64) -> QueryResult<AttendanceStats> {
+    let dates = attendance::table
+        .filter(attendance::user_id.eq(user))
+        .select(attendance::date)
+        .order(attendance::date)
+        .get_results::<NaiveDate>(db)?;
+
+    let today = UTC::today().naive_utc();
+    let this_month = dates.iter()
+        .filter(|x| x.year() == today.year() && x.month() == today.month())
+        .count();
+
+    // The Monday of every week with a class in it, in order.
+    let mut weeks = dates.iter()
+        .map(|x| *x - Duration::days(x.weekday().num_days_from_monday() as i64))
+        .collect::<Vec<_>>();
+    weeks.dedup();
+
+    let mut streak = 0;
+    let mut longest = 0;
+    for (i, week) in weeks.iter().enumerate() {
+        let continues = i > 0 && *week - weeks[i - 1] == Duration::weeks(1);
+        streak = if continues { streak + 1 } else { 1 };
+        longest = longest.max(streak);
+    }
+
+    // This week isn't over, so a streak up to last week is still going.
+    let this_week = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+    let current = match weeks.last() {
+        Some(&week) if this_week - week <= Duration::weeks(1) => streak,
+        _ => 0
+    };
+
+    Ok(AttendanceStats {
+        total: dates.len() as i64,
+        this_month: this_month as i64,
+        current_streak: current,
+        longest_streak: longest,
+        last_attended: dates.last().cloned()
+    })
+}
+
+/// Whether the user came to the session.
+pub fn attended(session: &Session, cache: Cache, user: i64) -> QueryResult<bool> {
+    attendance::table
+        .find((session.class().id(), session.date(), user))
+        .count()
+        .get_result::<i64>(&**cache.database())
+        .map(|count| count > 0)
+}
+
+/// Everyone who came to the session.
+pub fn attendees(session: &Session, cache: Cache) -> QueryResult<Vec<User>> {
+    let came = attendance::table
+        .filter(attendance::class_id.eq(session.class().id()))
+        .filter(attendance::date.eq(session.date()))
+        .select(attendance::user_id);
+
+    users::table
+        .filter(users::id.eq_any(came))
+        .order((users::first_name, users::last_name))
+        .get_results(&**cache.database())
+}
+
+/// Record whether each of the students came to a session. Their
+/// permissions should already have been checked.
+pub fn mark<'a>(
+    cache: Cache<'a>,
+    class: Class,
+    date: NaiveDate,
+    students: &[i64],
+    attended: bool
+) -> Result<Session<'a>, Error> {
+    let session = timetable::session(cache, class, date)?;
+    if session.cancelled() {
+        return Err(Error::invalid("date", "the session was cancelled"));
+    }
+
+    // Otherwise people could be credited for training they haven't done.
+    if UTC::now() < session.start() {
+        return Err(Error::invalid("date", "the session hasn't started yet"));
+    }
+
+    let db = &**cache.database();
+    if attended {
+        let marks = students.iter()
+            .map(|&student| Mark {
+                class_id: session.class().id(),
+                date: date,
+                user_id: student,
+                marked_by: cache.user
+            })
+            .collect::<Vec<_>>();
+
+        diesel::insert(&marks.on_conflict_do_nothing())
+            .into(attendance::table)
+            .execute(db)?;
+    } else {
+        diesel::delete(attendance::table
+            .filter(attendance::class_id.eq(session.class().id()))
+            .filter(attendance::date.eq(date))
+            .filter(attendance::user_id.eq_any(students)))
+            .execute(db)?;
+    }
+
+    Ok(session)
+}
+
+/// Check the user in to a session, using the code from its QR code.
+pub fn check_in<'a>(
+    cache: Cache<'a>,
+    config: &Config,
+    class: Class,
+    date: NaiveDate,
+    code: &str
+) -> Result<Session<'a>, Error> {
+    let user = match cache.user {
+        Some(user) => user,
+        None => return Err(Error::unauthorized())
+    };
+
+    let now = UTC::now();
+    let current = window(now);
+    let valid = match base64::decode_config(code, base64::URL_SAFE) {
+        Ok(ref vec) if vec.len() == 32 => {
+            let mut code = [0; 32];
+            code.clone_from_slice(vec);
+            (current - CHECK_IN_SKEW..current + CHECK_IN_SKEW + 1).any(|window| {
+                auth::verify_check_in(config.secret, class.id(), date, window, code)
+            })
+        },
+        _ => false
+    };
+
+    if !valid {
+        return Err(Error::invalid("code", "invalid check-in code"));
+    }
+
+    let session = timetable::session(cache, class, date)?;
+    if session.cancelled() {
+        return Err(Error::invalid("date", "the session was cancelled"));
+    }
+
+    let opens = session.start() - Duration::minutes(EARLY_CHECK_IN);
+    if now < opens || session.end() < now {
+        let e = Error::new(Code::NotFound, "no class to check in to right now");
+        return Err(e.on("date"));
+    }
+
+    diesel::insert(&Mark {
+        class_id: session.class().id(),
+        date: date,
+        user_id: user,
+        marked_by: None
+    }.on_conflict_do_nothing())
+    .into(attendance::table)
+    .execute(&**cache.database())?;
+
+    Ok(session)
+}
+
+/// The session's check-in code right now, for putting in a QR code.
+pub fn check_in_code(config: &Config, session: &Session) -> String {
+    let class = session.class().id();
+    let code = auth::check_in_code(config.secret, class, session.date(), window(UTC::now()));
+    base64::encode_config(&code, base64::URL_SAFE)
+}
+
+fn window(time: DateTime<UTC>) -> i64 {
+    time.timestamp() / (CHECK_IN_WINDOW * 60)
+}
@@ -0,0 +1,493 @@
+use auth;
+use base64;
+use bincode;
+use conditions::Cache;
+use config::Config;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use schema::{users, locations, instructor_locations, lower};
+use super::{Context, User, UserWrapper, Location, LocationWrapper};
+use super::{conditions, stringify_error};
+use super::error::Error;
+use super::directory::{self, UserFilter, UserSort, UserSortField};
+use super::{wrap_users, wrap_locations};
+
+/// The most edges handed out in one go, unless lists are limited further.
+pub const MAX_PAGE: i64 = 100;
+
+/// The most edges handed out in one go with this configuration.
+pub fn max_page(config: &Config) -> i64 {
+    MAX_PAGE.min(config.max_list_size)
+}
+
+/// A position in a list, handed to clients sealed so that it's opaque.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Cursor {
+    Id(i64),
+    Name(String, i64),
+    Keys(Vec<Key>, i64)
+}
+
+/// The value of a sorted column at some position.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Key {
+    Text(String),
+    Int(i64),
+    Time(i64, u32),
+    Score(f32)
+}
+
+impl Cursor {
+    pub fn encode(&self, key: [u8; 32]) -> String {
+        // Serializing something this simple can't fail.
+        let bytes = bincode::serialize(self, bincode::Infinite).unwrap();
+        base64::encode_config(&auth::seal_cursor(key, &bytes), base64::URL_SAFE)
+    }
+
+    pub fn decode(code: &str, key: [u8; 32]) -> Option<Cursor> {
+        base64::decode_config(code, base64::URL_SAFE)
+            .ok()
+            .and_then(|sealed| auth::open_cursor(key, &sealed))
+            .and_then(|bytes| bincode::deserialize(&bytes).ok())
+    }
+}
+
+/// Which slice of a list the client asked for.
+pub struct Window {
+    pub limit: i64,
+    pub cursor: Option<Cursor>,
+    pub backward: bool,
+    key: [u8; 32] // For sealing cursors.
+}
+
+impl Window {
+    pub fn new(
+        config: &Config,
+        first: Option<i64>,
+        after: Option<String>,
+        last: Option<i64>,
+        before: Option<String>
+    ) -> Result<Window, String> {
+        // Pages are counted from one end, so a bound at the other end
+        // would be ignored.
+        let (limit, cursor, backward) = match (first, last) {
+            (Some(_), Some(_)) =>
+                return Err(Error::invalid("last", "can't use first and last together").into()),
+            (_, Some(_)) if after.is_some() =>
+                return Err(Error::invalid("after", "can't use after with last").into()),
+            (_, Some(last)) => (last, before, true),
+            (_, None) if before.is_some() =>
+                return Err(Error::invalid("before", "can't use before without last").into()),
+            (first, None) => (first.unwrap_or(max_page(config)), after, false)
+        };
+
+        let max = max_page(config);
+        if limit < 0 || limit > max {
+            let field = if backward { "last" } else { "first" };
+            return Err(Error::invalid(field, format!(
+                "page size must be between 0 and {}", max)).into());
+        }
+
+        let mut window = Window {
+            limit: limit,
+            cursor: None,
+            backward: backward,
+            key: config.secret
+        };
+
+        if let Some(ref code) = cursor {
+            window.cursor = Some(Cursor::decode(code, config.secret)
+                .ok_or_else(|| window.invalid_cursor())?);
+        }
+
+        Ok(window)
+    }
+
+    /// The error for a cursor that doesn't belong to the list.
+    pub fn invalid_cursor(&self) -> String {
+        let field = if self.backward { "before" } else { "after" };
+        Error::invalid(field, "invalid cursor").into()
+    }
+
+    /// Trim the extra row that was fetched to see if there's another page,
+    /// and put everything back into ascending order.
+    fn finish<T, F>(&self, mut rows: Vec<T>, cursor: F) -> (Vec<(String, T)>, PageInfo)
+    where F: Fn(&T) -> Cursor {
+        let more = rows.len() as i64 > self.limit;
+        rows.truncate(self.limit as usize);
+
+        if self.backward {
+            rows.reverse();
+        }
+
+        let edges = rows.into_iter()
+            .map(|row| (cursor(&row).encode(self.key), row))
+            .collect::<Vec<_>>();
+
+        let info = PageInfo {
+            has_next_page: more && !self.backward,
+            has_previous_page: more && self.backward,
+            start_cursor: edges.first().map(|x| x.0.clone()),
+            end_cursor: edges.last().map(|x| x.0.clone())
+        };
+
+        (edges, info)
+    }
+}
+
+pub struct PageInfo {
+    has_next_page: bool,
+    has_previous_page: bool,
+    start_cursor: Option<String>,
+    end_cursor: Option<String>
+}
+
+pub struct UserConnection<'a> {
+    edges: Vec<UserEdge<'a>>,
+    page_info: PageInfo,
+    total_count: i64
+}
+
+pub struct LocationConnection<'a> {
+    edges: Vec<LocationEdge<'a>>,
+    page_info: PageInfo,
+    total_count: i64
+}
+
+pub struct UserEdge<'a> {
+    cursor: String,
+    node: UserWrapper<'a>
+}
+
+pub struct LocationEdge<'a> {
+    cursor: String,
+    node: LocationWrapper<'a>
+}
+
+graphql_object!(PageInfo: Context as "PageInfo" |&self| {
+    description: "Where a page sits in a longer list."
+
+    field hasNextPage() -> bool
+    as "Whether there's more after this page (only known when paging forward)." {
+        self.has_next_page
+    }
+
+    field hasPreviousPage() -> bool
+    as "Whether there's more before this page (only known when paging backward)." {
+        self.has_previous_page
+    }
+
+    field startCursor() -> Option<&str>
+    as "The cursor of the first edge." {
+        self.start_cursor.as_ref().map(|x| x.as_str())
+    }
+
+    field endCursor() -> Option<&str>
+    as "The cursor of the last edge." {
+        self.end_cursor.as_ref().map(|x| x.as_str())
+    }
+});
+
+graphql_object!(<'a> UserConnection<'a>: Context as "UserConnection" |&self| {
+    description: "A page of users."
+
+    field edges() -> &[UserEdge]
+    as "The users on this page." {
+        &self.edges
+    }
+
+    field pageInfo() -> &PageInfo
+    as "Where this page sits in the whole list." {
+        &self.page_info
+    }
+
+    field totalCount() -> i64
+    as "The number of users in the whole list." {
+        self.total_count
+    }
+});
+
+graphql_object!(<'a> UserEdge<'a>: Context as "UserEdge" |&self| {
+    description: "A user and their place in a list."
+
+    field cursor() -> &str
+    as "Pass this as `after` or `before` to continue from here." {
+        &self.cursor
+    }
+
+    field node() -> &UserWrapper
+    as "The user." {
+        &self.node
+    }
+});
+
+graphql_object!(<'a> LocationConnection<'a>: Context as "LocationConnection" |&self| {
+    description: "A page of locations."
+
+    field edges() -> &[LocationEdge]
+    as "The locations on this page." {
+        &self.edges
+    }
+
+    field pageInfo() -> &PageInfo
+    as "Where this page sits in the whole list." {
+        &self.page_info
+    }
+
+    field totalCount() -> i64
+    as "The number of locations in the whole list." {
+        self.total_count
+    }
+});
+
+graphql_object!(<'a> LocationEdge<'a>: Context as "LocationEdge" |&self| {
+    description: "A location and its place in a list."
+
+    field cursor() -> &str
+    as "Pass this as `after` or `before` to continue from here." {
+        &self.cursor
+    }
+
+    field node() -> &LocationWrapper
+    as "The location." {
+        &self.node
+    }
+});
+
+/// Everyone matching the filter, sorted by the given columns and then by ID.
+pub fn users<'a>(
+    cache: Cache<'a>,
+    window: Window,
+    query: Option<String>,
+    filter: Option<UserFilter>,
+    sort: Vec<UserSort>
+) -> Result<UserConnection<'a>, String> {
+    let db: &PgConnection = &**cache.database();
+    let score = || directory::score(&query);
+    let predicate = || filter.as_ref().and_then(UserFilter::predicate);
+
+    // Best matches first when searching, unless asked otherwise.
+    let mut terms = sort.iter().map(UserSort::term).collect::<Vec<_>>();
+    if terms.is_empty() && query.is_some() {
+        terms.push((UserSortField::Relevance, true));
+    }
+
+    let mut count = users::table.into_boxed();
+    if let Some(predicate) = predicate() {
+        count = count.filter(predicate);
+    }
+    let total_count = count.count()
+        .get_result(db)
+        .map_err(stringify_error)?;
+
+    let mut req = users::table
+        .select((users::all_columns, score()))
+        .into_boxed();
+
+    if let Some(predicate) = predicate() {
+        req = req.filter(predicate);
+    }
+
+    req = match window.cursor.clone() {
+        None => req,
+        Some(Cursor::Keys(keys, id)) =>
+            req.filter(directory::after(&terms, &keys, id, window.backward, &score)
+                .ok_or_else(|| window.invalid_cursor())?),
+        Some(_) => return Err(window.invalid_cursor())
+    };
+
+    let rows = req.order(directory::ordering(&terms, window.backward, &score))
+        .limit(window.limit + 1)
+        .get_results::<(User, f32)>(db)
+        .map_err(stringify_error)?;
+
+    let (edges, page_info) = window.finish(rows, |row| Cursor::Keys(
+        terms.iter().map(|&(field, _)| field.key(&row.0, row.1)).collect(),
+        row.0.id
+    ));
+    let users = edges.into_iter().map(|(c, (u, _))| (c, u)).collect();
+    user_connection(cache, users, page_info, total_count)
+}
+
+/// The people training at a location, by ID.
+pub fn students<'a>(
+    cache: Cache<'a>,
+    window: Window,
+    location: i64
+) -> Result<UserConnection<'a>, String> {
+    let db: &PgConnection = &**cache.database();
+
+    let total_count = users::table
+        .filter(users::training_location.eq(location))
+        .count()
+        .get_result(db)
+        .map_err(stringify_error)?;
+
+    let mut req = users::table
+        .filter(users::training_location.eq(location))
+        .into_boxed();
+
+    req = match (window.cursor.clone(), window.backward) {
+        (None, _) => req,
+        (Some(Cursor::Id(id)), false) => req.filter(users::id.gt(id)),
+        (Some(Cursor::Id(id)), true) => req.filter(users::id.lt(id)),
+        (Some(_), _) => return Err(window.invalid_cursor())
+    };
+
+    req = if window.backward {
+        req.order(users::id.desc())
+    } else {
+        req.order(users::id.asc())
+    };
+
+    let rows = req.limit(window.limit + 1)
+        .get_results::<User>(db)
+        .map_err(stringify_error)?;
+
+    let (users, page_info) = window.finish(rows, |user| Cursor::Id(user.id));
+    user_connection(cache, users, page_info, total_count)
+}
+
+/// The people currently instructing at a location, by ID.
+pub fn instructors<'a>(
+    cache: Cache<'a>,
+    window: Window,
+    location: i64
+) -> Result<UserConnection<'a>, String> {
+    let db: &PgConnection = &**cache.database();
+
+    let total_count = instructor_locations::table
+        .filter(instructor_locations::location_id.eq(location))
+        .filter(conditions::active_assignment())
+        .count()
+        .get_result(db)
+        .map_err(stringify_error)?;
+
+    let mut req = instructor_locations::table
+        .inner_join(users::table)
+        .filter(instructor_locations::location_id.eq(location))
+        .filter(conditions::active_assignment())
+        .select(users::all_columns)
+        .into_boxed();
+
+    req = match (window.cursor.clone(), window.backward) {
+        (None, _) => req,
+        (Some(Cursor::Id(id)), false) => req.filter(users::id.gt(id)),
+        (Some(Cursor::Id(id)), true) => req.filter(users::id.lt(id)),
+        (Some(_), _) => return Err(window.invalid_cursor())
+    };
+
+    req = if window.backward {
+        req.order(users::id.desc())
+    } else {
+        req.order(users::id.asc())
+    };
+
+    let rows = req.limit(window.limit + 1)
+        .get_results::<User>(db)
+        .map_err(stringify_error)?;
+
+    let (users, page_info) = window.finish(rows, |user| Cursor::Id(user.id));
+    user_connection(cache, users, page_info, total_count)
+}
+
+/// Every location, alphabetized by name, optionally only those whose name
+/// or address contains the search query. Private addresses aren't searched.
+pub fn locations<'a>(
+    cache: Cache<'a>,
+    window: Window,
+    query: Option<String>
+) -> Result<LocationConnection<'a>, String> {
+    let db: &PgConnection = &**cache.database();
+    let pattern = query.map(|query| format!("%{}%", escape_like(&query.to_lowercase())));
+
+    macro_rules! search {
+        ($req:expr) => {
+            match pattern {
+                Some(ref pattern) => $req.filter(
+                    lower(locations::name).like(pattern.clone())
+                    .or(locations::public
+                        .and(lower(locations::address).like(pattern.clone())))),
+                None => $req
+            }
+        }
+    }
+
+    let total_count = search!(locations::table.into_boxed())
+        .count()
+        .get_result(db)
+        .map_err(stringify_error)?;
+
+    let mut req = search!(locations::table.into_boxed());
+
+    req = match (window.cursor.clone(), window.backward) {
+        (None, _) => req,
+        (Some(Cursor::Name(name, id)), false) => req.filter(
+            locations::name.gt(name.clone())
+            .or(locations::name.eq(name).and(locations::id.gt(id)))),
+        (Some(Cursor::Name(name, id)), true) => req.filter(
+            locations::name.lt(name.clone())
+            .or(locations::name.eq(name).and(locations::id.lt(id)))),
+        (Some(_), _) => return Err(window.invalid_cursor())
+    };
+
+    req = if window.backward {
+        req.order((locations::name.desc(), locations::id.desc()))
+    } else {
+        req.order((locations::name.asc(), locations::id.asc()))
+    };
+
+    let rows = req.limit(window.limit + 1)
+        .get_results::<Location>(db)
+        .map_err(stringify_error)?;
+
+    let (edges, page_info) = window.finish(rows, |location| {
+        Cursor::Name(location.name.clone(), location.id)
+    });
+
+    let (cursors, locations): (Vec<_>, Vec<_>) = edges.into_iter().unzip();
+    let edges = cursors.into_iter()
+        .zip(wrap_locations(cache, locations))
+        .map(|(cursor, node)| LocationEdge { cursor: cursor, node: node })
+        .collect();
+
+    Ok(LocationConnection {
+        edges: edges,
+        page_info: page_info,
+        total_count: total_count
+    })
+}
+
+/// Make the wildcards in a LIKE pattern match themselves.
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '\\' || c == '%' || c == '_' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn user_connection<'a>(
+    cache: Cache<'a>,
+    edges: Vec<(String, User)>,
+    page_info: PageInfo,
+    total_count: i64
+) -> Result<UserConnection<'a>, String> {
+    let ids = edges.iter().map(|x| x.1.id).collect::<Vec<_>>();
+    cache.prefetch_students(&ids)?;
+
+    let (cursors, users): (Vec<_>, Vec<_>) = edges.into_iter().unzip();
+    let edges = cursors.into_iter()
+        .zip(wrap_users(cache, users))
+        .map(|(cursor, node)| UserEdge { cursor: cursor, node: node })
+        .collect();
+
+    Ok(UserConnection {
+        edges: edges,
+        page_info: page_info,
+        total_count: total_count
+    })
+}
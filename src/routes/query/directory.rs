@@ -0,0 +1,321 @@
+use chrono::{TimeZone, UTC};
+use conditions::{self, Cache};
+use diesel::expression::sql;
+use diesel::pg::Pg;
+use diesel::pg::PgQueryBuilder;
+use diesel::prelude::*;
+use diesel::query_builder::{BuildQueryResult, QueryFragment};
+use diesel::types::{Bool, Float};
+use diesel;
+use juniper::ID;
+use schema::{users, locations, role_grants, instructor_locations};
+use super::{Belt, Timestamp, User, check, word_similarity};
+use super::connection::Key;
+use super::error::Error;
+use super::node;
+
+pub type Predicate = Box<BoxableExpression<users::table, Pg, SqlType=Bool>>;
+pub type Score = Box<BoxableExpression<users::table, Pg, SqlType=Float>>;
+type Ordering = Box<BoxableExpression<users::table, Pg, SqlType=()>>;
+
+graphql_input_object!(
+    description: "Narrows down a list of users. Everything given has to match."
+
+    struct UserFilter {
+        roles: Option<Vec<i64>>
+            as "Only people with one of these roles, even temporarily.",
+        training_locations: Option<Vec<ID>>
+            as "Only people training at one of these locations.",
+        instructor: Option<bool>
+            as "Only people who are (or aren't) instructing somewhere.",
+        min_belt: Option<Belt>
+            as "Only people with at least this belt.",
+        max_belt: Option<Belt>
+            as "Only people with at most this belt.",
+        registered_after: Option<Timestamp>
+            as "Only people who signed up at or after this time.",
+        registered_before: Option<Timestamp>
+            as "Only people who signed up before this time."
+    }
+);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum UserSortField {
+    Username,
+    FirstName,
+    LastName,
+    Belt,
+    Registered,
+    Relevance
+}
+
+graphql_enum!(UserSortField {
+    UserSortField::Username => "USERNAME",
+    UserSortField::FirstName => "FIRST_NAME",
+    UserSortField::LastName => "LAST_NAME",
+    UserSortField::Belt => "BELT",
+    UserSortField::Registered => "REGISTERED",
+    UserSortField::Relevance => "RELEVANCE" as "How well the user matches the query."
+});
+
+graphql_input_object!(
+    description: "A column to sort users by."
+
+    struct UserSort {
+        field: UserSortField as "What to sort by.",
+        descending = false: bool as "Whether to put the highest first."
+    }
+);
+
+impl UserFilter {
+    /// Filtering on something can reveal it, so only let people filter on
+    /// what they could read about everyone.
+    pub fn check(&self, cache: &Cache) -> Result<(), String> {
+        if self.roles.is_some() {
+            check(cache, conditions::read_role)?;
+        }
+        if let Some(ref locations) = self.training_locations {
+            check(cache, conditions::read_students)?;
+            node::uuids(locations, node::LOCATION, "trainingLocations")?;
+        }
+        if self.instructor.is_some() {
+            check(cache, conditions::read_instructors)?;
+        }
+        if self.min_belt.is_some() || self.max_belt.is_some() {
+            check(cache, conditions::read_belt)?;
+        }
+        if self.registered_after.is_some() || self.registered_before.is_some() {
+            check(cache, conditions::read_registration_date)?;
+        }
+        Ok(())
+    }
+
+    /// What the users have to satisfy, or None if anyone will do.
+    pub fn predicate(&self) -> Option<Predicate> {
+        let mut predicates: Vec<Predicate> = Vec::new();
+
+        if let Some(ref roles) = self.roles {
+            let granted = role_grants::table
+                .filter(role_grants::role.eq_any(roles.clone()))
+                .filter(conditions::active_grant())
+                .select(role_grants::user_id);
+            predicates.push(Box::new(users::role.eq_any(roles.clone())
+                .or(users::id.eq_any(granted))));
+        }
+
+        if let Some(ref ids) = self.training_locations {
+            // Already checked, so they're all valid.
+            let uuids = node::uuids(ids, node::LOCATION, "trainingLocations")
+                .unwrap_or_default();
+            let locations = locations::table
+                .filter(locations::uuid.eq_any(uuids))
+                .select(locations::id.nullable());
+            predicates.push(Box::new(users::training_location.eq_any(locations)));
+        }
+
+        if let Some(instructor) = self.instructor {
+            let instructors = instructor_locations::table
+                .filter(conditions::active_assignment())
+                .select(instructor_locations::instructor_id);
+            if instructor {
+                predicates.push(Box::new(users::id.eq_any(instructors)));
+            } else {
+                predicates.push(Box::new(users::id.ne_any(instructors)));
+            }
+        }
+
+        if let Some(belt) = self.min_belt {
+            predicates.push(Box::new(users::belt.ge(belt.rank())));
+        }
+        if let Some(belt) = self.max_belt {
+            predicates.push(Box::new(users::belt.le(belt.rank())));
+        }
+
+        if let Some(ref time) = self.registered_after {
+            predicates.push(Box::new(users::registered.ge(time.0)));
+        }
+        if let Some(ref time) = self.registered_before {
+            predicates.push(Box::new(users::registered.lt(time.0)));
+        }
+
+        predicates.into_iter().fold(None, |all, next| match all {
+            Some(all) => Some(Box::new(all.and(next)) as Predicate),
+            None => Some(next)
+        })
+    }
+}
+
+impl UserSort {
+    /// Sorting by something reveals roughly what it is, so it needs the
+    /// same permission as filtering on it.
+    pub fn check(&self, cache: &Cache, query: bool) -> Result<(), String> {
+        match self.field {
+            UserSortField::Username => check(cache, conditions::read_username),
+            UserSortField::FirstName | UserSortField::LastName =>
+                check(cache, conditions::read_name),
+            UserSortField::Belt => check(cache, conditions::read_belt),
+            UserSortField::Registered =>
+                check(cache, conditions::read_registration_date),
+            UserSortField::Relevance if query => Ok(()),
+            UserSortField::Relevance =>
+                Err(Error::invalid("sort", "sorting by relevance needs a query").into())
+        }
+    }
+
+    pub fn term(&self) -> (UserSortField, bool) {
+        (self.field, self.descending)
+    }
+}
+
+impl UserSortField {
+    /// The value of this column for a row, to put in a cursor.
+    pub fn key(self, user: &User, score: f32) -> Key {
+        match self {
+            UserSortField::Username => Key::Text(user.username.clone()),
+            UserSortField::FirstName => Key::Text(user.first_name.clone()),
+            UserSortField::LastName => Key::Text(user.last_name.clone()),
+            UserSortField::Belt => Key::Int(user.belt),
+            UserSortField::Registered => Key::Time(
+                user.registered.timestamp(),
+                user.registered.timestamp_subsec_nanos()
+            ),
+            UserSortField::Relevance => Key::Score(score)
+        }
+    }
+
+    fn ordering(self, ascending: bool, score: &Fn() -> Score) -> Ordering {
+        macro_rules! ordering {
+            ($column:expr) => {
+                if ascending { Box::new($column.asc()) as Ordering }
+                else { Box::new($column.desc()) }
+            }
+        }
+
+        match self {
+            UserSortField::Username => ordering!(users::username),
+            UserSortField::FirstName => ordering!(users::first_name),
+            UserSortField::LastName => ordering!(users::last_name),
+            UserSortField::Belt => ordering!(users::belt),
+            UserSortField::Registered => ordering!(users::registered),
+            UserSortField::Relevance => ordering!(score())
+        }
+    }
+
+    /// Rows past the key in this column, and rows level with it, or None
+    /// if the key is from a different column.
+    fn compare(
+        self,
+        key: &Key,
+        greater: bool,
+        score: &Fn() -> Score
+    ) -> Option<(Predicate, Predicate)> {
+        macro_rules! compare {
+            ($column:expr, $value:expr) => {{
+                let past: Predicate =
+                    if greater { Box::new($column.gt($value)) }
+                    else { Box::new($column.lt($value)) };
+                (past, Box::new($column.eq($value)) as Predicate)
+            }}
+        }
+
+        Some(match (self, key) {
+            (UserSortField::Username, &Key::Text(ref s)) =>
+                compare!(users::username, s.clone()),
+            (UserSortField::FirstName, &Key::Text(ref s)) =>
+                compare!(users::first_name, s.clone()),
+            (UserSortField::LastName, &Key::Text(ref s)) =>
+                compare!(users::last_name, s.clone()),
+            (UserSortField::Belt, &Key::Int(rank)) =>
+                compare!(users::belt, rank),
+            (UserSortField::Registered, &Key::Time(secs, nanos)) =>
+                compare!(users::registered, UTC.timestamp(secs, nanos)),
+            (UserSortField::Relevance, &Key::Score(s)) =>
+                compare!(score(), s),
+            _ => return None
+        })
+    }
+}
+
+/// How well users match a search query, or nothing in particular if
+/// there isn't one.
+pub fn score(query: &Option<String>) -> Score {
+    match *query {
+        Some(ref query) => Box::new(word_similarity(query.clone(), users::username
+            .concat(" ")
+            .concat(users::first_name)
+            .concat(" ")
+            .concat(users::last_name))),
+        None => Box::new(sql::<Float>("0"))
+    }
+}
+
+/// Rows that come after the cursor, given the columns being sorted on.
+/// The ID breaks any ties. None if the cursor is for a different sort.
+pub fn after(
+    terms: &[(UserSortField, bool)],
+    keys: &[Key],
+    id: i64,
+    backward: bool,
+    score: &Fn() -> Score
+) -> Option<Predicate> {
+    if terms.len() != keys.len() {
+        return None;
+    }
+
+    let mut predicate: Predicate =
+        if backward { Box::new(users::id.lt(id)) }
+        else { Box::new(users::id.gt(id)) };
+
+    for (&(field, descending), key) in terms.iter().zip(keys).rev() {
+        let (past, level) = field.compare(key, descending == backward, score)?;
+        predicate = Box::new(past.or(level.and(predicate)));
+    }
+
+    Some(predicate)
+}
+
+/// The ORDER BY for the columns being sorted on, then the ID.
+pub fn ordering(
+    terms: &[(UserSortField, bool)],
+    backward: bool,
+    score: &Fn() -> Score
+) -> Orderings {
+    let mut orderings = terms.iter()
+        .map(|&(field, descending)| field.ordering(descending == backward, score))
+        .collect::<Vec<_>>();
+    orderings.push(if backward { Box::new(users::id.desc()) as Ordering }
+                   else { Box::new(users::id.asc()) });
+    Orderings(orderings)
+}
+
+/// Any number of orderings, since diesel only takes them in fixed-size tuples.
+pub struct Orderings(Vec<Ordering>);
+
+impl Expression for Orderings {
+    type SqlType = ();
+}
+
+impl AppearsOnTable<users::table> for Orderings {}
+
+impl QueryFragment<Pg> for Orderings {
+    fn to_sql(&self, out: &mut PgQueryBuilder) -> BuildQueryResult {
+        for (i, ordering) in self.0.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(", ");
+            }
+            ordering.to_sql(out)?;
+        }
+        Ok(())
+    }
+
+    fn collect_binds(&self, out: &mut <Pg as diesel::backend::Backend>::BindCollector) -> QueryResult<()> {
+        for ordering in &self.0 {
+            ordering.collect_binds(out)?;
+        }
+        Ok(())
+    }
+
+    fn is_safe_to_cache_prepared(&self) -> bool {
+        self.0.iter().all(|ordering| ordering.is_safe_to_cache_prepared())
+    }
+}
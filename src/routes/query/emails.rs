@@ -0,0 +1,57 @@
+use email::outbox::{self, Queued};
+use juniper::ID;
+use super::{Context, Timestamp};
+use super::node;
+
+graphql_object!(Queued: Context as "Email" |&self| {
+    description: "An email that was queued to be sent."
+
+    field id() -> ID
+    as "A unique ID for the email." {
+        node::encode(node::EMAIL, self.uuid)
+    }
+
+    field recipient() -> &str
+    as "Who it's for." {
+        &self.recipient
+    }
+
+    field subject() -> &str
+    as "The subject line." {
+        &self.subject
+    }
+
+    field status() -> &str
+    as "Whether it's 'pending', 'sent' or 'failed'." {
+        &self.status
+    }
+
+    field attempts() -> i64
+    as "How many times it's been tried." {
+        self.attempts as i64
+    }
+
+    field lastError() -> &Option<String>
+    as "What went wrong the last time it was tried, if anything." {
+        &self.last_error
+    }
+
+    field createdAt() -> Timestamp
+    as "When it was queued." {
+        Timestamp(self.created_at)
+    }
+
+    field nextAttempt() -> Option<Timestamp>
+    as "When it'll be tried next, if it's pending." {
+        if self.status == outbox::PENDING {
+            Some(Timestamp(self.next_attempt))
+        } else {
+            None
+        }
+    }
+
+    field sentAt() -> Option<Timestamp>
+    as "When it was sent, if it was." {
+        self.sent_at.map(Timestamp)
+    }
+});
@@ -0,0 +1,213 @@
+use diesel::result::{Error as DieselError, DatabaseErrorKind};
+use email::Error as EmailError;
+use i18n::Catalog;
+use juniper::ExecutionError;
+use serde_json::{self, Value};
+
+/// What went wrong, for clients to act on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Code {
+    Unauthorized,
+    NotFound,
+    InvalidArgument,
+    InvalidReference,
+    UsernameTaken,
+    EmailTaken,
+    AlreadyExists,
+    QueryTooDeep,
+    QueryTooComplex,
+    ListTooLong,
+    UnknownQuery,
+    PaymentDeclined,
+    TooManySubscriptions,
+    ServerError
+}
+
+impl Code {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Code::Unauthorized => "UNAUTHORIZED",
+            Code::NotFound => "NOT_FOUND",
+            Code::InvalidArgument => "INVALID_ARGUMENT",
+            Code::InvalidReference => "INVALID_REFERENCE",
+            Code::UsernameTaken => "USERNAME_TAKEN",
+            Code::EmailTaken => "EMAIL_TAKEN",
+            Code::AlreadyExists => "ALREADY_EXISTS",
+            Code::QueryTooDeep => "QUERY_TOO_DEEP",
+            Code::QueryTooComplex => "QUERY_TOO_COMPLEX",
+            Code::ListTooLong => "LIST_TOO_LONG",
+            Code::UnknownQuery => "UNKNOWN_QUERY",
+            Code::PaymentDeclined => "PAYMENT_DECLINED",
+            Code::TooManySubscriptions => "TOO_MANY_SUBSCRIPTIONS",
+            Code::ServerError => "SERVER_ERROR"
+        }
+    }
+}
+
+/// Database constraints, and the errors their violations mean.
+/// The field is the name of the argument that usually causes it.
+static CONSTRAINTS: &'static [(&'static str, Code, &'static str, &'static str)] = &[
+    ("users_username_key", Code::UsernameTaken, "username", "username taken"),
+    ("users_email_key", Code::EmailTaken, "email", "email address taken"),
+    ("users_username_check", Code::InvalidArgument, "username", "invalid username"),
+    ("users_email_check", Code::InvalidArgument, "email", "invalid email address"),
+    ("users_first_name_check", Code::InvalidArgument, "firstName", "empty first name"),
+    ("users_last_name_check", Code::InvalidArgument, "lastName", "empty last name"),
+    ("users_training_location_fkey", Code::InvalidReference, "location", "no such location"),
+    ("locations_name_check", Code::InvalidArgument, "name", "empty name"),
+    ("locations_address_check", Code::InvalidArgument, "address", "empty address"),
+    ("latlng_bounds", Code::InvalidArgument, "lat", "invalid coordinates"),
+    ("instructor_locations_instructor_id_fkey", Code::InvalidReference, "user", "no such user"),
+    ("instructor_locations_location_id_fkey", Code::InvalidReference, "location", "no such location"),
+    ("assignment_window", Code::InvalidArgument, "validUntil", "window ends before it starts"),
+    ("role_grants_user_id_fkey", Code::InvalidReference, "user", "no such user"),
+    ("grant_window", Code::InvalidArgument, "validUntil", "window ends before it starts"),
+    ("announcements_title_check", Code::InvalidArgument, "title", "empty title"),
+    ("announcements_body_check", Code::InvalidArgument, "body", "empty body"),
+    ("announcement_audience", Code::InvalidArgument, "role", "both a location and a role"),
+    ("announcements_location_id_fkey", Code::InvalidReference, "location", "no such location"),
+    ("users_locale_check", Code::InvalidArgument, "locale", "unsupported locale"),
+    ("classes_title_check", Code::InvalidArgument, "title", "empty title"),
+    ("classes_duration_check", Code::InvalidArgument, "duration", "duration isn't positive"),
+    ("class_window", Code::InvalidArgument, "validUntil", "window ends before it starts"),
+    ("classes_location_id_fkey", Code::InvalidReference, "location", "no such location"),
+    ("classes_instructor_id_fkey", Code::InvalidReference, "instructor", "no such user"),
+    ("class_cancellations_pkey", Code::AlreadyExists, "date", "already cancelled"),
+    ("attendance_user_id_fkey", Code::InvalidReference, "students", "no such user"),
+    ("membership_plans_name_check", Code::InvalidArgument, "name", "empty name"),
+    ("membership_plans_price_check", Code::InvalidArgument, "price", "negative price"),
+    ("membership_plans_currency_check", Code::InvalidArgument, "currency", "invalid currency"),
+    ("membership_plans_months_check", Code::InvalidArgument, "months", "months isn't positive"),
+    ("membership_plans_max_members_check", Code::InvalidArgument, "maxMembers", "max members isn't positive"),
+    ("memberships_plan_id_fkey", Code::InvalidReference, "plan", "no such plan"),
+    ("memberships_payer_id_fkey", Code::InvalidReference, "payer", "no such user"),
+    ("membership_window", Code::InvalidArgument, "endsOn", "ends before it starts"),
+    ("membership_members_user_id_fkey", Code::InvalidReference, "members", "no such user"),
+    ("payments_amount_check", Code::InvalidArgument, "amount", "amount isn't positive"),
+    ("duplicate_payments", Code::AlreadyExists, "reference", "payment already recorded")
+];
+
+/// An error from a resolver. Juniper only passes strings along, so it
+/// travels as JSON in the message and gets unpacked by `describe`.
+#[derive(Debug)]
+pub struct Error {
+    code: Code,
+    field: Option<&'static str>,
+    message: String
+}
+
+#[derive(Serialize, Deserialize)]
+struct Encoded {
+    code: String,
+    field: Option<String>,
+    message: String
+}
+
+impl Error {
+    pub fn new<S: Into<String>>(code: Code, message: S) -> Error {
+        Error {
+            code: code,
+            field: None,
+            message: message.into()
+        }
+    }
+
+    /// A bad value for the given argument.
+    pub fn invalid<S: Into<String>>(field: &'static str, message: S) -> Error {
+        Error::new(Code::InvalidArgument, message).on(field)
+    }
+
+    pub fn unauthorized() -> Error {
+        Error::new(Code::Unauthorized, "unauthorized")
+    }
+
+    pub fn code(&self) -> Code {
+        self.code
+    }
+
+    /// Blame a particular argument.
+    pub fn on(mut self, field: &'static str) -> Error {
+        self.field = Some(field);
+        self
+    }
+
+    /// The error on its own, for when the query as a whole is refused.
+    pub fn describe(self, locale: &str) -> Value {
+        let catalog = Catalog::get(locale);
+        json!({
+            "message": catalog.error(self.code.as_str(), &self.message),
+            "code": self.code.as_str(),
+            "field": self.field
+        })
+    }
+}
+
+impl From<Error> for String {
+    fn from(err: Error) -> String {
+        serde_json::to_string(&Encoded {
+            code: err.code.as_str().to_owned(),
+            field: err.field.map(|x| x.to_owned()),
+            message: err.message
+        }).unwrap()
+    }
+}
+
+impl From<DieselError> for Error {
+    fn from(err: DieselError) -> Error {
+        let (kind, info) = match err {
+            DieselError::NotFound =>
+                return Error::new(Code::NotFound, "not found"),
+            DieselError::DatabaseError(kind, info) => (kind, info),
+            _ => return Error::new(Code::ServerError, "server error")
+        };
+
+        let known = info.constraint_name().and_then(|name| {
+            CONSTRAINTS.iter().find(|constraint| constraint.0 == name)
+        });
+
+        match (known, kind) {
+            (Some(&(_, code, field, message)), _) =>
+                Error::new(code, message).on(field),
+            (None, DatabaseErrorKind::UniqueViolation) =>
+                Error::new(Code::AlreadyExists, "unique violation"),
+            (None, DatabaseErrorKind::ForeignKeyViolation) =>
+                Error::new(Code::InvalidReference, "foreign key violation"),
+            (None, _) =>
+                Error::new(Code::ServerError, "server error")
+        }
+    }
+}
+
+impl From<EmailError> for Error {
+    fn from(err: EmailError) -> Error {
+        match err {
+            EmailError::Database(e) => Error::from(e),
+            EmailError::Template(e) => {
+                error!("Email Error: {}.", e);
+                Error::new(Code::ServerError, "server error")
+            }
+        }
+    }
+}
+
+/// The errors from running a query, with the codes and fields unpacked
+/// and the messages translated.
+pub fn describe(errors: &[ExecutionError], locale: &str) -> Value {
+    let catalog = Catalog::get(locale);
+    Value::Array(errors.iter().map(|err| {
+        let encoded = serde_json::from_str::<Encoded>(err.message())
+            .unwrap_or_else(|_| Encoded {
+                code: Code::ServerError.as_str().to_owned(),
+                field: None,
+                message: err.message().to_owned()
+            });
+
+        json!({
+            "message": catalog.error(&encoded.code, &encoded.message),
+            "code": encoded.code,
+            "field": encoded.field,
+            "locations": [err.location()],
+            "path": err.path()
+        })
+    }).collect())
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use super::{User, Location};
+
+/// Rows belonging to other rows, fetched for a whole list the first time
+/// any item in it asks for them, rather than once per item.
+pub struct Loader<V> {
+    loaded: Mutex<HashMap<i64, Vec<V>>>
+}
+
+impl<V> Default for Loader<V> {
+    fn default() -> Loader<V> {
+        Loader { loaded: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl<V: Clone> Loader<V> {
+    /// The rows belonging to `key`. If they haven't been fetched yet, they're
+    /// fetched along with those of everything in `batch` that hasn't been
+    /// either. `fetch` gets the keys and returns (key, row) pairs.
+    pub fn load<F>(&self, key: i64, batch: &[i64], fetch: F) -> Result<Vec<V>, String>
+    where F: FnOnce(Vec<i64>) -> Result<Vec<(i64, V)>, String> {
+        let mut loaded = self.loaded.lock().unwrap();
+        if let Some(rows) = loaded.get(&key) {
+            return Ok(rows.clone());
+        }
+
+        let mut keys = batch.iter()
+            .cloned()
+            .filter(|k| !loaded.contains_key(k))
+            .collect::<Vec<_>>();
+        keys.push(key);
+        keys.sort();
+        keys.dedup();
+
+        let rows = fetch(keys.clone())?;
+        for k in keys {
+            loaded.insert(k, Vec::new());
+        }
+        for (k, row) in rows {
+            loaded.entry(k).or_insert_with(Vec::new).push(row);
+        }
+
+        Ok(loaded[&key].clone())
+    }
+
+    /// Fetch these keys' rows again the next time they're asked for.
+    pub fn forget(&self, keys: &[i64]) {
+        let mut loaded = self.loaded.lock().unwrap();
+        for key in keys {
+            loaded.remove(key);
+        }
+    }
+
+    /// Fetch everything again the next time it's asked for.
+    pub fn forget_all(&self) {
+        self.loaded.lock().unwrap().clear();
+    }
+}
+
+/// Everything loaded in batches during a request.
+#[derive(Default)]
+pub struct Loaders {
+    pub instructors: Loader<User>, // By location.
+    pub students: Loader<User>, // By location.
+    pub training_location: Loader<Location>, // By user.
+    pub instructing_locations: Loader<Location> // By user.
+}
+
+impl Loaders {
+    /// Forget everything about some users, after they or the places they
+    /// train or instruct at have changed. They could be anywhere in the
+    /// lists by location, so those are forgotten entirely.
+    pub fn forget_users(&self, users: &[i64]) {
+        self.instructors.forget_all();
+        self.students.forget_all();
+        self.training_location.forget(users);
+        self.instructing_locations.forget(users);
+    }
+
+    /// Forget everything about some locations, after they've changed.
+    pub fn forget_locations(&self, locations: &[i64]) {
+        self.instructors.forget(locations);
+        self.students.forget(locations);
+        self.training_location.forget_all();
+        self.instructing_locations.forget_all();
+    }
+}
@@ -0,0 +1,839 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, UTC};
+use conditions::Cache;
+use diesel;
+use diesel::pg::PgConnection;
+use diesel::pg::upsert::*;
+use diesel::prelude::*;
+use juniper::ID;
+use payments::{Charge, Error as ChargeError, Payments};
+use schema::{users, membership_plans, memberships, membership_members, invoices, payments};
+use std::collections::HashMap;
+use super::{Context, Day, Timestamp, User, UserWrapper, stringify_error, wrap_users};
+use super::error::{Code, Error};
+use super::node;
+use uuid::Uuid;
+
+//LONG: Prorating memberships that start or end partway through a period.
+
+/// How long after a period starts its invoice is due.
+const GRACE_DAYS: i64 = 7;
+
+#[derive(Queryable, Clone)]
+pub struct Plan {
+    id: i64,
+    name: String,
+    kind: String,
+    price: i64,
+    currency: String,
+    months: i32,
+    max_members: i32,
+    active: bool,
+    uuid: Uuid
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PlanKind {
+    Monthly,
+    Term,
+    Family
+}
+
+impl PlanKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PlanKind::Monthly => "monthly",
+            PlanKind::Term => "term",
+            PlanKind::Family => "family"
+        }
+    }
+
+    pub fn from_str(kind: &str) -> Option<PlanKind> {
+        [PlanKind::Monthly, PlanKind::Term, PlanKind::Family]
+            .iter()
+            .cloned()
+            .find(|x| x.as_str() == kind)
+    }
+}
+
+graphql_enum!(PlanKind {
+    PlanKind::Monthly => "MONTHLY",
+    PlanKind::Term => "TERM",
+    PlanKind::Family => "FAMILY"
+});
+
+#[derive(Insertable)]
+#[table_name="membership_plans"]
+pub struct NewPlan<'a> {
+    pub name: &'a str,
+    pub kind: &'static str,
+    pub price: i64,
+    pub currency: Option<&'a str>,
+    pub months: i32,
+    pub max_members: Option<i32>
+}
+
+#[derive(AsChangeset)]
+#[table_name="membership_plans"]
+pub struct PlanChanges<'a> {
+    pub name: Option<&'a str>,
+    pub price: Option<i64>,
+    pub max_members: Option<i32>,
+    pub active: Option<bool>
+}
+
+#[derive(Queryable)]
+pub struct Membership {
+    id: i64,
+    plan_id: i64,
+    payer_id: i64,
+    starts_on: NaiveDate,
+    ends_on: Option<NaiveDate>,
+    uuid: Uuid
+}
+
+pub struct MembershipWrapper<'a> {
+    membership: Membership,
+    cache: Cache<'a>
+}
+
+#[derive(Insertable)]
+#[table_name="memberships"]
+struct NewMembership {
+    plan_id: i64,
+    payer_id: i64,
+    starts_on: Option<NaiveDate>
+}
+
+#[derive(Insertable)]
+#[table_name="membership_members"]
+struct Member {
+    membership_id: i64,
+    user_id: i64
+}
+
+#[derive(Queryable, Clone)]
+pub struct Invoice {
+    id: i64,
+    membership_id: i64,
+    amount: i64,
+    currency: String,
+    period_start: NaiveDate,
+    period_end: NaiveDate,
+    due_on: NaiveDate,
+    created_at: DateTime<UTC>,
+    paid_at: Option<DateTime<UTC>>,
+    uuid: Uuid
+}
+
+pub struct InvoiceWrapper<'a> {
+    invoice: Invoice,
+    cache: Cache<'a>
+}
+
+#[derive(Insertable)]
+#[table_name="invoices"]
+struct NewInvoice<'a> {
+    membership_id: i64,
+    amount: i64,
+    currency: &'a str,
+    period_start: NaiveDate,
+    period_end: NaiveDate,
+    due_on: NaiveDate,
+    paid_at: Option<DateTime<UTC>>
+}
+
+#[derive(Queryable)]
+pub struct Payment {
+    id: i64,
+    invoice_id: i64,
+    amount: i64,
+    provider: Option<String>,
+    reference: Option<String>,
+    recorded_by: Option<i64>,
+    paid_at: DateTime<UTC>,
+    uuid: Uuid
+}
+
+#[derive(Insertable)]
+#[table_name="payments"]
+struct NewPayment<'a> {
+    invoice_id: i64,
+    amount: i64,
+    provider: Option<&'a str>,
+    reference: Option<&'a str>,
+    recorded_by: Option<i64>
+}
+
+/// Someone training at a location with invoices past due.
+pub struct OverdueMember<'a> {
+    user: UserWrapper<'a>,
+    invoices: Vec<InvoiceWrapper<'a>>,
+    owing: i64
+}
+
+graphql_object!(Plan: Context as "MembershipPlan" |&self| {
+    description: "Something people can pay for to train, charged every \
+                  period. Amounts are in cents."
+
+    field id() -> ID
+    as "A unique ID for the plan." {
+        node::encode(node::PLAN, self.uuid)
+    }
+
+    field name() -> &str
+    as "What the plan is called." {
+        &self.name
+    }
+
+    field kind() -> Option<PlanKind>
+    as "What sort of plan it is." {
+        PlanKind::from_str(&self.kind)
+    }
+
+    field price() -> i64
+    as "How much a period costs, in cents." {
+        self.price
+    }
+
+    field currency() -> &str
+    as "The ISO 4217 code of the currency it's charged in, like `AUD`." {
+        &self.currency
+    }
+
+    field months() -> i32
+    as "How many months a period lasts." {
+        self.months
+    }
+
+    field maxMembers() -> i32
+    as "How many people can train under one membership." {
+        self.max_members
+    }
+
+    field active() -> bool
+    as "Whether people can still join it." {
+        self.active
+    }
+});
+
+graphql_object!(<'a> MembershipWrapper<'a>: Context as "Membership" |&self| {
+    description: "Someone paying for a plan, for themselves or their family."
+
+    field id() -> ID
+    as "A unique ID for the membership." {
+        node::encode(node::MEMBERSHIP, self.membership.uuid)
+    }
+
+    field plan() -> Result<Plan, String>
+    as "What's being paid for." {
+        membership_plans::table.find(self.membership.plan_id)
+            .first(&**self.cache.database())
+            .map_err(stringify_error)
+    }
+
+    field payer() -> Result<UserWrapper, String>
+    as "Who gets the invoices." {
+        users::table.find(self.membership.payer_id)
+            .first::<User>(&**self.cache.database())
+            .map(|user| wrap_users(self.cache, vec![user]).remove(0))
+            .map_err(stringify_error)
+    }
+
+    field members() -> Result<Vec<UserWrapper>, String>
+    as "Who trains under it." {
+        let members = membership_members::table
+            .filter(membership_members::membership_id.eq(self.membership.id))
+            .select(membership_members::user_id);
+
+        users::table
+            .filter(users::id.eq_any(members))
+            .order((users::first_name, users::last_name))
+            .get_results(&**self.cache.database())
+            .map(|users| wrap_users(self.cache, users))
+            .map_err(stringify_error)
+    }
+
+    field startsOn() -> Day
+    as "The first day it covers." {
+        Day(self.membership.starts_on)
+    }
+
+    field endsOn() -> Option<Day>
+    as "The last day it covers, if it's been ended." {
+        self.membership.ends_on.map(Day)
+    }
+
+    field invoices() -> Result<Vec<InvoiceWrapper>, String>
+    as "What's been charged for it, newest first." {
+        invoices::table
+            .filter(invoices::membership_id.eq(self.membership.id))
+            .order(invoices::period_start.desc())
+            .get_results(&**self.cache.database())
+            .map(|found| wrap_invoices(self.cache, found))
+            .map_err(stringify_error)
+    }
+});
+
+graphql_object!(<'a> InvoiceWrapper<'a>: Context as "Invoice" |&self| {
+    description: "What's owed for one period of a membership. Amounts are \
+                  in cents."
+
+    field id() -> ID
+    as "A unique ID for the invoice." {
+        node::encode(node::INVOICE, self.invoice.uuid)
+    }
+
+    field amount() -> i64
+    as "How much it's for." {
+        self.invoice.amount
+    }
+
+    field currency() -> &str
+    as "The ISO 4217 code of the currency it's in." {
+        &self.invoice.currency
+    }
+
+    field periodStart() -> Day
+    as "The first day of the period it's for." {
+        Day(self.invoice.period_start)
+    }
+
+    field periodEnd() -> Day
+    as "The day after the period it's for ends." {
+        Day(self.invoice.period_end)
+    }
+
+    field dueOn() -> Day
+    as "When it has to be paid by." {
+        Day(self.invoice.due_on)
+    }
+
+    field created() -> Timestamp
+    as "When it was issued." {
+        Timestamp(self.invoice.created_at)
+    }
+
+    field paidAt() -> Option<Timestamp>
+    as "When it was paid off, if it has been." {
+        self.invoice.paid_at.map(Timestamp)
+    }
+
+    field overdue() -> bool
+    as "Whether it's unpaid and past due." {
+        self.invoice.paid_at.is_none() && self.invoice.due_on < UTC::today().naive_utc()
+    }
+
+    field owing() -> Result<i64, String>
+    as "How much is left to pay." {
+        owing(&**self.cache.database(), &self.invoice).map_err(stringify_error)
+    }
+
+    field payments() -> Result<Vec<Payment>, String>
+    as "The payments towards it, oldest first." {
+        payments::table
+            .filter(payments::invoice_id.eq(self.invoice.id))
+            .order(payments::paid_at)
+            .get_results(&**self.cache.database())
+            .map_err(stringify_error)
+    }
+
+    field membership() -> Result<MembershipWrapper, String>
+    as "The membership it's for." {
+        memberships::table.find(self.invoice.membership_id)
+            .first(&**self.cache.database())
+            .map(|membership| MembershipWrapper { membership: membership, cache: self.cache })
+            .map_err(stringify_error)
+    }
+});
+
+graphql_object!(Payment: Context as "Payment" |&self| {
+    description: "Money received for an invoice. Amounts are in cents."
+
+    field id() -> ID
+    as "A unique ID for the payment." {
+        node::encode(node::PAYMENT, self.uuid)
+    }
+
+    field amount() -> i64
+    as "How much was paid." {
+        self.amount
+    }
+
+    field provider() -> Option<&str>
+    as "Who took the payment, or null if it was recorded by hand." {
+        self.provider.as_ref().map(|x| x.as_str())
+    }
+
+    field reference() -> Option<&str>
+    as "The provider's or a receipt's reference for it." {
+        self.reference.as_ref().map(|x| x.as_str())
+    }
+
+    field paidAt() -> Timestamp
+    as "When it was received." {
+        Timestamp(self.paid_at)
+    }
+});
+
+graphql_object!(<'a> OverdueMember<'a>: Context as "OverdueMember" |&self| {
+    description: "Someone training at a location with invoices past due."
+
+    field user() -> &UserWrapper
+    as "Who it is." {
+        &self.user
+    }
+
+    field invoices() -> &Vec<InvoiceWrapper>
+    as "Their memberships' overdue invoices, oldest first." {
+        &self.invoices
+    }
+
+    field owing() -> i64
+    as "How much is left to pay on them altogether, in cents." {
+        self.owing
+    }
+});
+
+pub fn plans(db: &PgConnection, include_inactive: bool) -> QueryResult<Vec<Plan>> {
+    if include_inactive {
+        membership_plans::table
+            .order(membership_plans::name)
+            .get_results(db)
+    } else {
+        membership_plans::table
+            .filter(membership_plans::active.eq(true))
+            .order(membership_plans::name)
+            .get_results(db)
+    }
+}
+
+pub fn create_plan(db: &PgConnection, new_plan: &NewPlan) -> Result<Plan, Error> {
+    let shared = new_plan.max_members.map_or(false, |x| x > 1);
+    if shared && new_plan.kind != PlanKind::Family.as_str() {
+        return Err(Error::invalid("maxMembers", "only family plans can be shared"));
+    }
+
+    diesel::insert(new_plan)
+        .into(membership_plans::table)
+        .get_result(db)
+        .map_err(Error::from)
+}
+
+pub fn edit_plan(db: &PgConnection, id: i64, changes: &PlanChanges) -> Result<Plan, Error> {
+    let plan = membership_plans::table.find(id).first::<Plan>(db)?;
+    let shared = changes.max_members.map_or(false, |x| x > 1);
+    if shared && plan.kind != PlanKind::Family.as_str() {
+        return Err(Error::invalid("maxMembers", "only family plans can be shared"));
+    }
+
+    diesel::update(membership_plans::table.find(id))
+        .set(changes)
+        .get_result(db)
+        .map_err(Error::from)
+}
+
+pub fn find(db: &PgConnection, id: i64) -> Result<Membership, Error> {
+    memberships::table.find(id)
+        .first(db)
+        .map_err(Error::from)
+}
+
+pub fn find_invoice(db: &PgConnection, id: i64) -> Result<(Invoice, Membership), Error> {
+    let invoice = invoices::table.find(id).first::<Invoice>(db)?;
+    let membership = find(db, invoice.membership_id)?;
+    Ok((invoice, membership))
+}
+
+impl Membership {
+    pub fn payer(&self) -> i64 {
+        self.payer_id
+    }
+}
+
+/// The memberships the user pays for or trains under, newest first.
+pub fn of_user<'a>(cache: Cache<'a>, user: i64) -> QueryResult<Vec<MembershipWrapper<'a>>> {
+    let member_of = membership_members::table
+        .filter(membership_members::user_id.eq(user))
+        .select(membership_members::membership_id);
+
+    memberships::table
+        .filter(memberships::payer_id.eq(user).or(memberships::id.eq_any(member_of)))
+        .order(memberships::starts_on.desc())
+        .get_results::<Membership>(&**cache.database())
+        .map(|found| found.into_iter()
+            .map(|membership| MembershipWrapper { membership: membership, cache: cache })
+            .collect())
+}
+
+/// Sign people up to a plan. With no members, the payer trains under it.
+pub fn create<'a>(
+    cache: Cache<'a>,
+    plan: i64,
+    payer: i64,
+    members: &[i64],
+    starts_on: Option<NaiveDate>
+) -> Result<MembershipWrapper<'a>, Error> {
+    let db = &**cache.database();
+    let plan = match membership_plans::table.find(plan).first::<Plan>(db).optional()? {
+        Some(plan) => plan,
+        None => return Err(Error::new(Code::InvalidReference, "no such plan").on("plan"))
+    };
+
+    if !plan.active {
+        return Err(Error::invalid("plan", "the plan isn't taking new members"));
+    }
+
+    let mut members = if members.is_empty() { vec![payer] } else { members.to_vec() };
+    members.sort();
+    members.dedup();
+    if members.len() > plan.max_members as usize {
+        return Err(Error::invalid("members", "too many members for the plan"));
+    }
+
+    let membership = db.transaction::<_, Error, _>(|| {
+        let membership: Membership = diesel::insert(&NewMembership {
+            plan_id: plan.id,
+            payer_id: payer,
+            starts_on: starts_on
+        })
+        .into(memberships::table)
+        .get_result(db)?;
+
+        let rows = members.iter()
+            .map(|&user| Member { membership_id: membership.id, user_id: user })
+            .collect::<Vec<_>>();
+
+        diesel::insert(&rows)
+            .into(membership_members::table)
+            .execute(db)?;
+
+        Ok(membership)
+    })?;
+
+    Ok(MembershipWrapper { membership: membership, cache: cache })
+}
+
+/// Stop a membership after the given day, dropping unpaid invoices for
+/// periods that start later.
+pub fn end<'a>(
+    cache: Cache<'a>,
+    membership: Membership,
+    ends_on: NaiveDate
+) -> Result<MembershipWrapper<'a>, Error> {
+    let db = &**cache.database();
+    let membership = db.transaction::<_, Error, _>(|| {
+        diesel::delete(invoices::table
+            .filter(invoices::membership_id.eq(membership.id))
+            .filter(invoices::period_start.gt(ends_on))
+            .filter(invoices::paid_at.is_null()))
+            .execute(db)?;
+
+        diesel::update(memberships::table.find(membership.id))
+            .set(memberships::ends_on.eq(Some(ends_on)))
+            .get_result::<Membership>(db)
+            .map_err(Error::from)
+    })?;
+
+    Ok(MembershipWrapper { membership: membership, cache: cache })
+}
+
+/// Issue every invoice for periods that have started by the given day and
+/// haven't been invoiced yet. Running it again does nothing new, so it's
+/// safe to run as often as you like.
+pub fn issue<'a>(cache: Cache<'a>, today: NaiveDate) -> Result<Vec<InvoiceWrapper<'a>>, Error> {
+    let db = &**cache.database();
+    let current = memberships::table
+        .filter(memberships::starts_on.le(today))
+        .get_results::<Membership>(db)?;
+
+    let plans = membership_plans::table
+        .filter(membership_plans::id.eq_any(current.iter().map(|x| x.plan_id).collect::<Vec<_>>()))
+        .get_results::<Plan>(db)?
+        .into_iter()
+        .map(|plan| (plan.id, plan))
+        .collect::<HashMap<_, _>>();
+
+    // Where each membership's invoices are up to.
+    let mut invoiced = HashMap::new();
+    let issued = invoices::table
+        .filter(invoices::membership_id.eq_any(current.iter().map(|x| x.id).collect::<Vec<_>>()))
+        .select((invoices::membership_id, invoices::period_end))
+        .get_results::<(i64, NaiveDate)>(db)?;
+    for (membership, end) in issued {
+        let latest = invoiced.entry(membership).or_insert(end);
+        *latest = end.max(*latest);
+    }
+
+    let now = UTC::now();
+    let mut new_invoices = Vec::new();
+    for membership in &current {
+        let plan = &plans[&membership.plan_id];
+        let latest = invoiced.get(&membership.id).cloned();
+        for n in 0.. {
+            let (start, end) = period(membership.starts_on, plan.months, n);
+            if start > today || membership.ends_on.map_or(false, |last| start > last) {
+                break;
+            }
+            if latest.map_or(false, |latest| start < latest) {
+                continue;
+            }
+
+            new_invoices.push(NewInvoice {
+                membership_id: membership.id,
+                amount: plan.price,
+                currency: &plan.currency,
+                period_start: start,
+                period_end: end,
+                due_on: start + Duration::days(GRACE_DAYS),
+                paid_at: if plan.price == 0 { Some(now) } else { None }
+            });
+        }
+    }
+
+    if new_invoices.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    diesel::insert(&new_invoices.on_conflict_do_nothing())
+        .into(invoices::table)
+        .get_results(db)
+        .map(|found| wrap_invoices(cache, found))
+        .map_err(Error::from)
+}
+
+/// Record money received for an invoice, marking it paid once it's all
+/// there. It can't be more than what's left to pay.
+pub fn record_payment<'a>(
+    cache: Cache<'a>,
+    invoice: Invoice,
+    amount: i64,
+    reference: Option<&str>
+) -> Result<InvoiceWrapper<'a>, Error> {
+    let db = &**cache.database();
+    let invoice = db.transaction::<_, Error, _>(|| {
+        let (invoice, owing) = lock(db, invoice.id)?;
+        if amount > owing {
+            return Err(Error::invalid("amount", "more than what's owed"));
+        }
+
+        record(db, cache.user, invoice, owing, amount, None, reference)
+    })?;
+
+    Ok(InvoiceWrapper { invoice: invoice, cache: cache })
+}
+
+/// Pay off what's left of an invoice through the payment provider.
+pub fn pay<'a>(
+    cache: Cache<'a>,
+    payments: &Payments,
+    invoice: Invoice,
+    source: &str
+) -> Result<InvoiceWrapper<'a>, Error> {
+    let provider = match payments.provider() {
+        Some(provider) => provider,
+        None => return Err(Error::invalid("source", "online payments aren't available"))
+    };
+
+    // The invoice stays locked while it's charged, so paying twice at once
+    // waits and then finds it paid instead of charging again.
+    let db = &**cache.database();
+    let invoice = db.transaction::<_, Error, _>(|| {
+        let (invoice, owing) = lock(db, invoice.id)?;
+        let description = format!("Invoice {}", invoice.id);
+        // The same for retries until something's paid, in case a charge
+        // went through but couldn't be recorded.
+        let key = format!("invoice-{}-{}", invoice.id, invoice.amount - owing);
+        let charged = provider.charge(&Charge {
+            amount: owing,
+            currency: &invoice.currency,
+            source: source,
+            description: &description,
+            idempotency_key: &key
+        });
+
+        let reference = match charged {
+            Ok(reference) => reference,
+            Err(ChargeError::Declined(e)) => {
+                info!("A payment for invoice {} was declined: {}.", invoice.id, e);
+                let e = Error::new(Code::PaymentDeclined, "payment declined");
+                return Err(e.on("source"));
+            },
+            Err(ChargeError::Unavailable(e)) => {
+                error!("Could not charge for invoice {}: {}.", invoice.id, e);
+                return Err(Error::new(Code::ServerError, "server error"));
+            }
+        };
+
+        let id = invoice.id;
+        record(db, cache.user, invoice, owing, owing, Some(provider.name()), Some(&reference))
+            .map_err(|e| {
+                error!(
+                    "Could not record payment {} from {} for invoice {}.",
+                    reference,
+                    provider.name(),
+                    id
+                );
+                e
+            })
+    })?;
+
+    Ok(InvoiceWrapper { invoice: invoice, cache: cache })
+}
+
+/// Lock an unpaid invoice until the end of the transaction, so payments
+/// towards it happen one at a time, and get how much is left to pay on it.
+fn lock(db: &PgConnection, invoice: i64) -> Result<(Invoice, i64), Error> {
+    //LONG: Use the query builder once Diesel supports FOR UPDATE.
+    db.execute(&format!("SELECT id FROM invoices WHERE id = {} FOR UPDATE", invoice))?;
+    let invoice = invoices::table.find(invoice).first::<Invoice>(db)?;
+    let owing = owing(db, &invoice)?;
+    if invoice.paid_at.is_some() || owing <= 0 {
+        return Err(Error::invalid("invoice", "already paid"));
+    }
+
+    Ok((invoice, owing))
+}
+
+/// Add a payment to a locked invoice, marking it paid if that covers it.
+fn record(
+    db: &PgConnection,
+    recorded_by: Option<i64>,
+    invoice: Invoice,
+    owing: i64,
+    amount: i64,
+    provider: Option<&str>,
+    reference: Option<&str>
+) -> Result<Invoice, Error> {
+    diesel::insert(&NewPayment {
+        invoice_id: invoice.id,
+        amount: amount,
+        provider: provider,
+        reference: reference,
+        recorded_by: recorded_by
+    })
+    .into(payments::table)
+    .execute(db)?;
+
+    if amount < owing {
+        return Ok(invoice);
+    }
+
+    diesel::update(invoices::table.find(invoice.id))
+        .set(invoices::paid_at.eq(Some(UTC::now())))
+        .get_result::<Invoice>(db)
+        .map_err(Error::from)
+}
+
+/// Everyone training at the location under a membership with invoices past
+/// due, by name.
+pub fn overdue<'a>(
+    cache: Cache<'a>,
+    location: i64,
+    today: NaiveDate
+) -> Result<Vec<OverdueMember<'a>>, Error> {
+    let db = &**cache.database();
+    let overdue = invoices::table
+        .filter(invoices::paid_at.is_null())
+        .filter(invoices::due_on.lt(today))
+        .order(invoices::due_on)
+        .get_results::<Invoice>(db)?;
+
+    let members = membership_members::table
+        .filter(membership_members::membership_id.eq_any(
+            overdue.iter().map(|x| x.membership_id).collect::<Vec<_>>()
+        ))
+        .select((membership_members::membership_id, membership_members::user_id))
+        .get_results::<(i64, i64)>(db)?;
+
+    let students = users::table
+        .filter(users::training_location.eq(location))
+        .filter(users::id.eq_any(members.iter().map(|x| x.1).collect::<Vec<_>>()))
+        .order((users::last_name, users::first_name))
+        .get_results::<User>(db)?;
+
+    let paid = paid(db, &overdue.iter().map(|x| x.id).collect::<Vec<_>>())?;
+    Ok(wrap_users(cache, students).into_iter()
+        .map(|user| {
+            let invoices = overdue.iter()
+                .filter(|invoice| members.contains(&(invoice.membership_id, user.user.id)))
+                .cloned()
+                .collect::<Vec<_>>();
+            let owing = invoices.iter()
+                .map(|x| x.amount - paid.get(&x.id).cloned().unwrap_or(0))
+                .sum::<i64>();
+
+            OverdueMember {
+                user: user,
+                invoices: wrap_invoices(cache, invoices),
+                owing: owing
+            }
+        })
+        .collect())
+}
+
+/// How much is left to pay on an invoice.
+fn owing(db: &PgConnection, invoice: &Invoice) -> QueryResult<i64> {
+    let paid = paid(db, &[invoice.id])?;
+    Ok(invoice.amount - paid.get(&invoice.id).cloned().unwrap_or(0))
+}
+
+/// How much has been paid towards each of the invoices.
+fn paid(db: &PgConnection, invoices: &[i64]) -> QueryResult<HashMap<i64, i64>> {
+    let mut paid = HashMap::new();
+    let found = payments::table
+        .filter(payments::invoice_id.eq_any(invoices))
+        .select((payments::invoice_id, payments::amount))
+        .get_results::<(i64, i64)>(db)?;
+
+    for (invoice, amount) in found {
+        *paid.entry(invoice).or_insert(0) += amount;
+    }
+    Ok(paid)
+}
+
+fn wrap_invoices<'a>(cache: Cache<'a>, found: Vec<Invoice>) -> Vec<InvoiceWrapper<'a>> {
+    found.into_iter()
+        .map(|invoice| InvoiceWrapper { invoice: invoice, cache: cache })
+        .collect()
+}
+
+/// When the nth period of a membership starts and ends. They're all counted
+/// from the start, so one that starts on the 31st goes back to the 31st
+/// after a short month.
+fn period(starts_on: NaiveDate, months: i32, n: i32) -> (NaiveDate, NaiveDate) {
+    (add_months(starts_on, n * months), add_months(starts_on, (n + 1) * months))
+}
+
+/// The same day of the month some months later, or the last day of that
+/// month if it's too short.
+fn add_months(date: NaiveDate, months: i32) -> NaiveDate {
+    let total = date.year() * 12 + date.month0() as i32 + months;
+    let (year, month) = (total / 12, total % 12 + 1);
+    (0..4)
+        .filter_map(|x| NaiveDate::from_ymd_opt(year, month as u32, date.day() - x))
+        .next()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use super::{add_months, period};
+
+    #[test]
+    fn add_months_clamps_to_the_end_of_the_month() {
+        let date = |y, m, d| NaiveDate::from_ymd(y, m, d);
+        assert_eq!(add_months(date(2017, 1, 15), 1), date(2017, 2, 15));
+        assert_eq!(add_months(date(2017, 1, 31), 1), date(2017, 2, 28));
+        assert_eq!(add_months(date(2017, 11, 30), 3), date(2018, 2, 28));
+        assert_eq!(add_months(date(2016, 8, 31), 6), date(2017, 2, 28));
+    }
+
+    #[test]
+    fn periods_keep_the_day_they_started_on() {
+        let date = |y, m, d| NaiveDate::from_ymd(y, m, d);
+        assert_eq!(period(date(2017, 1, 31), 1, 1), (date(2017, 2, 28), date(2017, 3, 31)));
+        assert_eq!(period(date(2017, 1, 31), 1, 2), (date(2017, 3, 31), date(2017, 4, 30)));
+        assert_eq!(period(date(2017, 1, 31), 1, 3), (date(2017, 4, 30), date(2017, 5, 31)));
+    }
+}
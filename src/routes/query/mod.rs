@@ -0,0 +1,2180 @@
+use auth::{self, Cookie, SealedCookie};
+use conditions::{self, Cache, Memo};
+use config::Config;
+use database::{self, DbPointer};
+use i18n;
+use iron::headers::AcceptLanguage;
+use iron::prelude::*;
+use juniper::{self, ID};
+use persistent::Read;
+use diesel::{self, select};
+use diesel::expression::{exists, NonAggregate};
+use diesel::prelude::*;
+use diesel::pg::PgQueryBuilder;
+use diesel::query_builder::BuildQueryResult;
+use diesel::query_builder::QueryFragment;
+use diesel::types::{Double, Float};
+use diesel::types::Text;
+use diesel::expression::AsExpression;
+use diesel::query_builder::QueryBuilder;
+use diesel::pg::{Pg, PgConnection};
+use chrono::{DateTime, NaiveDate, NaiveTime, UTC};
+use diesel::pg::upsert::*;
+use email::{self, Category, Templates};
+use email::outbox::{self as email_outbox, Queued};
+use juniper::Value;
+use listener::Change;
+use payments::Payments;
+use schema::{users, locations, instructor_locations, role_grants};
+use routes::calendar;
+use schema::{impersonation_log, outbox, set_config};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use uuid::Uuid;
+use self::announcements::AnnouncementWrapper;
+use self::attendance::AttendanceStats;
+use self::connection::{UserConnection, LocationConnection, Window};
+use self::directory::{UserFilter, UserSort};
+use self::error::{Code, Error};
+use self::loader::Loaders;
+use self::memberships::{InvoiceWrapper, MembershipWrapper, OverdueMember, Plan, PlanKind};
+use self::node::Node;
+use self::preferences::NotificationPreference;
+use self::timetable::{ClassWrapper, Level, Session, Weekday};
+pub use self::subscription::Subscription;
+
+mod announcements;
+mod attendance;
+mod connection;
+mod directory;
+mod emails;
+pub mod error;
+mod loader;
+mod memberships;
+pub mod node;
+mod preferences;
+mod subscription;
+pub mod timetable;
+
+//LONG: MODULARISE.
+//LONG: More robust solution to case-insensitive usernames and emails.
+
+//LONG: Remove along with the deprecated offset/limit fields.
+macro_rules! sleiss {
+    ($req:expr, $offset:expr, $limit:expr, $db:expr) => {
+        match ($offset, $limit) {
+            (Some(offset), Some(limit)) =>
+                $req.offset(offset).limit(limit).get_results($db),
+            (Some(offset), None) =>
+                $req.offset(offset).get_results($db),
+            (None, Some(limit)) =>
+                $req.limit(limit).get_results($db),
+            (None, None) =>
+                $req.get_results($db)
+        }
+    }
+}
+
+pub struct Context {
+    config: Arc<Config>,
+    templates: Arc<Templates>,
+    payments: Arc<Payments>,
+    database: DbPointer,
+    memo: Memo,
+    loaders: Loaders,
+    user: Option<i64>,
+    impersonator: Option<i64>,
+    locale: Cell<Locale>, // What to translate messages into.
+    change: Option<Change> // What a subscription is being told about.
+}
+
+pub struct Query;
+pub struct Mutate;
+
+/// The language to answer in, which takes a query to work out for users
+/// who are logged in, so it's only done if anything needs translating.
+#[derive(Clone, Copy)]
+pub enum Locale {
+    Known(&'static str),
+    /// Not looked up yet, with what to fall back on if the user hasn't
+    /// chosen one.
+    Unknown(&'static str)
+}
+
+/// The setting that changed rows are put down to, as in the migration.
+const IMPERSONATION: &'static str = "ttkkdd.impersonation";
+
+/// Unsets the log entry that changes are put down to when dropped, before
+/// the connection goes back to the pool.
+struct Attribution<'a>(&'a PgConnection);
+
+impl<'a> Drop for Attribution<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = select(set_config(IMPERSONATION, "", false)).execute(self.0) {
+            error!("Failed to stop attributing changes to an impersonator: {}.", e);
+        }
+    }
+}
+
+impl juniper::Context for Context {}
+
+impl Context {
+    pub fn new(
+        config: Arc<Config>,
+        templates: Arc<Templates>,
+        payments: Arc<Payments>,
+        database: DbPointer,
+        session: Option<Cookie>,
+        locale: Locale,
+        change: Option<Change>
+    ) -> Context {
+        Context {
+            config: config,
+            templates: templates,
+            payments: payments,
+            database: database,
+            memo: Memo::default(),
+            loaders: Loaders::default(),
+            user: session.map(|x| x.id),
+            impersonator: session.and_then(|x| x.impersonator),
+            locale: Cell::new(locale),
+            change: change
+        }
+    }
+
+    /// Keep a record of what an admin does while viewing the site as
+    /// somebody else, giving the log entry's ID. Does nothing for everyone
+    /// else.
+    pub fn record(&self, query: &str) -> QueryResult<Option<i64>> {
+        #[derive(Insertable)]
+        #[table_name="impersonation_log"]
+        struct NewEntry<'a> {
+            admin_id: i64,
+            user_id: i64,
+            query: &'a str
+        }
+
+        match (self.impersonator, self.user) {
+            (Some(admin), Some(user)) => diesel::insert(&NewEntry {
+                admin_id: admin,
+                user_id: user,
+                query: query
+            })
+            .into(impersonation_log::table)
+            .returning(impersonation_log::id)
+            .get_result(&*self.database)
+            .map(Some),
+            _ => Ok(None)
+        }
+    }
+
+    /// Run a query with every row it changes put down to its log entry, if
+    /// it was logged.
+    pub fn attributed<T, F>(&self, entry: Option<i64>, run: F) -> QueryResult<T>
+    where F: FnOnce() -> T {
+        let entry = match entry {
+            Some(entry) => entry,
+            None => return Ok(run())
+        };
+
+        // Not just for a transaction, since a failed field would undo the
+        // rest, so it's unset again even if the query panics.
+        select(set_config(IMPERSONATION, entry.to_string(), false))
+            .execute(&*self.database)?;
+        let _attribution = Attribution(&*self.database);
+        Ok(run())
+    }
+
+    /// Whether the user has a permission that isn't about anyone else.
+    pub fn may(&self, perm: conditions::Conditions) -> bool {
+        perm.check(&cache(self, None)).unwrap_or(false)
+    }
+
+    pub fn database(&self) -> &DbPointer {
+        &self.database
+    }
+
+    pub fn locale(&self) -> &'static str {
+        match self.locale.get() {
+            Locale::Known(locale) => locale,
+            Locale::Unknown(fallback) => {
+                let locale = chosen(&*self.database, self.user).unwrap_or(fallback);
+                self.locale.set(Locale::Known(locale));
+                locale
+            }
+        }
+    }
+}
+
+impl Context {
+    /// The context for a request, or a response to give up with if there
+    /// isn't a database connection to spare.
+    pub fn from_request(req: &mut Request) -> Result<Context, Response> {
+        let config = req.extensions.get::<Read<Config>>().unwrap().clone();
+        let templates = req.extensions.get::<Read<Templates>>().unwrap().clone();
+        let payments = req.extensions.get::<Read<Payments>>().unwrap().clone();
+        let database = database::connection(req)?;
+        let session = session(req, &config);
+        let locale = Locale::Unknown(accepted(req));
+        Ok(Context::new(config, templates, payments, database, session, locale, None))
+    }
+}
+
+/// The language to answer in: the user's choice if they're logged in, or
+/// else whatever the client asks for that's supported.
+pub fn locale(req: &Request, db: &PgConnection, session: Option<Cookie>) -> &'static str {
+    chosen(db, session.map(|x| x.id)).unwrap_or_else(|| accepted(req))
+}
+
+/// The locale a user chose, if they're logged in.
+fn chosen(db: &PgConnection, user: Option<i64>) -> Option<&'static str> {
+    let chosen = user.and_then(|user| {
+        users::table.find(user)
+            .select(users::locale)
+            .first::<String>(db)
+            .ok()
+    });
+
+    chosen.as_ref().and_then(|x| i18n::supported(x))
+}
+
+/// Whatever supported locale the client asks for, or the default.
+fn accepted(req: &Request) -> &'static str {
+    req.headers.get::<AcceptLanguage>()
+        .and_then(i18n::negotiate)
+        .unwrap_or(i18n::DEFAULT)
+}
+
+/// The logged in user's token, if they sent a valid one.
+pub fn session(req: &Request, config: &Config) -> Option<Cookie> {
+    req.headers.get::<SealedCookie>()
+        .and_then(|sealed| sealed.unseal(config.secret).ok())
+        .and_then(|token| if token.valid() { Some(token) } else { None })
+}
+
+#[derive(Identifiable, Queryable, Associations, Clone)]
+#[has_many(instructor_locations, foreign_key="instructor_id")]
+#[table_name="users"]
+pub struct User {
+    id: i64,
+    first_name: String,
+    last_name: String,
+    username: String,
+    email: String,
+    password: Vec<u8>,
+    training_location: Option<i64>,
+    role: i64,
+    belt: i64,
+    registered: DateTime<UTC>,
+    uuid: Uuid,
+    locale: String
+}
+
+pub struct UserWrapper<'a> {
+    user: User,
+    batch: Rc<Vec<i64>>, // Everyone fetched along with this user.
+    cache: Cache<'a>
+}
+
+#[derive(Identifiable, Queryable, Associations, Clone)]
+#[has_many(users, foreign_key="training_location")]
+#[has_many(instructor_locations, foreign_key="location_id")]
+#[table_name="locations"]
+pub struct Location {
+    id: i64,
+    name: String,
+    address: String,
+    lat: f64,
+    lng: f64,
+    public: bool,
+    uuid: Uuid,
+    time_zone: String
+}
+
+pub struct LocationWrapper<'a> {
+    location: Location,
+    distance: Option<f64>, // From wherever the user searched, if anywhere.
+    batch: Rc<Vec<i64>>, // Every location fetched along with this one.
+    cache: Cache<'a>
+}
+
+impl<'a> LocationWrapper<'a> {
+    /// Check a permission, along with the right to see private locations
+    /// if this is one.
+    fn allowed(&self, perm: conditions::Conditions) -> Result<(), String> {
+        check(&self.cache, perm)?;
+        if !self.location.public {
+            check(&self.cache, conditions::read_private_location)?;
+        }
+        Ok(())
+    }
+
+    /// Check that the user may see who trains here, and remember whether
+    /// they're all the user's students.
+    fn students_cache(&self) -> Result<Cache<'a>, String> {
+        if !self.location.public {
+            check(&self.cache, conditions::read_private_location)?;
+        }
+
+        let mut cache = self.cache;
+        let student = if let Some(user) = cache.user {
+            select(exists(
+                instructor_locations::table.filter(
+                    instructor_locations::location_id.eq(self.location.id)
+                    .and(instructor_locations::instructor_id.eq(user))
+                    .and(conditions::active_assignment())
+                )
+            ))
+            .get_result(&**cache.database())
+            .map_err(stringify_error)?
+        } else {
+            false
+        };
+
+        cache.student = Some(student);
+        check(&cache, conditions::read_students)?;
+        Ok(cache)
+    }
+}
+
+#[derive(Queryable, Identifiable, Associations)]
+#[belongs_to(User, foreign_key="instructor_id")]
+#[belongs_to(Location, foreign_key="location_id")]
+#[table_name="instructor_locations"]
+pub struct InstructorLocation {
+    id: i64,
+    instructor_id: i64,
+    location_id: i64,
+    valid_from: DateTime<UTC>,
+    valid_until: Option<DateTime<UTC>>
+}
+
+#[derive(Queryable)]
+pub struct RoleGrant {
+    id: i64,
+    user_id: i64,
+    role: i64,
+    valid_from: DateTime<UTC>,
+    valid_until: DateTime<UTC>,
+    uuid: Uuid
+}
+
+pub struct Role(conditions::Role);
+
+#[derive(Clone, Copy)]
+pub enum Belt {
+    White,
+    Yellow,
+    Green,
+    Blue,
+    Red,
+    Black
+}
+
+impl Belt {
+    pub fn from_int(rank: i64) -> Option<Belt> {
+        match rank {
+            0 => Some(Belt::White),
+            1 => Some(Belt::Yellow),
+            2 => Some(Belt::Green),
+            3 => Some(Belt::Blue),
+            4 => Some(Belt::Red),
+            5 => Some(Belt::Black),
+            _ => None
+        }
+    }
+
+    pub fn rank(self) -> i64 {
+        self as i64
+    }
+}
+
+pub struct Timestamp(DateTime<UTC>);
+
+graphql_scalar!(Timestamp {
+    description: "An RFC 3339 date and time, such as '2017-07-02T10:30:00Z'."
+
+    resolve(&self) -> Value {
+        Value::string(&self.0.to_rfc3339())
+    }
+
+    from_input_value(v: &InputValue) -> Option<Timestamp> {
+        v.as_string_value()
+            .and_then(|s| s.parse().ok())
+            .map(Timestamp)
+    }
+});
+
+/// A day without a time, like a class's date.
+pub struct Day(NaiveDate);
+
+graphql_scalar!(Day as "Date" {
+    description: "An ISO 8601 date, such as '2017-07-02'."
+
+    resolve(&self) -> Value {
+        Value::string(&self.0.to_string())
+    }
+
+    from_input_value(v: &InputValue) -> Option<Day> {
+        v.as_string_value()
+            .and_then(|s| s.parse().ok())
+            .map(Day)
+    }
+});
+
+pub struct TimeOfDay(NaiveTime);
+
+graphql_scalar!(TimeOfDay as "Time" {
+    description: "A 24-hour time of day, such as '18:30'."
+
+    resolve(&self) -> Value {
+        Value::string(&self.0.format("%H:%M").to_string())
+    }
+
+    from_input_value(v: &InputValue) -> Option<TimeOfDay> {
+        v.as_string_value()
+            .and_then(|s| NaiveTime::parse_from_str(s, "%H:%M").ok())
+            .map(TimeOfDay)
+    }
+});
+
+graphql_enum!(Belt {
+    Belt::White => "WHITE",
+    Belt::Yellow => "YELLOW",
+    Belt::Green => "GREEN",
+    Belt::Blue => "BLUE",
+    Belt::Red => "RED",
+    Belt::Black => "BLACK"
+});
+
+graphql_object!(RoleGrant: Context as "RoleGrant" |&self| {
+    description: "A role given to a user for a limited time."
+
+    field id() -> ID
+    as "A unique ID for the grant." {
+        node::encode(node::ROLE_GRANT, self.uuid)
+    }
+
+    field role() -> Option<Role>
+    as "The role that was granted." {
+        conditions::Role::from_int(self.role).map(Role)
+    }
+
+    field validFrom() -> Timestamp
+    as "When the grant takes effect." {
+        Timestamp(self.valid_from)
+    }
+
+    field validUntil() -> Timestamp
+    as "When the grant expires." {
+        Timestamp(self.valid_until)
+    }
+});
+
+graphql_object!(Role: Context as "Role" |&self| {
+    description: "A role, such as 'admin', that defines users' privileges."
+
+    field id() -> i64
+    as "A unique numeric ID." {
+        self.0 as i64
+    }
+
+    field name() -> &str
+    as "A snake-cased name." {
+        self.0.to_string()
+    }
+});
+
+graphql_object!(<'a> LocationWrapper<'a>: Context as "Location" |&self| {
+    description: "A place where people train (forbidden fields will be nulled)."
+
+    field id() -> ID
+    as "A unique ID for the location." {
+        node::encode(node::LOCATION, self.location.uuid)
+    }
+
+    field name() -> &str
+    as "The name of the location." {
+        &self.location.name
+    }
+
+    field public() -> bool
+    as "Whether the location's details are shown to everyone." {
+        self.location.public
+    }
+
+    field timeZone() -> &str
+    as "The time zone its classes' times are in, like Australia/Sydney." {
+        &self.location.time_zone
+    }
+
+    field address(&executor) -> Option<&str>
+    as "A human-readable address." {
+        self.allowed(conditions::read_location_address)
+            .ok()
+            .and(Some(&*self.location.address))
+    }
+
+    field lat(&executor) -> Option<f64>
+    as "The latitude of the location." {
+        self.allowed(conditions::read_location_coordinates)
+            .ok()
+            .and(Some(self.location.lat))
+    }
+
+    field lng(&executor) -> Option<f64>
+    as "The longitude of the location." {
+        self.allowed(conditions::read_location_coordinates)
+            .ok()
+            .and(Some(self.location.lng))
+    }
+
+    field distance(&executor) -> Option<f64>
+    as "How far away the location is in kilometres, when searching near a point." {
+        self.allowed(conditions::read_location_coordinates)
+            .ok()
+            .and(self.distance)
+    }
+
+    field instructors(&executor) -> Result<Vec<UserWrapper>, String>
+    as "The people instructing at this location, by ID, up to as many as a \
+        list can have." {
+        self.allowed(conditions::read_instructors)?;
+        let first_cache = self.cache;
+        let ctx = executor.context();
+        let mut users = ctx.loaders.instructors.load(self.location.id, &self.batch, |ids| {
+            let rows = instructor_locations::table
+                .inner_join(users::table)
+                .filter(instructor_locations::location_id.eq_any(ids))
+                .filter(conditions::active_assignment())
+                .select((instructor_locations::location_id, users::all_columns))
+                .order(users::id)
+                .get_results::<(i64, User)>(&**first_cache.database())
+                .map_err(stringify_error)?;
+
+            let ids = rows.iter().map(|row| row.1.id).collect::<Vec<_>>();
+            first_cache.prefetch_students(&ids)?;
+            Ok(rows)
+        })?;
+
+        users.truncate(ctx.config.max_list_size as usize);
+        Ok(wrap_users(first_cache, users))
+    }
+
+    field instructorsConnection(
+        &executor,
+        first: Option<i64>,
+        after: Option<String>,
+        last: Option<i64>,
+        before: Option<String>
+    ) -> Result<UserConnection, String>
+    as "A page of the people instructing at this location, by ID." {
+        self.allowed(conditions::read_instructors)?;
+        let config = &executor.context().config;
+        let window = Window::new(config, first, after, last, before)?;
+        connection::instructors(self.cache, window, self.location.id)
+    }
+
+    field students(&executor) -> Result<Vec<UserWrapper>, String>
+    as "The people training at this location, by ID, up to as many as a \
+        list can have." {
+        let first_cache = self.students_cache()?;
+        let ctx = executor.context();
+        let mut users = ctx.loaders.students.load(self.location.id, &self.batch, |ids| {
+            users::table
+                .filter(users::training_location.eq_any(ids))
+                .order(users::id)
+                .get_results::<User>(&**first_cache.database())
+                .map(|users| users.into_iter()
+                    .filter_map(|user| user.training_location.map(|id| (id, user)))
+                    .collect())
+                .map_err(stringify_error)
+        })?;
+        users.truncate(ctx.config.max_list_size as usize);
+
+        // Everyone here trains at the same place, so they're either all
+        // students of the current user or none of them are.
+        let student = first_cache.student == Some(true);
+        first_cache.memo.remember_students(users.iter().map(|u| u.id), student);
+        Ok(wrap_users(first_cache, users))
+    }
+
+    field studentsConnection(
+        &executor,
+        first: Option<i64>,
+        after: Option<String>,
+        last: Option<i64>,
+        before: Option<String>
+    ) -> Result<UserConnection, String>
+    as "A page of the people training at this location, by ID." {
+        let first_cache = self.students_cache()?;
+        let config = &executor.context().config;
+        let window = Window::new(config, first, after, last, before)?;
+        connection::students(first_cache, window, self.location.id)
+    }
+
+    field classes(&executor) -> Result<Vec<ClassWrapper>, String>
+    as "The classes held here every week that haven't ended." {
+        self.allowed(conditions::read_timetable)?;
+        timetable::current(self.cache, self.location.id).map_err(String::from)
+    }
+
+    field calendarUrl(&executor) -> Result<Option<String>, String>
+    as "An iCalendar feed of the classes here, if the location is public." {
+        self.allowed(conditions::read_location_info)?;
+        let config = &executor.context().config;
+        Ok(if self.location.public {
+            Some(calendar::location_url(config, self.location.uuid))
+        } else {
+            None
+        })
+    }
+
+    interfaces: [Node<'a>]
+});
+
+graphql_object!(<'a> UserWrapper<'a>: Context as "User" |&self| {
+    description: "A user (forbidden fields will be nulled)."
+
+    field id() -> ID
+    as "A unique ID for the user." {
+        node::encode(node::USER, self.user.uuid)
+    }
+
+    field firstName(&executor) -> Option<&str>
+    as "The user's first name." {
+        check(&self.cache, conditions::read_name)
+            .ok()
+            .and(Some(&*self.user.first_name))
+    }
+
+    field lastName(&executor) -> Option<&str>
+    as "The user's last name." {
+        check(&self.cache, conditions::read_name)
+            .ok()
+            .and(Some(&*self.user.last_name))
+    }
+
+    field username(&executor) -> Option<&str>
+    as "The user's username." {
+        check(&self.cache, conditions::read_username)
+            .ok()
+            .and(Some(&*self.user.username))
+    }
+
+    field email(&executor) -> Option<&str>
+    as "The user's email address." {
+        check(&self.cache, conditions::read_email_address)
+            .ok()
+            .and(Some(&*self.user.email))
+    }
+
+    field role(&executor) -> Option<Role>
+    as "The user's role." {
+        check(&self.cache, conditions::read_role)
+            .ok()
+            .and(conditions::Role::from_int(self.user.role).map(Role))
+    }
+
+    field belt(&executor) -> Option<Belt>
+    as "The user's belt." {
+        check(&self.cache, conditions::read_belt)
+            .ok()
+            .and(Belt::from_int(self.user.belt))
+    }
+
+    field registered(&executor) -> Option<Timestamp>
+    as "When the user signed up." {
+        check(&self.cache, conditions::read_registration_date)
+            .ok()
+            .and(Some(Timestamp(self.user.registered)))
+    }
+
+    field locale(&executor) -> Option<&str>
+    as "The language the user gets emails and messages in, like `en`." {
+        check(&self.cache, conditions::read_locale)
+            .ok()
+            .and(Some(&*self.user.locale))
+    }
+
+    field calendarUrl(&executor) -> Result<String, String>
+    as "A private iCalendar feed of the classes where the user trains and \
+        instructs. Anyone with the link can see it." {
+        check(&self.cache, conditions::read_calendar_url)?;
+        let config = &executor.context().config;
+        auth::calendar_token(&**self.cache.database(), self.user.id)
+            .map(|token| calendar::user_url(config, self.user.uuid, &token))
+            .map_err(stringify_error)
+    }
+
+    field attendance(&executor) -> Result<AttendanceStats, String>
+    as "How often the user comes to class." {
+        check(&self.cache, conditions::read_attendance)?;
+        attendance::stats(&**self.cache.database(), self.user.id)
+            .map_err(stringify_error)
+    }
+
+    field memberships(&executor) -> Result<Vec<MembershipWrapper>, String>
+    as "The memberships the user pays for or trains under, newest first." {
+        check(&self.cache, conditions::read_memberships)?;
+        memberships::of_user(self.cache, self.user.id).map_err(stringify_error)
+    }
+
+    field notificationPreferences(&executor) -> Result<Vec<NotificationPreference>, String>
+    as "Which kinds of optional emails the user gets." {
+        check(&self.cache, conditions::read_notification_preferences)?;
+        email::preferences::all(&**self.cache.database(), self.user.id)
+            .map(|all| all.into_iter().map(|(category, enabled)| NotificationPreference {
+                category: category,
+                enabled: enabled
+            }).collect())
+            .map_err(stringify_error)
+    }
+
+    field roleGrants(&executor) -> Result<Vec<RoleGrant>, String>
+    as "The user's current and upcoming temporary roles." {
+        check(&self.cache, conditions::read_role)?;
+        role_grants::table
+            .filter(role_grants::user_id.eq(self.user.id))
+            .filter(role_grants::valid_until.gt(UTC::now()))
+            .order(role_grants::valid_from)
+            .get_results(&**self.cache.database())
+            .map_err(stringify_error)
+    }
+
+    field training_location(&executor) -> Result<Option<LocationWrapper>, String>
+    as "The place where the user trains." {
+        check(&self.cache, conditions::read_students)?;
+        if self.user.training_location.is_none() {
+            return Ok(None);
+        }
+
+        let db = &**self.cache.database();
+        let loader = &executor.context().loaders.training_location;
+        let locations = loader.load(self.user.id, &self.batch, |ids| {
+            let trainees = users::table
+                .filter(users::id.eq_any(ids))
+                .select((users::id, users::training_location))
+                .get_results::<(i64, Option<i64>)>(db)
+                .map_err(stringify_error)?;
+
+            let location_ids = trainees.iter()
+                .filter_map(|row| row.1)
+                .collect::<Vec<_>>();
+            let locations = locations::table
+                .filter(locations::id.eq_any(location_ids))
+                .get_results::<Location>(db)
+                .map_err(stringify_error)?
+                .into_iter()
+                .map(|location| (location.id, location))
+                .collect::<HashMap<_, _>>();
+
+            Ok(trainees.into_iter()
+                .filter_map(|(user, location)| location
+                    .and_then(|id| locations.get(&id))
+                    .map(|location| (user, location.clone())))
+                .collect())
+        })?;
+
+        Ok(locations.into_iter().next().map(|location| wrap_location(self.cache, location)))
+    }
+
+    field instructing_locations(&executor) -> Result<Vec<LocationWrapper>, String>
+    as "The places where the user is an instructor." {
+        check(&self.cache, conditions::read_instructors)?;
+        let db = &**self.cache.database();
+        let loader = &executor.context().loaders.instructing_locations;
+        let locations = loader.load(self.user.id, &self.batch, |ids| {
+            instructor_locations::table
+                .inner_join(locations::table)
+                .filter(instructor_locations::instructor_id.eq_any(ids))
+                .filter(conditions::active_assignment())
+                .select((instructor_locations::instructor_id, locations::all_columns))
+                .get_results(db)
+                .map_err(stringify_error)
+        })?;
+
+        Ok(wrap_locations(self.cache, locations))
+    }
+
+    interfaces: [Node<'a>]
+});
+
+graphql_object!(Query: Context as "Query" |&self| {
+    field roles(&executor) -> Vec<Role>
+    as "A list of roles." {
+        //LONG: Add a permission `read_roles` or something.
+        //LONG: Surely there is an easier way to do this.
+        conditions::ROLES.iter().cloned().map(Role).collect()
+    }
+
+    field announcements(
+        &executor,
+        first: Option<i64>
+    ) -> Result<Vec<AnnouncementWrapper>, String>
+    as "The newest announcements for oneself." {
+        let ctx = executor.context();
+        let limit = list_size(ctx, "first", first)?;
+        announcements::feed(cache(ctx, None), limit).map_err(String::from)
+    }
+
+    field timetable(
+        &executor,
+        location: ID,
+        from: Timestamp,
+        to: Timestamp
+    ) -> Result<Vec<Session>, String>
+    as "Every session of a location's classes starting between two times, \
+        earliest first. Cancelled ones are included." {
+        let cache = cache(executor.context(), None);
+        let location = node::location(&**cache.database(), &location, "location")?;
+        let found = locations::table.find(location)
+            .first(&**cache.database())
+            .map(|location| wrap_location(cache, location))
+            .map_err(stringify_error)?;
+
+        found.allowed(conditions::read_timetable)?;
+        timetable::timetable(found.cache, location, from.0, to.0).map_err(String::from)
+    }
+
+    field membershipPlans(
+        &executor,
+        include_inactive: Option<bool>
+    ) -> Result<Vec<Plan>, String>
+    as "The plans people can join, by name. Admins can include ones that \
+        aren't taking new members." {
+        let cache = cache(executor.context(), None);
+        check(&cache, conditions::read_membership_plans)?;
+        let include_inactive = include_inactive.unwrap_or(false);
+        if include_inactive {
+            check(&cache, conditions::edit_membership_plans)?;
+        }
+
+        memberships::plans(&**cache.database(), include_inactive).map_err(stringify_error)
+    }
+
+    field overdueMembers(
+        &executor,
+        location: ID
+    ) -> Result<Vec<OverdueMember>, String>
+    as "Everyone training at a location whose membership has invoices past \
+        due, by last name." {
+        let ctx = executor.context();
+        let location = node::location(&**ctx.database(), &location, "location")?;
+        let cache = cache(ctx, None).at(Some(location));
+        check(&cache, conditions::read_overdue_members)?;
+        memberships::overdue(cache, location, UTC::today().naive_utc()).map_err(String::from)
+    }
+
+    field failedEmails(
+        &executor,
+        first: Option<i64>
+    ) -> Result<Vec<Queued>, String>
+    as "Emails that were given up on, newest first." {
+        let ctx = executor.context();
+        let cache = cache(ctx, None);
+        check(&cache, conditions::read_email_outbox)?;
+        outbox::table
+            .filter(outbox::status.eq(email_outbox::FAILED))
+            .order(outbox::created_at.desc())
+            .limit(list_size(ctx, "first", first)?)
+            .get_results(&**cache.database())
+            .map_err(stringify_error)
+    }
+
+    field node(&executor, id: ID) -> Result<Option<Node>, String>
+    as "The user or location with the given ID, if there is one. Other IDs, \
+        like a class's, are only taken by the fields that deal with them." {
+        let ctx = executor.context();
+        let (kind, uuid) = match node::decode(&id) {
+            Some(decoded) => decoded,
+            None => return Err(Error::invalid("id", "invalid ID").into())
+        };
+
+        match kind.as_str() {
+            node::USER => users::table
+                .filter(users::uuid.eq(uuid))
+                .first::<User>(&**ctx.database())
+                .optional()
+                .map(|user| user.map(|user| Node::User(UserWrapper {
+                    batch: Rc::new(vec![user.id]),
+                    cache: cache(ctx, Some(user.id)),
+                    user: user
+                })))
+                .map_err(stringify_error),
+            node::LOCATION => {
+                let cache = cache(ctx, None);
+                check(&cache, conditions::read_location_info)?;
+                locations::table
+                    .filter(locations::uuid.eq(uuid))
+                    .first(&**cache.database())
+                    .optional()
+                    .map(|location| location.map(|location| {
+                        Node::Location(wrap_location(cache, location))
+                    }))
+                    .map_err(stringify_error)
+            },
+            node::CLASS | node::PLAN | node::MEMBERSHIP | node::INVOICE |
+            node::PAYMENT | node::ANNOUNCEMENT | node::EMAIL | node::ROLE_GRANT => {
+                let message = "only users and locations can be fetched by ID";
+                Err(Error::invalid("id", message).into())
+            },
+            _ => Err(Error::invalid("id", "invalid ID").into())
+        }
+    }
+
+    //LONG: Consider moving the read_location_info checks into the actual reading functions.
+    field location(&executor, id: ID) -> Result<LocationWrapper, String>
+    as "The location with the given ID." {
+        let cache = cache(executor.context(), None);
+        check(&cache, conditions::read_location_info)?;
+        let id = node::location(&**cache.database(), &id, "id")?;
+        locations::table.find(id)
+            .first(&**cache.database())
+            .map(|location| wrap_location(cache, location))
+            .map_err(stringify_error)
+    }
+
+    field deprecated "Use locationsConnection, which won't skip or repeat \
+                      locations when they change between pages."
+    locations(
+        &executor,
+        offset: Option<i64>,
+        limit: Option<i64>
+    ) -> Result<Vec<LocationWrapper>, String>
+    as "All the locations, alphabetized by name." {
+        let ctx = executor.context();
+        let cache = cache(ctx, None);
+        check(&cache, conditions::read_location_info)?;
+        let limit = Some(list_size(ctx, "limit", limit)?);
+        let db = &**cache.database();
+        sleiss!(locations::table.order(locations::name), offset, limit, db)
+            .map(|locations| wrap_locations(cache, locations))
+            .map_err(stringify_error)
+    }
+
+    field locationsConnection(
+        &executor,
+        first: Option<i64>,
+        after: Option<String>,
+        last: Option<i64>,
+        before: Option<String>,
+        query: Option<String>
+    ) -> Result<LocationConnection, String>
+    as "A page of the locations, alphabetized by name, optionally only those \
+        whose name or address contains the query." {
+        let cache = cache(executor.context(), None);
+        check(&cache, conditions::read_location_info)?;
+        let config = &executor.context().config;
+        let window = Window::new(config, first, after, last, before)?;
+        connection::locations(cache, window, query)
+    }
+
+    field nearbyLocations(
+        &executor,
+        lat: f64,
+        lng: f64,
+        radius: Option<f64> as "The furthest to look, in kilometres.",
+        first: Option<i64> as "The most locations to return."
+    ) -> Result<Vec<LocationWrapper>, String>
+    as "The closest public locations to a point, nearest first." {
+        let cache = cache(executor.context(), None);
+        check(&cache, conditions::read_location_info)?;
+        check(&cache, conditions::read_location_coordinates)?;
+
+        // Same bounds as the `latlng_bounds` constraint.
+        if !(-90.0 <= lat && lat <= 90.0 && -180.0 <= lng && lng < 180.0) {
+            return Err(Error::invalid("lat", "invalid coordinates").into());
+        }
+
+        let max = connection::max_page(&executor.context().config);
+        let limit = first.unwrap_or(max);
+        if limit < 0 || limit > max {
+            return Err(Error::invalid("first", format!(
+                "first must be between 0 and {}", max)).into());
+        }
+
+        let mut req = locations::table
+            .select((locations::all_columns, distance(lat, lng)))
+            .filter(locations::public)
+            .into_boxed();
+
+        if let Some(radius) = radius {
+            req = req.filter(distance(lat, lng).le(radius));
+        }
+
+        req.order((distance(lat, lng), locations::id))
+            .limit(limit)
+            .get_results::<(Location, f64)>(&**cache.database())
+            .map(|rows| {
+                let (locations, distances): (Vec<_>, Vec<_>) = rows.into_iter().unzip();
+                wrap_locations(cache, locations).into_iter()
+                    .zip(distances)
+                    .map(|(location, km)| LocationWrapper {
+                        distance: Some(km),
+                        ..location
+                    })
+                    .collect()
+            })
+            .map_err(stringify_error)
+    }
+
+    field user(&executor, id: Option<ID>) -> Result<UserWrapper, String>
+    as "The user with the given ID, or oneself if an ID is not given." {
+        let ctx = executor.context();
+        let id =
+            if let Some(id) = id { node::user(&**ctx.database(), &id, "id")? }
+            else if let Some(user) = ctx.user { user }
+            else { return Err(Error::unauthorized().into()) };
+
+        let cache = cache(ctx, Some(id));
+        users::table.find(id)
+            .first(&**cache.database())
+            .map(|user| UserWrapper {
+                batch: Rc::new(vec![user.id]),
+                user: user,
+                cache: cache
+            }).map_err(stringify_error)
+    }
+
+    field deprecated "Use usersConnection, which won't skip or repeat \
+                      users when they change between pages."
+    users(
+        &executor,
+        offset: Option<i64>,
+        limit: Option<i64>,
+        query: Option<String>
+    ) -> Result<Vec<UserWrapper>, String>
+    as "All the users." {
+        let ctx = executor.context();
+        let first_cache = cache(ctx, None);
+        let limit = Some(list_size(ctx, "limit", limit)?);
+        let res = if let Some(query) = query {
+            check(&first_cache, conditions::search_users)?;
+            sleiss!(
+                users::table.order(
+                    word_similarity(query, users::username
+                        .concat(" ")
+                        .concat(users::first_name)
+                        .concat(" ")
+                        .concat(users::last_name)).desc()),
+                offset, limit, &**first_cache.database()
+            )
+        } else {
+            let db = &**first_cache.database();
+            sleiss!(users::table.order(users::id), offset, limit, db)
+        };
+
+        let users: Vec<User> = res.map_err(stringify_error)?;
+        let ids = users.iter().map(|user| user.id).collect::<Vec<_>>();
+        first_cache.prefetch_students(&ids)?;
+        Ok(wrap_users(first_cache, users))
+    }
+
+    field usersConnection(
+        &executor,
+        first: Option<i64>,
+        after: Option<String>,
+        last: Option<i64>,
+        before: Option<String>,
+        query: Option<String>,
+        filter: Option<UserFilter>,
+        sort: Option<Vec<UserSort>> as "Columns to sort by, most important first."
+    ) -> Result<UserConnection, String>
+    as "A page of the users matching the filter. They're sorted by the given \
+        columns and then by ID, or by relevance if there's a query and no sort." {
+        let first_cache = cache(executor.context(), None);
+        if query.is_some() {
+            check(&first_cache, conditions::search_users)?;
+        }
+        if let Some(ref filter) = filter {
+            filter.check(&first_cache)?;
+        }
+        let sort = sort.unwrap_or_default();
+        for term in &sort {
+            term.check(&first_cache, query.is_some())?;
+        }
+        let config = &executor.context().config;
+        let window = Window::new(config, first, after, last, before)?;
+        connection::users(first_cache, window, query, filter, sort)
+    }
+});
+
+graphql_object!(Mutate: Context as "Mutate" |&self| {
+    field addUser(
+        &executor,
+        first_name: String,
+        last_name: String,
+        username: String,
+        password: String,
+        email: String
+    ) -> Result<UserWrapper, String>
+    as "Add a user." {
+        #[derive(Insertable)]
+        #[table_name="users"]
+        struct NewUser<'a> {
+            first_name: String,
+            last_name: String,
+            username: String,
+            email: String,
+            password: &'a [u8]
+        }
+
+        let cache = cache(executor.context(), None);
+        check(&cache, conditions::create_user)?;
+
+        let hash = auth::hash(password.as_bytes());
+        let new_user = NewUser {
+            first_name: first_name,
+            last_name: last_name,
+            username: username,
+            password: hash.as_ref(),
+            email: email
+        };
+
+        diesel::insert(&new_user)
+            .into(users::table)
+            .get_result(&**cache.database())
+            .map(|user| UserWrapper {
+                batch: Rc::new(vec![user.id]),
+                user: user,
+                cache: cache
+            })
+            .map_err(stringify_error)
+    }
+
+    field removeUser(
+        &executor,
+        id: ID
+    ) -> Result<UserWrapper, String>
+    as "Remove the user with the given ID." {
+        let ctx = executor.context();
+        let id = node::user(&**ctx.database(), &id, "id")?;
+        let cache = cache(ctx, Some(id));
+        check(&cache, conditions::delete_user)?;
+        let user = diesel::delete(users::table.find(id))
+            .get_result::<User>(&**cache.database())
+            .map_err(stringify_error)?;
+
+        ctx.loaders.forget_users(&[id]);
+        ctx.memo.forget_places();
+        Ok(UserWrapper {
+            batch: Rc::new(vec![user.id]),
+            user: user,
+            cache: cache
+        })
+    }
+
+    field editUser(
+        &executor,
+        id: ID,
+        first_name: Option<String>,
+        last_name: Option<String>,
+        username: Option<String>,
+        password: Option<String>,
+        email: Option<String>,
+        role: Option<i64>,
+        belt: Option<Belt>,
+        locale: Option<String>
+    ) -> Result<UserWrapper, String>
+    as "Update the attributes of the user with the given ID." {
+        #[derive(AsChangeset)]
+        #[table_name="users"]
+        struct UserChanges<'a> {
+            first_name: Option<String>,
+            last_name: Option<String>,
+            username: Option<String>,
+            password: Option<&'a [u8]>,
+            email: Option<String>,
+            role: Option<i64>,
+            belt: Option<i64>,
+            locale: Option<String>
+        }
+
+        let ctx = executor.context();
+        let id = node::user(&**ctx.database(), &id, "id")?;
+        let cache = cache(ctx, Some(id));
+        if first_name.is_some() || last_name.is_some() {
+            check(&cache, conditions::edit_name)?;
+        }
+        if username.is_some() {
+            check(&cache, conditions::edit_username)?;
+        }
+        if email.is_some() {
+            check(&cache, conditions::edit_email_address)?;
+        }
+        if password.is_some() {
+            check(&cache, conditions::edit_password)?;
+        }
+        if role.is_some() {
+            check(&cache, conditions::edit_role)?;
+        }
+        if belt.is_some() {
+            check(&cache, conditions::edit_belt)?;
+        }
+        if locale.is_some() {
+            check(&cache, conditions::edit_locale)?;
+        }
+
+        let hash = password.map(|x| auth::hash(x.as_bytes()));
+        let changes = UserChanges {
+            first_name: first_name,
+            last_name: last_name,
+            username: username,
+            password: hash.as_ref().map(|x| x.as_ref()),
+            email: email,
+            role: role,
+            belt: belt.map(Belt::rank),
+            locale: locale
+        };
+
+        let user = diesel::update(users::table.find(id))
+            .set(&changes)
+            .get_result::<User>(&**cache.database())
+            .map_err(stringify_error)?;
+
+        ctx.loaders.forget_users(&[id]);
+        ctx.memo.forget_places();
+        if changes.role.is_some() {
+            ctx.memo.forget_role();
+        }
+        Ok(UserWrapper {
+            batch: Rc::new(vec![user.id]),
+            user: user,
+            cache: cache
+        })
+    }
+
+    field editTrainingLocation(
+        &executor,
+        student: ID,
+        location: Option<ID>
+    ) -> Result<UserWrapper, String>
+    as "Set the user's training location. Pass null to unset." {
+        #[derive(AsChangeset)]
+        #[table_name="users"]
+        struct UserChanges {
+            training_location: Option<Option<i64>>
+        }
+
+        let ctx = executor.context();
+        let student = node::user(&**ctx.database(), &student, "student")?;
+        let cache = cache(ctx, Some(student));
+        check(&cache, conditions::edit_students)?;
+
+        let location = match location {
+            Some(location) => Some(node::location(&**ctx.database(), &location, "location")?),
+            None => None
+        };
+
+        let user = diesel::update(users::table.find(student))
+            .set(&UserChanges { training_location: Some(location) })
+            .get_result::<User>(&**cache.database())
+            .map_err(stringify_error)?;
+
+        ctx.loaders.forget_users(&[student]);
+        ctx.memo.forget_places();
+        Ok(UserWrapper {
+            batch: Rc::new(vec![user.id]),
+            user: user,
+            cache: cache
+        })
+    }
+
+    field addLocation(
+        &executor,
+        name: String,
+        address: String,
+        lat: f64,
+        lng: f64,
+        public: Option<bool>,
+        time_zone: Option<String>
+    ) -> Result<LocationWrapper, String>
+    as "Add a location. Locations are public and in UTC unless stated \
+        otherwise." {
+        #[derive(Insertable)]
+        #[table_name="locations"]
+        struct NewLocation {
+            name: String,
+            address: String,
+            lat: f64,
+            lng: f64,
+            public: Option<bool>,
+            time_zone: Option<String>
+        }
+
+        let cache = cache(executor.context(), None);
+        check(&cache, conditions::create_location)?;
+        if let Some(ref zone) = time_zone {
+            timetable::check_time_zone(&**cache.database(), zone)?;
+        }
+
+        let new_location = NewLocation {
+            name: name,
+            address: address,
+            lat: lat,
+            lng: lng,
+            public: public,
+            time_zone: time_zone
+        };
+
+        diesel::insert(&new_location)
+            .into(locations::table)
+            .get_result(&**cache.database())
+            .map(|location| wrap_location(cache, location))
+            .map_err(stringify_error)
+    }
+
+    field importLocationWithRoster(
+        &executor,
+        name: String,
+        address: String,
+        lat: f64,
+        lng: f64,
+        public: Option<bool>,
+        time_zone: Option<String>,
+        instructors: Option<Vec<ID>>,
+        students: Option<Vec<ID>>
+    ) -> Result<LocationWrapper, String>
+    as "Add a location along with its instructors, and move its students \
+        there. Either all of it happens or none of it does." {
+        #[derive(Insertable)]
+        #[table_name="locations"]
+        struct NewLocation {
+            name: String,
+            address: String,
+            lat: f64,
+            lng: f64,
+            public: Option<bool>,
+            time_zone: Option<String>
+        }
+
+        #[derive(Insertable)]
+        #[table_name="instructor_locations"]
+        struct NewAssignment {
+            instructor_id: i64,
+            location_id: i64
+        }
+
+        let ctx = executor.context();
+        let cache = cache(ctx, None);
+        check(&cache, conditions::create_location)?;
+
+        let db = &**cache.database();
+        if let Some(ref zone) = time_zone {
+            timetable::check_time_zone(db, zone)?;
+        }
+
+        let instructors = instructors.unwrap_or_default();
+        let mut instructors = node::users(db, &instructors, "instructors")?;
+        instructors.sort();
+        instructors.dedup();
+        if !instructors.is_empty() {
+            check(&cache, conditions::edit_instructors)?;
+        }
+
+        let students = students.unwrap_or_default();
+        let mut students = node::users(db, &students, "students")?;
+        students.sort();
+        students.dedup();
+        cache.prefetch_students(&students)?;
+        for &student in &students {
+            check(&cache.retarget(Some(student)), conditions::edit_students)?;
+        }
+
+        let new_location = NewLocation {
+            name: name,
+            address: address,
+            lat: lat,
+            lng: lng,
+            public: public,
+            time_zone: time_zone
+        };
+
+        let location = db.transaction::<_, Error, _>(|| {
+            let location: Location = diesel::insert(&new_location)
+                .into(locations::table)
+                .get_result(db)?;
+
+            let assignments = instructors.iter()
+                .map(|&instructor| NewAssignment {
+                    instructor_id: instructor,
+                    location_id: location.id
+                })
+                .collect::<Vec<_>>();
+
+            if !assignments.is_empty() {
+                diesel::insert(&assignments)
+                    .into(instructor_locations::table)
+                    .execute(db)
+                    .map_err(|e| Error::from(e).on("instructors"))?;
+            }
+
+            let moved = diesel::update(users::table.filter(users::id.eq_any(students.clone())))
+                .set(users::training_location.eq(Some(location.id)))
+                .execute(db)?;
+
+            if moved != students.len() {
+                return Err(Error::new(Code::NotFound, "no such student").on("students"));
+            }
+
+            Ok(location)
+        })?;
+
+        ctx.loaders.forget_users(&students);
+        ctx.loaders.forget_users(&instructors);
+        ctx.memo.forget_places();
+        Ok(wrap_location(cache, location))
+    }
+
+    field removeLocation(
+        &executor,
+        id: ID
+    ) -> Result<LocationWrapper, String>
+    as "Remove the location with the given ID." {
+        let ctx = executor.context();
+        let cache = cache(ctx, None);
+        check(&cache, conditions::delete_location)?;
+        let id = node::location(&**cache.database(), &id, "id")?;
+        let location = diesel::delete(locations::table.find(id))
+            .get_result(&**cache.database())
+            .map_err(stringify_error)?;
+
+        ctx.loaders.forget_locations(&[id]);
+        ctx.memo.forget_places();
+        Ok(wrap_location(cache, location))
+    }
+
+    field editLocation(
+        &executor,
+        id: ID,
+        name: Option<String>,
+        address: Option<String>,
+        lat: Option<f64>,
+        lng: Option<f64>,
+        public: Option<bool>,
+        time_zone: Option<String>
+    ) -> Result<LocationWrapper, String>
+    as "Edit the location with the given ID. Its classes keep their times \
+        of day if its time zone changes." {
+        #[derive(AsChangeset)]
+        #[table_name="locations"]
+        struct LocationChanges {
+            name: Option<String>,
+            address: Option<String>,
+            lat: Option<f64>,
+            lng: Option<f64>,
+            public: Option<bool>,
+            time_zone: Option<String>
+        }
+        let ctx = executor.context();
+        let cache = cache(ctx, None);
+        check(&cache, conditions::edit_location_info)?;
+        let id = node::location(&**cache.database(), &id, "id")?;
+        if let Some(ref zone) = time_zone {
+            timetable::check_time_zone(&**cache.database(), zone)?;
+        }
+
+        let changes = LocationChanges {
+            name: name,
+            address: address,
+            lat: lat,
+            lng: lng,
+            public: public,
+            time_zone: time_zone
+        };
+        let location = diesel::update(locations::table.find(id))
+            .set(&changes)
+            .get_result(&**cache.database())
+            .map_err(stringify_error)?;
+
+        ctx.loaders.forget_locations(&[id]);
+        ctx.memo.forget_places();
+        Ok(wrap_location(cache, location))
+    }
+
+    field assignInstructor(
+        &executor,
+        user: ID,
+        location: ID,
+        valid_from: Option<Timestamp>,
+        valid_until: Option<Timestamp>
+    ) -> Result<(), String>
+    as "Assign a user to instruct at a particular location, optionally only \
+        for a while. Reassigning someone replaces their old window." {
+        #[derive(Insertable)]
+        #[table_name="instructor_locations"]
+        struct NewAssignment {
+            instructor_id: i64,
+            location_id: i64,
+            valid_from: Option<DateTime<UTC>>,
+            valid_until: Option<DateTime<UTC>>
+        }
+
+        let ctx = executor.context();
+        let cache = cache(ctx, None);
+        check(&cache, conditions::edit_instructors)?;
+        let user = node::user(&**cache.database(), &user, "user")?;
+        let location = node::location(&**cache.database(), &location, "location")?;
+
+        let assignment = NewAssignment {
+            instructor_id: user,
+            location_id: location,
+            valid_from: valid_from.map(|x| x.0),
+            valid_until: valid_until.map(|x| x.0)
+        };
+
+        diesel::insert(&assignment.on_conflict(
+            on_constraint("duplicate_assignments"),
+            do_update().set((
+                instructor_locations::valid_from
+                    .eq(excluded(instructor_locations::valid_from)),
+                instructor_locations::valid_until
+                    .eq(excluded(instructor_locations::valid_until))
+            ))
+        ))
+        .into(instructor_locations::table)
+        .execute(&**cache.database())
+        .map_err(stringify_error)?;
+
+        ctx.loaders.forget_users(&[user]);
+        ctx.memo.forget_places();
+        Ok(())
+    }
+
+    field unassignInstructor(
+        &executor,
+        user: ID,
+        location: ID
+    ) -> Result<(), String>
+    as "Remove an instructor from a particular location." {
+        let ctx = executor.context();
+        let cache = cache(ctx, None);
+        check(&cache, conditions::edit_instructors)?;
+        let user = node::user(&**cache.database(), &user, "user")?;
+        let location = node::location(&**cache.database(), &location, "location")?;
+        diesel::delete(instructor_locations::table.filter(
+            instructor_locations::instructor_id.eq(user)
+            .and(instructor_locations::location_id.eq(location))
+        ))
+        .execute(&**cache.database())
+        .map_err(stringify_error)?;
+
+        ctx.loaders.forget_users(&[user]);
+        ctx.memo.forget_places();
+        Ok(())
+    }
+
+    field grantRole(
+        &executor,
+        user: ID,
+        role: i64,
+        valid_from: Option<Timestamp>,
+        valid_until: Timestamp
+    ) -> Result<RoleGrant, String>
+    as "Give a user a role until the given time, after which it lapses." {
+        #[derive(Insertable)]
+        #[table_name="role_grants"]
+        struct NewGrant {
+            user_id: i64,
+            role: i64,
+            valid_from: Option<DateTime<UTC>>,
+            valid_until: DateTime<UTC>
+        }
+
+        let ctx = executor.context();
+        let user = node::user(&**ctx.database(), &user, "user")?;
+        let cache = cache(ctx, Some(user));
+        check(&cache, conditions::edit_role)?;
+
+        if conditions::Role::from_int(role).is_none() {
+            return Err(Error::invalid("role", "invalid role").into());
+        }
+
+        let grant = diesel::insert(&NewGrant {
+            user_id: user,
+            role: role,
+            valid_from: valid_from.map(|x| x.0),
+            valid_until: valid_until.0
+        })
+        .into(role_grants::table)
+        .get_result(&**cache.database())
+        .map_err(stringify_error)?;
+
+        ctx.memo.forget_role();
+        Ok(grant)
+    }
+
+    field impersonate(
+        &executor,
+        user: ID
+    ) -> Result<String, String>
+    as "Get a short-lived token for viewing the site as the given user. \
+        Everything done with it is recorded against the admin." {
+        let ctx = executor.context();
+        let user = node::user(&**ctx.database(), &user, "user")?;
+        let cache = cache(ctx, Some(user));
+        check(&cache, conditions::impersonate)?;
+
+        let admin = match (ctx.user, ctx.impersonator) {
+            (Some(admin), None) => admin,
+            _ => return Err(Error::unauthorized().into()) // No nesting.
+        };
+
+        let cookie = Cookie::impersonating(
+            admin,
+            user,
+            ctx.config.impersonation_length
+        );
+
+        Ok(cookie.seal(ctx.config.secret).encode())
+    }
+
+    field revokeRoleGrant(
+        &executor,
+        id: ID
+    ) -> Result<RoleGrant, String>
+    as "End a temporary role early." {
+        let ctx = executor.context();
+        let cache = cache(ctx, None);
+        check(&cache, conditions::edit_role)?;
+        let id = node::role_grant(&**cache.database(), &id, "id")?;
+        let grant = diesel::delete(role_grants::table.find(id))
+            .get_result(&**cache.database())
+            .map_err(stringify_error)?;
+
+        ctx.memo.forget_role();
+        Ok(grant)
+    }
+
+    field setNotificationPreference(
+        &executor,
+        user: ID,
+        category: Category,
+        enabled: bool
+    ) -> Result<NotificationPreference, String>
+    as "Choose whether a user gets a kind of optional email." {
+        let ctx = executor.context();
+        let user = node::user(&**ctx.database(), &user, "user")?;
+        let cache = cache(ctx, Some(user));
+        check(&cache, conditions::edit_notification_preferences)?;
+        email::preferences::set(&**cache.database(), user, category, enabled)
+            .map(|()| NotificationPreference { category: category, enabled: enabled })
+            .map_err(stringify_error)
+    }
+
+    field resetCalendarUrl(&executor, user: ID) -> Result<String, String>
+    as "Give a user's calendar feed a new link, so the old one stops \
+        working, and return it." {
+        let ctx = executor.context();
+        let user = node::user(&**ctx.database(), &user, "user")?;
+        let cache = cache(ctx, Some(user));
+        check(&cache, conditions::edit_calendar_url)?;
+        let uuid = users::table.find(user)
+            .select(users::uuid)
+            .first::<Uuid>(&**cache.database())
+            .map_err(stringify_error)?;
+
+        auth::reset_calendar_token(&**cache.database(), user)
+            .map(|token| calendar::user_url(&ctx.config, uuid, &token))
+            .map_err(stringify_error)
+    }
+
+    field sendAnnouncement(
+        &executor,
+        title: String,
+        body: String,
+        location: Option<ID>,
+        role: Option<i64>
+    ) -> Result<AnnouncementWrapper, String>
+    as "Email an announcement to a location's students, everyone with at \
+        least a role, or everybody if neither is given, and add it to \
+        their feeds." {
+        let ctx = executor.context();
+        if location.is_some() && role.is_some() {
+            let e = Error::invalid("role", "both a location and a role");
+            return Err(e.into());
+        }
+        if role.map_or(false, |role| conditions::Role::from_int(role).is_none()) {
+            return Err(Error::invalid("role", "no such role").into());
+        }
+
+        let location = match location {
+            Some(location) => Some(node::location(&**ctx.database(), &location, "location")?),
+            None => None
+        };
+
+        let cache = cache(ctx, None).at(location);
+        check(&cache, conditions::send_announcement)?;
+        announcements::send(cache, &ctx.config, &ctx.templates, &title, &body, location, role)
+            .map_err(String::from)
+    }
+
+    field createClass(
+        &executor,
+        location: ID,
+        title: String,
+        level: Level,
+        weekday: Weekday,
+        starts_at: TimeOfDay,
+        duration: i32,
+        instructor: Option<ID>,
+        valid_from: Option<Day>,
+        valid_until: Option<Day>
+    ) -> Result<ClassWrapper, String>
+    as "Add a class that happens every week at a location, from today or \
+        the given day. The instructor has to instruct there." {
+        let ctx = executor.context();
+        let location = node::location(&**ctx.database(), &location, "location")?;
+        let instructor = match instructor {
+            Some(instructor) => Some(node::user(&**ctx.database(), &instructor, "instructor")?),
+            None => None
+        };
+
+        let cache = cache(ctx, None).at(Some(location));
+        check(&cache, conditions::edit_timetable)?;
+        timetable::create(cache, &timetable::NewClass {
+            location_id: location,
+            instructor_id: instructor,
+            title: &title,
+            level: level.as_str(),
+            weekday: weekday.number(),
+            starts_at: starts_at.0,
+            duration: duration,
+            valid_from: valid_from.map(|x| x.0),
+            valid_until: valid_until.map(|x| x.0)
+        })
+        .map_err(String::from)
+    }
+
+    field editClass(
+        &executor,
+        id: ID,
+        title: Option<String>,
+        level: Option<Level>,
+        duration: Option<i32>,
+        instructor: Option<ID>,
+        valid_until: Option<Day>,
+        clear_instructor: Option<bool>,
+        clear_valid_until: Option<bool>
+    ) -> Result<ClassWrapper, String>
+    as "Edit the class with the given ID. To move it to another day or time, \
+        end it and add a new one, so past sessions stay where they were. \
+        Clearing the instructor or when it ends takes it away, and can't be \
+        done while also setting it." {
+        let ctx = executor.context();
+        let instructor = match instructor {
+            Some(instructor) => Some(node::user(&**ctx.database(), &instructor, "instructor")?),
+            None => None
+        };
+        let instructor = change(instructor, clear_instructor, "clearInstructor")?;
+        let valid_until = change(valid_until.map(|x| x.0), clear_valid_until, "clearValidUntil")?;
+
+        let id = node::class(&**ctx.database(), &id, "id")?;
+        let (class, cache) = timetable::find(cache(ctx, None), id)?;
+        check(&cache, conditions::edit_timetable)?;
+        timetable::edit(cache, class, &timetable::ClassChanges {
+            instructor_id: instructor,
+            title: title.as_ref().map(|x| x.as_str()),
+            level: level.map(Level::as_str),
+            duration: duration,
+            valid_until: valid_until
+        })
+        .map_err(String::from)
+    }
+
+    field deleteClass(&executor, id: ID) -> Result<ClassWrapper, String>
+    as "Remove the class with the given ID, along with its whole history. \
+        To stop it from now on, set when it ends instead." {
+        let ctx = executor.context();
+        let id = node::class(&**ctx.database(), &id, "id")?;
+        let (class, cache) = timetable::find(cache(ctx, None), id)?;
+        check(&cache, conditions::edit_timetable)?;
+        timetable::delete(cache, class).map_err(String::from)
+    }
+
+    field cancelSession(
+        &executor,
+        class: ID,
+        date: Day,
+        reason: Option<String>
+    ) -> Result<Session, String>
+    as "Call off a class on one day." {
+        let ctx = executor.context();
+        let class = node::class(&**ctx.database(), &class, "class")?;
+        let (class, cache) = timetable::find(cache(ctx, None), class)?;
+        check(&cache, conditions::edit_timetable)?;
+        let reason = reason.as_ref().map(|x| x.as_str());
+        timetable::cancel(cache, class, date.0, true, reason).map_err(String::from)
+    }
+
+    field markAttendance(
+        &executor,
+        class: ID,
+        date: Day,
+        students: Vec<ID>,
+        attended: bool
+    ) -> Result<Session, String>
+    as "Record whether students came to a class on a day, once it's started." {
+        let ctx = executor.context();
+        let class = node::class(&**ctx.database(), &class, "class")?;
+        let mut students = node::users(&**ctx.database(), &students, "students")?;
+        students.sort();
+        students.dedup();
+
+        let (class, cache) = timetable::find(cache(ctx, None), class)?;
+        cache.prefetch_students(&students)?;
+        for &student in &students {
+            check(&cache.retarget(Some(student)), conditions::edit_attendance)?;
+        }
+
+        attendance::mark(cache, class, date.0, &students, attended).map_err(String::from)
+    }
+
+    field checkIn(
+        &executor,
+        class: ID,
+        date: Day,
+        code: String
+    ) -> Result<Session, String>
+    as "Check oneself in to a session that's about to start or going on, \
+        with the code from its QR code." {
+        let ctx = executor.context();
+        let class = node::class(&**ctx.database(), &class, "class")?;
+        let (class, cache) = timetable::find(cache(ctx, None), class)?;
+        if cache.user.is_none() {
+            return Err(Error::unauthorized().into());
+        }
+
+        check(&cache, conditions::check_in)?;
+        attendance::check_in(cache, &ctx.config, class, date.0, &code).map_err(String::from)
+    }
+
+    field restoreSession(
+        &executor,
+        class: ID,
+        date: Day
+    ) -> Result<Session, String>
+    as "Undo calling off a class on one day." {
+        let ctx = executor.context();
+        let class = node::class(&**ctx.database(), &class, "class")?;
+        let (class, cache) = timetable::find(cache(ctx, None), class)?;
+        check(&cache, conditions::edit_timetable)?;
+        timetable::cancel(cache, class, date.0, false, None).map_err(String::from)
+    }
+
+    field createMembershipPlan(
+        &executor,
+        name: String,
+        kind: PlanKind,
+        price: i64,
+        months: i32,
+        currency: Option<String>,
+        max_members: Option<i32>
+    ) -> Result<Plan, String>
+    as "Add a plan people can join, costing a price in cents every given \
+        number of months. Only family plans can have more than one member." {
+        let cache = cache(executor.context(), None);
+        check(&cache, conditions::edit_membership_plans)?;
+        memberships::create_plan(&**cache.database(), &memberships::NewPlan {
+            name: &name,
+            kind: kind.as_str(),
+            price: price,
+            currency: currency.as_ref().map(|x| x.as_str()),
+            months: months,
+            max_members: max_members
+        })
+        .map_err(String::from)
+    }
+
+    field editMembershipPlan(
+        &executor,
+        id: ID,
+        name: Option<String>,
+        price: Option<i64>,
+        max_members: Option<i32>,
+        active: Option<bool>
+    ) -> Result<Plan, String>
+    as "Edit the plan with the given ID. A new price applies from the next \
+        invoices on. Set active to false to stop people joining it." {
+        let cache = cache(executor.context(), None);
+        check(&cache, conditions::edit_membership_plans)?;
+        let id = node::plan(&**cache.database(), &id, "id")?;
+        memberships::edit_plan(&**cache.database(), id, &memberships::PlanChanges {
+            name: name.as_ref().map(|x| x.as_str()),
+            price: price,
+            max_members: max_members,
+            active: active
+        })
+        .map_err(String::from)
+    }
+
+    field createMembership(
+        &executor,
+        plan: ID,
+        payer: ID,
+        members: Option<Vec<ID>>,
+        starts_on: Option<Day>
+    ) -> Result<MembershipWrapper, String>
+    as "Sign people up to a plan from today or the given day, with the payer \
+        getting the invoices. With no members, the payer trains under it." {
+        let ctx = executor.context();
+        let plan = node::plan(&**ctx.database(), &plan, "plan")?;
+        let payer = node::user(&**ctx.database(), &payer, "payer")?;
+        let members = match members {
+            Some(members) => node::users(&**ctx.database(), &members, "members")?,
+            None => Vec::new()
+        };
+
+        let cache = cache(ctx, Some(payer));
+        check(&cache, conditions::edit_memberships)?;
+        memberships::create(cache, plan, payer, &members, starts_on.map(|x| x.0))
+            .map_err(String::from)
+    }
+
+    field endMembership(
+        &executor,
+        id: ID,
+        ends_on: Option<Day>
+    ) -> Result<MembershipWrapper, String>
+    as "Stop a membership after today or the given day. Unpaid invoices for \
+        later periods are dropped." {
+        let ctx = executor.context();
+        let id = node::membership(&**ctx.database(), &id, "id")?;
+        let membership = memberships::find(&**ctx.database(), id)?;
+        let cache = cache(ctx, Some(membership.payer()));
+        check(&cache, conditions::edit_memberships)?;
+        let ends_on = ends_on.map_or(UTC::today().naive_utc(), |x| x.0);
+        memberships::end(cache, membership, ends_on).map_err(String::from)
+    }
+
+    field issueInvoices(&executor) -> Result<Vec<InvoiceWrapper>, String>
+    as "Invoice every membership for the periods that have started. It only \
+        issues what's missing, so it's safe to run every day." {
+        let cache = cache(executor.context(), None);
+        check(&cache, conditions::edit_memberships)?;
+        memberships::issue(cache, UTC::today().naive_utc()).map_err(String::from)
+    }
+
+    field recordPayment(
+        &executor,
+        invoice: ID,
+        amount: i64,
+        reference: Option<String>
+    ) -> Result<InvoiceWrapper, String>
+    as "Record money received for an invoice by hand, like cash at the desk, \
+        in cents. It can't be more than what's left to pay." {
+        let ctx = executor.context();
+        let invoice = node::invoice(&**ctx.database(), &invoice, "invoice")?;
+        let (invoice, membership) = memberships::find_invoice(&**ctx.database(), invoice)?;
+        let cache = cache(ctx, Some(membership.payer()));
+        check(&cache, conditions::record_payment)?;
+        let reference = reference.as_ref().map(|x| x.as_str());
+        memberships::record_payment(cache, invoice, amount, reference)
+            .map_err(String::from)
+    }
+
+    field payInvoice(
+        &executor,
+        invoice: ID,
+        source: String
+    ) -> Result<InvoiceWrapper, String>
+    as "Pay off what's left of an invoice through the payment provider, \
+        with a source like a card token from its checkout form." {
+        let ctx = executor.context();
+        let invoice = node::invoice(&**ctx.database(), &invoice, "invoice")?;
+        let (invoice, membership) = memberships::find_invoice(&**ctx.database(), invoice)?;
+        let cache = cache(ctx, Some(membership.payer()));
+        check(&cache, conditions::pay_invoice)?;
+        memberships::pay(cache, &ctx.payments, invoice, &source).map_err(String::from)
+    }
+});
+
+#[inline]
+fn stringify_error(err: diesel::result::Error) -> String {
+    error!("Database Error: {:?}.", err);
+    Error::from(err).into()
+}
+
+#[inline]
+fn cache<'a>(ctx: &'a Context, target: Option<i64>) -> Cache<'a> {
+    Cache::new(ctx.user, target, &ctx.database, &ctx.memo)
+}
+
+fn wrap_users<'a>(cache: Cache<'a>, users: Vec<User>) -> Vec<UserWrapper<'a>> {
+    let batch = Rc::new(users.iter().map(|user| user.id).collect::<Vec<_>>());
+    users.into_iter()
+        .map(|user| UserWrapper {
+            cache: cache.retarget(Some(user.id)).at(None),
+            batch: batch.clone(),
+            user: user
+        })
+        .collect()
+}
+
+fn wrap_location<'a>(cache: Cache<'a>, location: Location) -> LocationWrapper<'a> {
+    LocationWrapper {
+        cache: cache.retarget(None).at(Some(location.id)),
+        distance: None,
+        batch: Rc::new(vec![location.id]),
+        location: location
+    }
+}
+
+fn wrap_locations<'a>(
+    cache: Cache<'a>,
+    locations: Vec<Location>
+) -> Vec<LocationWrapper<'a>> {
+    let batch = Rc::new(locations.iter().map(|l| l.id).collect::<Vec<_>>());
+    locations.into_iter()
+        .map(|location| LocationWrapper {
+            batch: batch.clone(),
+            ..wrap_location(cache, location)
+        })
+        .collect()
+}
+
+/// How long a list to give back: as many as asked for, or else as many as
+/// lists are allowed to have.
+fn list_size(ctx: &Context, field: &'static str, size: Option<i64>) -> Result<i64, Error> {
+    let max = ctx.config.max_list_size;
+    match size {
+        Some(size) if size < 0 => Err(Error::invalid(field, "can't be negative")),
+        Some(size) if size > max => Err(Error::new(
+            Code::ListTooLong,
+            format!("{} is over the limit of {}", field, max)
+        ).on(field)),
+        Some(size) => Ok(size),
+        None => Ok(max)
+    }
+}
+
+#[inline]
+fn check(
+    cache: &Cache,
+    perm: conditions::Conditions
+) -> Result<(), String> {
+    match perm.check(cache) {
+        Ok(true) => Ok(()),
+        // Nobody's logged in, so their role can't be checked.
+        Ok(false) | Err("unauthorized") => Err(Error::unauthorized().into()),
+        Err(e) => Err(Error::new(Code::ServerError, e).into())
+    }
+}
+
+/// A change to a nullable column: nothing, a new value, or clearing it.
+fn change<T>(
+    value: Option<T>,
+    clear: Option<bool>,
+    field: &'static str
+) -> Result<Option<Option<T>>, Error> {
+    match (value, clear.unwrap_or(false)) {
+        (Some(_), true) => Err(Error::invalid(field, "can't be set and cleared at once")),
+        (Some(value), false) => Ok(Some(Some(value))),
+        (None, true) => Ok(Some(None)),
+        (None, false) => Ok(None)
+    }
+}
+
+/// The great-circle distance from a point to a location, in kilometres.
+pub fn distance(lat: f64, lng: f64) -> Distance {
+    Distance { lat: lat, lng: lng }
+}
+
+pub struct Distance {
+    lat: f64,
+    lng: f64
+}
+
+impl Expression for Distance {
+    type SqlType = Double;
+}
+
+impl QueryFragment<Pg> for Distance {
+    fn to_sql(&self, out: &mut PgQueryBuilder) -> BuildQueryResult {
+        // The haversine formula, with the earth's mean radius.
+        out.push_sql("(12742 * asin(sqrt(power(sin(radians(locations.lat - ");
+        out.push_bind_param();
+        out.push_sql(") / 2), 2) + cos(radians(");
+        out.push_bind_param();
+        out.push_sql(")) * cos(radians(locations.lat)) * \
+            power(sin(radians(locations.lng - ");
+        out.push_bind_param();
+        out.push_sql(") / 2), 2))))");
+        Ok(())
+    }
+
+    fn collect_binds(&self, out: &mut <Pg as diesel::backend::Backend>::BindCollector) -> QueryResult<()> {
+        try!(self.lat.as_expression().collect_binds(out));
+        try!(self.lat.as_expression().collect_binds(out));
+        try!(self.lng.as_expression().collect_binds(out));
+        Ok(())
+    }
+
+    fn is_safe_to_cache_prepared(&self) -> bool {
+        true
+    }
+}
+
+impl_query_id!(noop: Distance);
+
+impl SelectableExpression<locations::table> for Distance {}
+impl AppearsOnTable<locations::table> for Distance {}
+impl NonAggregate for Distance {}
+
+pub fn word_similarity<T, U>(l: T, r: U)
+-> WordSim<T::Expression, U::Expression>
+where T: AsExpression<Text>, U: AsExpression<Text> {
+    WordSim(l.as_expression(), r.as_expression())
+}
+
+pub struct WordSim<T, U>(T, U);
+
+impl<L, R> Expression for WordSim<L, R> {
+    type SqlType = Float;
+}
+
+impl<L, R> QueryFragment<Pg> for WordSim<L, R> where
+    L: QueryFragment<Pg>, R: QueryFragment<Pg>
+{
+    fn to_sql(&self, out: &mut PgQueryBuilder) -> BuildQueryResult {
+        out.push_sql("word_similarity(");
+        self.0.to_sql(out)?;
+        out.push_sql(",");
+        self.1.to_sql(out)?;
+        out.push_sql(")");
+        Ok(())
+    }
+
+    fn collect_binds(&self, out: &mut <Pg as diesel::backend::Backend>::BindCollector) -> QueryResult<()> {
+        try!(self.0.collect_binds(out));
+        try!(self.1.collect_binds(out));
+        Ok(())
+    }
+
+    fn is_safe_to_cache_prepared(&self) -> bool {
+        self.0.is_safe_to_cache_prepared() && self.1.is_safe_to_cache_prepared()
+    }
+}
+
+impl_query_id!(WordSim<T, U>);
+
+impl<T, U> NonAggregate for WordSim<T, U> where
+    T: NonAggregate, U: NonAggregate
+{}
+
+impl<T, U, QS> SelectableExpression<QS>
+    for WordSim<T, U> where
+        WordSim<T, U>: AppearsOnTable<QS>,
+        T: SelectableExpression<QS>,
+        U: SelectableExpression<QS>
+{}
+
+impl<T, U, QS> AppearsOnTable<QS>
+    for WordSim<T, U> where
+        WordSim<T, U>: Expression,
+        T: AppearsOnTable<QS>,
+        U: AppearsOnTable<QS>
+{}
@@ -0,0 +1,176 @@
+use base64;
+use diesel::prelude::*;
+use juniper::ID;
+use schema::{users, locations, classes, membership_plans, memberships, invoices, role_grants};
+use std::fmt::Display;
+use std::str;
+use super::{Context, LocationWrapper, UserWrapper};
+use super::error::{Code, Error};
+use uuid::Uuid;
+
+pub const USER: &'static str = "User";
+pub const LOCATION: &'static str = "Location";
+pub const CLASS: &'static str = "Class";
+pub const PLAN: &'static str = "MembershipPlan";
+pub const MEMBERSHIP: &'static str = "Membership";
+pub const INVOICE: &'static str = "Invoice";
+pub const PAYMENT: &'static str = "Payment";
+pub const ANNOUNCEMENT: &'static str = "Announcement";
+pub const EMAIL: &'static str = "Email";
+pub const ROLE_GRANT: &'static str = "RoleGrant";
+
+/// Anything that can be fetched by its global ID. That's only users and
+/// locations: everything else's IDs are encoded the same way, but are only
+/// taken by the fields that deal with them, which check who may see them.
+pub enum Node<'a> {
+    User(UserWrapper<'a>),
+    Location(LocationWrapper<'a>)
+}
+
+/// A global ID, which is the type and the UUID, so that nobody can guess
+/// them or tell how many there are.
+pub fn encode<U: Display>(kind: &str, uuid: U) -> ID {
+    let id = format!("{}:{}", kind, uuid);
+    ID::from(base64::encode_config(id.as_bytes(), base64::URL_SAFE))
+}
+
+/// The type and UUID in a global ID, if it's valid.
+pub fn decode(id: &ID) -> Option<(String, Uuid)> {
+    let bytes = base64::decode_config(&**id, base64::URL_SAFE).ok()?;
+    let id = str::from_utf8(&bytes).ok()?;
+    let colon = id.find(':')?;
+    let uuid = Uuid::parse_str(&id[colon + 1..]).ok()?;
+    Some((id[..colon].to_owned(), uuid))
+}
+
+/// The UUID in a global ID of the given type, or else an error blaming
+/// the argument.
+pub fn uuid(id: &ID, kind: &str, field: &'static str) -> Result<Uuid, Error> {
+    match decode(id) {
+        Some((ref k, uuid)) if k == kind => Ok(uuid),
+        _ => Err(Error::invalid(field, "invalid ID"))
+    }
+}
+
+pub fn uuids(ids: &[ID], kind: &str, field: &'static str) -> Result<Vec<Uuid>, Error> {
+    ids.iter().map(|id| uuid(id, kind, field)).collect()
+}
+
+/// The key of the user with the given global ID.
+pub fn user(db: &PgConnection, id: &ID, field: &'static str) -> Result<i64, Error> {
+    Ok(users(db, &[id.clone()], field)?[0])
+}
+
+/// The keys of the users with the given global IDs, in the same order.
+pub fn users(db: &PgConnection, ids: &[ID], field: &'static str) -> Result<Vec<i64>, Error> {
+    let uuids = uuids(ids, USER, field)?;
+    let found = users::table
+        .filter(users::uuid.eq_any(uuids.clone()))
+        .select((users::uuid, users::id))
+        .get_results::<(Uuid, i64)>(db)?;
+    keys(uuids, found, "no such user", field)
+}
+
+/// The key of the location with the given global ID.
+pub fn location(db: &PgConnection, id: &ID, field: &'static str) -> Result<i64, Error> {
+    Ok(locations(db, &[id.clone()], field)?[0])
+}
+
+/// The keys of the locations with the given global IDs, in the same order.
+pub fn locations(db: &PgConnection, ids: &[ID], field: &'static str) -> Result<Vec<i64>, Error> {
+    let uuids = uuids(ids, LOCATION, field)?;
+    let found = locations::table
+        .filter(locations::uuid.eq_any(uuids.clone()))
+        .select((locations::uuid, locations::id))
+        .get_results::<(Uuid, i64)>(db)?;
+    keys(uuids, found, "no such location", field)
+}
+
+/// The key of the class with the given global ID.
+pub fn class(db: &PgConnection, id: &ID, field: &'static str) -> Result<i64, Error> {
+    let uuid = uuid(id, CLASS, field)?;
+    let found = classes::table
+        .filter(classes::uuid.eq(uuid))
+        .select((classes::uuid, classes::id))
+        .get_results::<(Uuid, i64)>(db)?;
+    Ok(keys(vec![uuid], found, "no such class", field)?[0])
+}
+
+/// The key of the membership plan with the given global ID.
+pub fn plan(db: &PgConnection, id: &ID, field: &'static str) -> Result<i64, Error> {
+    let uuid = uuid(id, PLAN, field)?;
+    let found = membership_plans::table
+        .filter(membership_plans::uuid.eq(uuid))
+        .select((membership_plans::uuid, membership_plans::id))
+        .get_results::<(Uuid, i64)>(db)?;
+    Ok(keys(vec![uuid], found, "no such plan", field)?[0])
+}
+
+/// The key of the membership with the given global ID.
+pub fn membership(db: &PgConnection, id: &ID, field: &'static str) -> Result<i64, Error> {
+    let uuid = uuid(id, MEMBERSHIP, field)?;
+    let found = memberships::table
+        .filter(memberships::uuid.eq(uuid))
+        .select((memberships::uuid, memberships::id))
+        .get_results::<(Uuid, i64)>(db)?;
+    Ok(keys(vec![uuid], found, "no such membership", field)?[0])
+}
+
+/// The key of the invoice with the given global ID.
+pub fn invoice(db: &PgConnection, id: &ID, field: &'static str) -> Result<i64, Error> {
+    let uuid = uuid(id, INVOICE, field)?;
+    let found = invoices::table
+        .filter(invoices::uuid.eq(uuid))
+        .select((invoices::uuid, invoices::id))
+        .get_results::<(Uuid, i64)>(db)?;
+    Ok(keys(vec![uuid], found, "no such invoice", field)?[0])
+}
+
+/// The key of the role grant with the given global ID.
+pub fn role_grant(db: &PgConnection, id: &ID, field: &'static str) -> Result<i64, Error> {
+    let uuid = uuid(id, ROLE_GRANT, field)?;
+    let found = role_grants::table
+        .filter(role_grants::uuid.eq(uuid))
+        .select((role_grants::uuid, role_grants::id))
+        .get_results::<(Uuid, i64)>(db)?;
+    Ok(keys(vec![uuid], found, "no such grant", field)?[0])
+}
+
+fn keys(
+    uuids: Vec<Uuid>,
+    found: Vec<(Uuid, i64)>,
+    missing: &'static str,
+    field: &'static str
+) -> Result<Vec<i64>, Error> {
+    uuids.into_iter()
+        .map(|uuid| {
+            found.iter()
+                .find(|row| row.0 == uuid)
+                .map(|row| row.1)
+                .ok_or_else(|| Error::new(Code::NotFound, missing).on(field))
+        })
+        .collect()
+}
+
+graphql_interface!(<'a> Node<'a>: Context as "Node" |&self| {
+    description: "Something that can be fetched by its ID."
+
+    field id() -> ID
+    as "A unique ID." {
+        match *self {
+            Node::User(ref x) => encode(USER, x.user.uuid),
+            Node::Location(ref x) => encode(LOCATION, x.location.uuid)
+        }
+    }
+
+    instance_resolvers: |_| {
+        &UserWrapper<'a> => match *self {
+            Node::User(ref x) => Some(x),
+            _ => None
+        },
+        &LocationWrapper<'a> => match *self {
+            Node::Location(ref x) => Some(x),
+            _ => None
+        },
+    }
+});
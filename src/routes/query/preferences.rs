@@ -0,0 +1,25 @@
+use email::Category;
+use super::Context;
+
+pub struct NotificationPreference {
+    pub category: Category,
+    pub enabled: bool
+}
+
+graphql_enum!(Category {
+    Category::Announcements => "ANNOUNCEMENTS" as "Announcements from instructors and admins."
+});
+
+graphql_object!(NotificationPreference: Context as "NotificationPreference" |&self| {
+    description: "Whether a user gets a kind of optional email."
+
+    field category() -> Category
+    as "The kind of email." {
+        self.category
+    }
+
+    field enabled() -> bool
+    as "Whether they get it." {
+        self.enabled
+    }
+});
@@ -0,0 +1,315 @@
+use conditions::{self, Cache};
+use diesel::select;
+use diesel::expression::exists;
+use diesel::prelude::*;
+use juniper::ID;
+use listener::Change;
+use schema::{users, locations, instructor_locations};
+use std::rc::Rc;
+use super::{
+    Context,
+    Location,
+    LocationWrapper,
+    User,
+    UserWrapper,
+    cache,
+    node,
+    stringify_error,
+    wrap_location
+};
+
+/// The root of subscriptions. Each one is run against every change as it
+/// happens, and is only sent if a field isn't null.
+pub struct Subscription;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Operation {
+    Created,
+    Updated,
+    Deleted,
+    Left // A student moved away from a location.
+}
+
+graphql_enum!(Operation {
+    Operation::Created => "CREATED",
+    Operation::Updated => "UPDATED",
+    Operation::Deleted => "DELETED",
+    Operation::Left => "LEFT"
+});
+
+pub struct UserChange<'a> {
+    operation: Operation,
+    change: Change,
+    user: Option<UserWrapper<'a>>
+}
+
+pub struct LocationChange<'a> {
+    operation: Operation,
+    change: Change,
+    location: Option<LocationWrapper<'a>>
+}
+
+pub struct AssignmentChange<'a> {
+    operation: Operation,
+    change: Change,
+    instructor: Option<UserWrapper<'a>>,
+    location: Option<LocationWrapper<'a>>
+}
+
+graphql_object!(Subscription: Context as "Subscription" |&self| {
+    field userChanged(
+        &executor,
+        operations: Option<Vec<Operation>>
+    ) -> Result<Option<UserChange>, String>
+    as "A user was created, edited or removed. Only sent for oneself, \
+        one's students, or anyone at all for admins. When a student moves \
+        away, their old instructors only get their ID, as LEFT." {
+        let ctx = executor.context();
+        let (change, operation) = match relevant(ctx, "users", &None) {
+            Some(relevant) => relevant,
+            None => return Ok(None)
+        };
+
+        let mut cache = cache(ctx, Some(change.id));
+        cache.student = Some(instructs(&cache, change.location)?);
+
+        if !conditions::read_students.check(&cache).unwrap_or(false) {
+            let left = operation == Operation::Updated &&
+                change.old_location != change.location &&
+                instructs(&cache, change.old_location)?;
+
+            return Ok(if left && wanted(&operations, Operation::Left) {
+                Some(UserChange { operation: Operation::Left, change: change.clone(), user: None })
+            } else {
+                None
+            });
+        }
+
+        if !wanted(&operations, operation) {
+            return Ok(None);
+        }
+
+        let user = if operation == Operation::Deleted {
+            None
+        } else {
+            users::table.find(change.id)
+                .first(&**cache.database())
+                .optional()
+                .map_err(stringify_error)?
+                .map(|user| UserWrapper {
+                    batch: Rc::new(vec![change.id]),
+                    user: user,
+                    cache: cache
+                })
+        };
+
+        Ok(Some(UserChange {
+            operation: operation,
+            change: change.clone(),
+            user: user
+        }))
+    }
+
+    field locationChanged(
+        &executor,
+        operations: Option<Vec<Operation>>
+    ) -> Result<Option<LocationChange>, String>
+    as "A location was created, edited or removed." {
+        let ctx = executor.context();
+        let (change, operation) = match relevant(ctx, "locations", &operations) {
+            Some(relevant) => relevant,
+            None => return Ok(None)
+        };
+
+        let cache = cache(ctx, None).at(Some(change.id));
+        if !visible(&cache, change.public.unwrap_or(false)) {
+            return Ok(None);
+        }
+
+        let location = if operation == Operation::Deleted {
+            None
+        } else {
+            locations::table.find(change.id)
+                .first(&**cache.database())
+                .optional()
+                .map_err(stringify_error)?
+                .map(|location| wrap_location(cache, location))
+        };
+
+        Ok(Some(LocationChange {
+            operation: operation,
+            change: change.clone(),
+            location: location
+        }))
+    }
+
+    field instructorAssignmentChanged(
+        &executor,
+        operations: Option<Vec<Operation>>
+    ) -> Result<Option<AssignmentChange>, String>
+    as "An instructor was assigned to a location, had their assignment \
+        changed, or was unassigned." {
+        let ctx = executor.context();
+        let (change, operation) = match relevant(ctx, "instructor_locations", &operations) {
+            Some(relevant) => relevant,
+            None => return Ok(None)
+        };
+
+        let (instructor, location) = match (change.user, change.location) {
+            (Some(instructor), Some(location)) => (instructor, location),
+            _ => return Ok(None)
+        };
+
+        let cache = cache(ctx, None).at(Some(location));
+        let found = locations::table.find(location)
+            .first::<Location>(&**cache.database())
+            .optional()
+            .map_err(stringify_error)?;
+
+        // Assignments go along with their locations, so if it's gone,
+        // there's no telling whether it was public.
+        let public = found.as_ref().map_or(false, |x| x.public);
+        if !visible(&cache, public) ||
+           !conditions::read_instructors.check(&cache).unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let user = users::table.find(instructor)
+            .first::<User>(&**cache.database())
+            .optional()
+            .map_err(stringify_error)?;
+
+        Ok(Some(AssignmentChange {
+            operation: operation,
+            change: change.clone(),
+            instructor: user.map(|user| UserWrapper {
+                batch: Rc::new(vec![instructor]),
+                user: user,
+                cache: cache.retarget(Some(instructor))
+            }),
+            location: found.map(|location| wrap_location(cache, location))
+        }))
+    }
+});
+
+graphql_object!(<'a> UserChange<'a>: Context as "UserChange" |&self| {
+    description: "Something that happened to a user."
+
+    field operation() -> Operation
+    as "What happened." {
+        self.operation
+    }
+
+    field id() -> Option<ID>
+    as "The user's ID." {
+        self.change.user_uuid.as_ref().map(|uuid| node::encode(node::USER, uuid))
+    }
+
+    field user() -> &Option<UserWrapper<'a>>
+    as "The user as they are now, unless they were removed or left." {
+        &self.user
+    }
+});
+
+graphql_object!(<'a> LocationChange<'a>: Context as "LocationChange" |&self| {
+    description: "Something that happened to a location."
+
+    field operation() -> Operation
+    as "What happened." {
+        self.operation
+    }
+
+    field id() -> Option<ID>
+    as "The location's ID." {
+        self.change.location_uuid.as_ref().map(|uuid| node::encode(node::LOCATION, uuid))
+    }
+
+    field location() -> &Option<LocationWrapper<'a>>
+    as "The location as it is now, unless it was removed." {
+        &self.location
+    }
+});
+
+graphql_object!(<'a> AssignmentChange<'a>: Context as "AssignmentChange" |&self| {
+    description: "Something that happened to an instructor's assignment."
+
+    field operation() -> Operation
+    as "What happened." {
+        self.operation
+    }
+
+    field instructorId() -> Option<ID>
+    as "The instructor's ID." {
+        self.change.user_uuid.as_ref().map(|uuid| node::encode(node::USER, uuid))
+    }
+
+    field locationId() -> Option<ID>
+    as "The location's ID." {
+        self.change.location_uuid.as_ref().map(|uuid| node::encode(node::LOCATION, uuid))
+    }
+
+    field instructor() -> &Option<UserWrapper<'a>>
+    as "The instructor, unless they were removed." {
+        &self.instructor
+    }
+
+    field location() -> &Option<LocationWrapper<'a>>
+    as "The location, unless it was removed." {
+        &self.location
+    }
+});
+
+/// The change being handled, if it's to the table and is one of the
+/// operations asked for.
+fn relevant<'a>(
+    ctx: &'a Context,
+    table: &str,
+    operations: &Option<Vec<Operation>>
+) -> Option<(&'a Change, Operation)> {
+    let change = match ctx.change {
+        Some(ref change) if change.table == table => change,
+        _ => return None
+    };
+
+    let operation = match change.operation.as_str() {
+        "INSERT" => Operation::Created,
+        "UPDATE" => Operation::Updated,
+        "DELETE" => Operation::Deleted,
+        _ => return None
+    };
+
+    if wanted(operations, operation) {
+        Some((change, operation))
+    } else {
+        None
+    }
+}
+
+/// Whether the subscriber asked for the operation, or for every one.
+fn wanted(operations: &Option<Vec<Operation>>, operation: Operation) -> bool {
+    operations.as_ref().map_or(true, |x| x.contains(&operation))
+}
+
+/// Whether the user instructs at the location right now.
+fn instructs(cache: &Cache, location: Option<i64>) -> Result<bool, String> {
+    let (user, location) = match (cache.user, location) {
+        (Some(user), Some(location)) => (user, location),
+        _ => return Ok(false)
+    };
+
+    select(exists(
+        instructor_locations::table.filter(
+            instructor_locations::location_id.eq(location)
+            .and(instructor_locations::instructor_id.eq(user))
+            .and(conditions::active_assignment())
+        )
+    ))
+    .get_result(&**cache.database())
+    .map_err(stringify_error)
+}
+
+/// Whether the user may see a location, given whether it's public.
+fn visible(cache: &Cache, public: bool) -> bool {
+    conditions::read_location_info.check(cache).unwrap_or(false) &&
+    (public || conditions::read_private_location.check(cache).unwrap_or(false))
+}
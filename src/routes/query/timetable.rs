@@ -0,0 +1,564 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, UTC};
+use conditions::{self, Cache};
+use diesel;
+use diesel::expression::exists;
+use diesel::pg::PgConnection;
+use diesel::result::Error as DieselError;
+use diesel::prelude::*;
+use diesel::select;
+use juniper::ID;
+use schema::{users, locations, instructor_locations, classes, class_cancellations};
+use schema::{in_time_zone, timezone};
+use std::rc::Rc;
+use super::{
+    Context,
+    Day,
+    Location,
+    LocationWrapper,
+    TimeOfDay,
+    Timestamp,
+    User,
+    UserWrapper,
+    check,
+    stringify_error,
+    wrap_location,
+    wrap_users
+};
+use super::attendance;
+use super::error::Error;
+use super::node;
+use uuid::Uuid;
+
+/// The most days a timetable can cover at once.
+const MAX_DAYS: i64 = 92;
+
+#[derive(Queryable, Clone)]
+pub struct Class {
+    id: i64,
+    location_id: i64,
+    instructor_id: Option<i64>,
+    title: String,
+    level: String,
+    weekday: i32,
+    starts_at: NaiveTime,
+    duration: i32,
+    valid_from: NaiveDate,
+    valid_until: Option<NaiveDate>,
+    uuid: Uuid
+}
+
+pub struct ClassWrapper<'a> {
+    class: Class,
+    cache: Cache<'a>
+}
+
+/// One time a class happens.
+pub struct Session<'a> {
+    class: ClassWrapper<'a>,
+    date: NaiveDate,
+    starts_at: DateTime<UTC>,
+    cancelled: Option<Option<String>> // With the reason, if there was one.
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Level {
+    All,
+    Beginner,
+    Intermediate,
+    Advanced
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::All => "all",
+            Level::Beginner => "beginner",
+            Level::Intermediate => "intermediate",
+            Level::Advanced => "advanced"
+        }
+    }
+
+    pub fn from_str(level: &str) -> Option<Level> {
+        [Level::All, Level::Beginner, Level::Intermediate, Level::Advanced]
+            .iter()
+            .cloned()
+            .find(|x| x.as_str() == level)
+    }
+}
+
+graphql_enum!(Level {
+    Level::All => "ALL",
+    Level::Beginner => "BEGINNER",
+    Level::Intermediate => "INTERMEDIATE",
+    Level::Advanced => "ADVANCED"
+});
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday
+}
+
+impl Weekday {
+    pub fn from_int(day: i32) -> Option<Weekday> {
+        match day {
+            1 => Some(Weekday::Monday),
+            2 => Some(Weekday::Tuesday),
+            3 => Some(Weekday::Wednesday),
+            4 => Some(Weekday::Thursday),
+            5 => Some(Weekday::Friday),
+            6 => Some(Weekday::Saturday),
+            7 => Some(Weekday::Sunday),
+            _ => None
+        }
+    }
+
+    /// The day of the week, where Monday is 1.
+    pub fn number(self) -> i32 {
+        self as i32 + 1
+    }
+}
+
+graphql_enum!(Weekday {
+    Weekday::Monday => "MONDAY",
+    Weekday::Tuesday => "TUESDAY",
+    Weekday::Wednesday => "WEDNESDAY",
+    Weekday::Thursday => "THURSDAY",
+    Weekday::Friday => "FRIDAY",
+    Weekday::Saturday => "SATURDAY",
+    Weekday::Sunday => "SUNDAY"
+});
+
+#[derive(Insertable)]
+#[table_name="classes"]
+pub struct NewClass<'a> {
+    pub location_id: i64,
+    pub instructor_id: Option<i64>,
+    pub title: &'a str,
+    pub level: &'static str,
+    pub weekday: i32,
+    pub starts_at: NaiveTime,
+    pub duration: i32,
+    pub valid_from: Option<NaiveDate>,
+    pub valid_until: Option<NaiveDate>
+}
+
+#[derive(AsChangeset)]
+#[table_name="classes"]
+pub struct ClassChanges<'a> {
+    pub instructor_id: Option<Option<i64>>,
+    pub title: Option<&'a str>,
+    pub level: Option<&'static str>,
+    pub duration: Option<i32>,
+    pub valid_until: Option<Option<NaiveDate>>
+}
+
+#[derive(Insertable)]
+#[table_name="class_cancellations"]
+struct Cancellation<'a> {
+    class_id: i64,
+    date: NaiveDate,
+    reason: Option<&'a str>
+}
+
+impl Class {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn location(&self) -> i64 {
+        self.location_id
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// Whether the class happens on the day, cancellations aside.
+    pub fn on(&self, date: NaiveDate) -> bool {
+        date.weekday().number_from_monday() as i32 == self.weekday &&
+        self.valid_from <= date &&
+        self.valid_until.map_or(true, |until| date <= until)
+    }
+}
+
+impl<'a> Session<'a> {
+    pub fn class(&self) -> &Class {
+        &self.class.class
+    }
+
+    pub fn date(&self) -> NaiveDate {
+        self.date
+    }
+
+    pub fn start(&self) -> DateTime<UTC> {
+        self.starts_at
+    }
+
+    pub fn end(&self) -> DateTime<UTC> {
+        self.starts_at + Duration::minutes(self.class().duration as i64)
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.cancelled.is_some()
+    }
+
+    /// Why it was called off, if it was and someone said.
+    pub fn reason(&self) -> Option<&str> {
+        self.cancelled.as_ref().and_then(|x| x.as_ref()).map(|x| x.as_str())
+    }
+}
+
+graphql_object!(<'a> ClassWrapper<'a>: Context as "Class" |&self| {
+    description: "A class that happens every week at a location."
+
+    field id() -> ID
+    as "A unique ID for the class." {
+        node::encode(node::CLASS, self.class.uuid)
+    }
+
+    field title() -> &str
+    as "What the class is called." {
+        &self.class.title
+    }
+
+    field level() -> Option<Level>
+    as "Who the class is for." {
+        Level::from_str(&self.class.level)
+    }
+
+    field weekday() -> Option<Weekday>
+    as "The day of the week it's on." {
+        Weekday::from_int(self.class.weekday)
+    }
+
+    field startsAt() -> TimeOfDay
+    as "The time of day it starts, in its location's time zone." {
+        TimeOfDay(self.class.starts_at)
+    }
+
+    field duration() -> i32
+    as "How long it goes for, in minutes." {
+        self.class.duration
+    }
+
+    field validFrom() -> Day
+    as "The first day it could be on." {
+        Day(self.class.valid_from)
+    }
+
+    field validUntil() -> Option<Day>
+    as "The last day it could be on, if it ends." {
+        self.class.valid_until.map(Day)
+    }
+
+    field location() -> Result<LocationWrapper, String>
+    as "Where it's held." {
+        locations::table.find(self.class.location_id)
+            .first::<Location>(&**self.cache.database())
+            .map(|location| wrap_location(self.cache, location))
+            .map_err(stringify_error)
+    }
+
+    field instructor() -> Result<Option<UserWrapper>, String>
+    as "Who teaches it, if anyone's been chosen." {
+        let instructor = match self.class.instructor_id {
+            Some(instructor) => instructor,
+            None => return Ok(None)
+        };
+
+        users::table.find(instructor)
+            .first::<User>(&**self.cache.database())
+            .optional()
+            .map(|user| user.map(|user| UserWrapper {
+                batch: Rc::new(vec![user.id]),
+                user: user,
+                cache: self.cache.retarget(Some(instructor)).at(None)
+            }))
+            .map_err(stringify_error)
+    }
+});
+
+graphql_object!(<'a> Session<'a>: Context as "Session" |&self| {
+    description: "One time a class happens."
+
+    field class() -> &ClassWrapper
+    as "The class it's a session of." {
+        &self.class
+    }
+
+    field date() -> Day
+    as "The day it's on." {
+        Day(self.date)
+    }
+
+    field startsAt() -> Timestamp
+    as "When it starts." {
+        Timestamp(self.start())
+    }
+
+    field endsAt() -> Timestamp
+    as "When it ends." {
+        Timestamp(self.end())
+    }
+
+    field cancelled() -> bool
+    as "Whether it's been called off." {
+        self.cancelled.is_some()
+    }
+
+    field cancellationReason() -> Option<&str>
+    as "Why it was called off, if it was and someone said." {
+        self.reason()
+    }
+
+    field attended() -> Result<bool, String>
+    as "Whether oneself was there." {
+        match self.class.cache.user {
+            Some(user) => attendance::attended(self, self.class.cache, user).map_err(stringify_error),
+            None => Ok(false)
+        }
+    }
+
+    field attendees() -> Result<Vec<UserWrapper>, String>
+    as "Everyone who was there." {
+        check(&self.class.cache, conditions::read_class_attendance)?;
+        attendance::attendees(self, self.class.cache)
+            .map(|users| wrap_users(self.class.cache, users))
+            .map_err(stringify_error)
+    }
+
+    field checkInCode(&executor) -> Result<String, String>
+    as "The code for members to check themselves in, to be shown as a QR \
+        code. It changes every few minutes, so fetch it again to keep it \
+        working." {
+        check(&self.class.cache, conditions::read_check_in_code)?;
+        Ok(attendance::check_in_code(&executor.context().config, self))
+    }
+});
+
+/// Every session at the location starting between two times, earliest first,
+/// cancelled ones included.
+pub fn timetable<'a>(
+    cache: Cache<'a>,
+    location: i64,
+    from: DateTime<UTC>,
+    to: DateTime<UTC>
+) -> Result<Vec<Session<'a>>, Error> {
+    if to <= from {
+        return Err(Error::invalid("to", "ends before it starts"));
+    }
+    if to - from > Duration::days(MAX_DAYS) {
+        return Err(Error::invalid("to", format!("at most {} days at a time", MAX_DAYS)));
+    }
+
+    // Local dates can be a day either side of the UTC ones.
+    let db = &**cache.database();
+    let first = from.naive_utc().date().pred();
+    let last = to.naive_utc().date().succ();
+
+    let found = classes::table
+        .filter(classes::location_id.eq(location))
+        .filter(classes::valid_from.le(last))
+        .filter(classes::valid_until.is_null().or(classes::valid_until.ge(first)))
+        .get_results::<Class>(db)?;
+
+    let ids = found.iter().map(|class| class.id).collect::<Vec<_>>();
+    let cancellations = class_cancellations::table
+        .filter(class_cancellations::class_id.eq_any(ids))
+        .filter(class_cancellations::date.between(first..last))
+        .get_results::<(i64, NaiveDate, Option<String>)>(db)?;
+
+    let mut held = Vec::new();
+    let mut date = first;
+    while date <= last {
+        held.extend(found.iter().filter(|class| class.on(date)).map(|class| (class, date)));
+        date = date.succ();
+    }
+
+    let times = held.iter().map(|&(class, date)| date.and_time(class.starts_at)).collect();
+    let starts = local_times(db, location, times)?;
+
+    let mut sessions = Vec::new();
+    for (&(class, date), start) in held.iter().zip(starts) {
+        if start < from || to <= start {
+            continue;
+        }
+
+        let cancelled = cancellations.iter()
+            .find(|x| x.0 == class.id && x.1 == date)
+            .map(|x| x.2.clone());
+
+        sessions.push(Session {
+            class: ClassWrapper { class: class.clone(), cache: cache },
+            date: date,
+            starts_at: start,
+            cancelled: cancelled
+        });
+    }
+
+    sessions.sort_by_key(|x| (x.starts_at, x.class.class.id));
+    Ok(sessions)
+}
+
+/// The classes at a location that haven't ended, by day and time.
+pub fn current<'a>(cache: Cache<'a>, location: i64) -> Result<Vec<ClassWrapper<'a>>, Error> {
+    let today = UTC::today().naive_utc();
+    let found = classes::table
+        .filter(classes::location_id.eq(location))
+        .filter(classes::valid_until.is_null().or(classes::valid_until.ge(today)))
+        .order((classes::weekday, classes::starts_at))
+        .get_results::<Class>(&**cache.database())?;
+
+    Ok(found.into_iter()
+        .map(|class| ClassWrapper { class: class, cache: cache })
+        .collect())
+}
+
+/// The class, and a cache for checking permissions at its location.
+pub fn find<'a>(cache: Cache<'a>, id: i64) -> Result<(Class, Cache<'a>), Error> {
+    let class = classes::table.find(id)
+        .first::<Class>(&**cache.database())?;
+    let cache = cache.at(Some(class.location_id));
+    Ok((class, cache))
+}
+
+pub fn create<'a>(cache: Cache<'a>, new_class: &NewClass) -> Result<ClassWrapper<'a>, Error> {
+    let db = &**cache.database();
+    if let Some(instructor) = new_class.instructor_id {
+        check_instructor(db, instructor, new_class.location_id)?;
+    }
+
+    let class = diesel::insert(new_class)
+        .into(classes::table)
+        .get_result::<Class>(db)?;
+    Ok(ClassWrapper { class: class, cache: cache })
+}
+
+pub fn edit<'a>(
+    cache: Cache<'a>,
+    class: Class,
+    changes: &ClassChanges
+) -> Result<ClassWrapper<'a>, Error> {
+    let db = &**cache.database();
+    if let Some(Some(instructor)) = changes.instructor_id {
+        check_instructor(db, instructor, class.location_id)?;
+    }
+
+    let class = diesel::update(classes::table.find(class.id))
+        .set(changes)
+        .get_result::<Class>(db)?;
+    Ok(ClassWrapper { class: class, cache: cache })
+}
+
+pub fn delete<'a>(cache: Cache<'a>, class: Class) -> Result<ClassWrapper<'a>, Error> {
+    let class = diesel::delete(classes::table.find(class.id))
+        .get_result::<Class>(&**cache.database())?;
+    Ok(ClassWrapper { class: class, cache: cache })
+}
+
+/// The session of the class on the day, if it's on.
+pub fn session<'a>(cache: Cache<'a>, class: Class, date: NaiveDate) -> Result<Session<'a>, Error> {
+    if !class.on(date) {
+        return Err(Error::invalid("date", "the class isn't on that day"));
+    }
+
+    let db = &**cache.database();
+    let cancelled = class_cancellations::table.find((class.id, date))
+        .select(class_cancellations::reason)
+        .first::<Option<String>>(db)
+        .optional()?;
+
+    Ok(Session {
+        starts_at: start(db, &class, date)?,
+        class: ClassWrapper { class: class, cache: cache },
+        date: date,
+        cancelled: cancelled
+    })
+}
+
+/// Call off or restore one session of a class.
+pub fn cancel<'a>(
+    cache: Cache<'a>,
+    class: Class,
+    date: NaiveDate,
+    cancelled: bool,
+    reason: Option<&str>
+) -> Result<Session<'a>, Error> {
+    if !class.on(date) {
+        return Err(Error::invalid("date", "the class isn't on that day"));
+    }
+
+    let db = &**cache.database();
+    if cancelled {
+        diesel::insert(&Cancellation { class_id: class.id, date: date, reason: reason })
+            .into(class_cancellations::table)
+            .execute(db)?;
+    } else {
+        diesel::delete(class_cancellations::table.find((class.id, date)))
+            .execute(db)?;
+    }
+
+    Ok(Session {
+        starts_at: start(db, &class, date)?,
+        class: ClassWrapper { class: class, cache: cache },
+        date: date,
+        cancelled: if cancelled { Some(reason.map(|x| x.to_owned())) } else { None }
+    })
+}
+
+/// Make sure Postgres knows the time zone, since classes' times are read
+/// in it.
+pub fn check_time_zone(db: &PgConnection, zone: &str) -> Result<(), Error> {
+    // In a savepoint, since the failed query would spoil any transaction.
+    let now = UTC::now().naive_utc();
+    match db.transaction(|| select(timezone(zone, now)).execute(db)) {
+        Ok(_) => Ok(()),
+        Err(DieselError::DatabaseError(..)) => {
+            Err(Error::invalid("timeZone", "unknown time zone"))
+        },
+        Err(e) => Err(e.into())
+    }
+}
+
+/// When a session of the class starts.
+fn start(db: &PgConnection, class: &Class, date: NaiveDate) -> QueryResult<DateTime<UTC>> {
+    let starts = local_times(db, class.location_id, vec![date.and_time(class.starts_at)])?;
+    starts.into_iter().next().ok_or(DieselError::NotFound)
+}
+
+/// When each of the times, read in the location's time zone, happens.
+fn local_times(
+    db: &PgConnection,
+    location: i64,
+    times: Vec<NaiveDateTime>
+) -> QueryResult<Vec<DateTime<UTC>>> {
+    let zone = locations::table.find(location)
+        .select(locations::time_zone)
+        .first::<String>(db)?;
+
+    select(in_time_zone(zone, times)).get_result(db)
+}
+
+/// Make sure a class's instructor actually instructs at its location.
+fn check_instructor(db: &PgConnection, instructor: i64, location: i64) -> Result<(), Error> {
+    let instructs = select(exists(
+        instructor_locations::table.filter(
+            instructor_locations::location_id.eq(location)
+            .and(instructor_locations::instructor_id.eq(instructor))
+            .and(conditions::active_assignment())
+        )
+    ))
+    .get_result::<bool>(db)?;
+
+    if instructs {
+        Ok(())
+    } else {
+        Err(Error::invalid("instructor", "not an instructor at the location"))
+    }
+}
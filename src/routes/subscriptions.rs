@@ -0,0 +1,314 @@
+//! Subscriptions, sent as server-sent events. Juniper doesn't know about
+//! subscriptions, so the document is run as a query against the
+//! `Subscription` root for every change, as whoever subscribed, and the
+//! result is sent unless all of it came back null.
+
+use auth::Cookie;
+use config::Config;
+use database::{self, Database};
+use email::Templates;
+use iron::headers::{CacheControl, CacheDirective};
+use iron::middleware::Handler;
+use iron::mime::Mime;
+use iron::prelude::*;
+use iron::response::WriteBody;
+use iron::status;
+use juniper::{self, RootNode, Value, Variables};
+use juniper::parser::{Lexer, Token};
+use listener::{Full, Listener, Subscription as Changes};
+use payments::Payments;
+use persistent::Read;
+use serde_json;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+use super::graphql::{self, GraphQLRequest};
+use super::limits;
+use super::persisted::PersistedQueries;
+use super::query::{self, Context, Locale, Mutate, Subscription};
+use super::query::error::{self, Code, Error};
+
+/// How often to check that the subscriber's still there, in seconds.
+const KEEPALIVE: u64 = 30;
+
+// Only subscriptions are let through, so the mutations can't be reached.
+type SubscriptionRoot = RootNode<'static, Subscription, Mutate>;
+
+/// POST /subscriptions
+/// Body:
+///     The same as for POST /, but with a subscription.
+/// Response:
+///     An event stream, with a `next` event holding the data and errors
+///     for each change, until the session expires and a `complete` event
+///     is sent. Subscribers who fall too far behind are cut off.
+/// Status Codes:
+///     200: Subscribed.
+///     400: Bad message body, or the subscription couldn't be parsed or
+///          validated, or it isn't a subscription.
+///     401: Not logged in.
+///     403: Only persisted queries are allowed, except for admins.
+///     404: There's no persisted query with that id.
+///     413: The subscription is too deep, too complex, or asks for too long
+///          a list.
+///     429: The user already has as many subscriptions as they're allowed.
+///     500: The subscription couldn't be looked up or recorded.
+///     503: There are already as many subscribers as the server allows, or
+///          there wasn't a database connection to spare.
+//LONG: Websockets, so that subscribers don't each tie up a thread.
+pub struct SubscriptionHandler {
+    root: Arc<SubscriptionRoot>,
+    persisted: Arc<PersistedQueries>,
+    listener: Listener
+}
+
+impl SubscriptionHandler {
+    pub fn new(persisted: Arc<PersistedQueries>, listener: Listener) -> SubscriptionHandler {
+        SubscriptionHandler {
+            root: Arc::new(RootNode::new(Subscription, Mutate)),
+            persisted: persisted,
+            listener: listener
+        }
+    }
+}
+
+impl Handler for SubscriptionHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let body = match serde_json::from_reader::<_, GraphQLRequest>(&mut req.body) {
+            Ok(body) => body,
+            Err(_) => return Ok(Response::with(status::BadRequest))
+        };
+
+        let config = req.extensions.get::<Read<Config>>().unwrap().clone();
+        let templates = req.extensions.get::<Read<Templates>>().unwrap().clone();
+        let payments = req.extensions.get::<Read<Payments>>().unwrap().clone();
+        let database = req.extensions.get::<Database>().unwrap().clone();
+        let session = query::session(req, &config);
+
+        let connection = match database::connection(req) {
+            Ok(connection) => connection,
+            Err(res) => return Ok(res)
+        };
+        let locale = query::locale(req, &*connection, session);
+
+        let user = match session {
+            Some(session) => session.id,
+            None => {
+                let e = Error::unauthorized();
+                return Ok(graphql::refuse(status::Unauthorized, e, locale));
+            }
+        };
+
+        // Without a change, every field resolves to null, so this only
+        // checks that the subscription is valid.
+        let context = Context::new(
+            config.clone(),
+            templates.clone(),
+            payments.clone(),
+            connection,
+            session,
+            Locale::Known(locale),
+            None
+        );
+
+        let document = match graphql::document(
+            &self.persisted,
+            &config,
+            &context,
+            body.id,
+            body.query
+        ) {
+            Ok(document) => document,
+            Err(res) => return Ok(res)
+        };
+
+        let variables = body.variables.unwrap_or_default();
+        let operation_name = body.operation_name.as_ref().map(|x| x.as_str());
+        if let Err(e) = limits::check(
+            &*self.root,
+            &config,
+            &document,
+            operation_name,
+            &variables
+        ) {
+            return Ok(graphql::refuse(limits::status(&e), e, locale));
+        }
+
+        let query = match as_query(&document) {
+            Some(query) => query,
+            None => {
+                let e = Error::invalid("query", "only subscriptions can be run here");
+                return Ok(graphql::refuse(status::BadRequest, e, locale));
+            }
+        };
+
+        if let Err(e) = juniper::execute(
+            &query,
+            operation_name,
+            &*self.root,
+            &variables,
+            &context
+        ) {
+            return Ok(graphql::respond(status::BadRequest, &json!({ "errors": e })));
+        }
+
+        if let Err(e) = context.record(&document) {
+            error!("Failed to record an impersonated subscription: {}.", e);
+            return Ok(Response::with(status::InternalServerError));
+        }
+
+        let changes = match self.listener.subscribe(user) {
+            Ok(changes) => changes,
+            Err(Full::Everyone) => {
+                let e = Error::new(Code::TooManySubscriptions, "too many subscribers");
+                return Ok(graphql::refuse(status::ServiceUnavailable, e, locale));
+            },
+            Err(Full::User) => {
+                let e = Error::new(Code::TooManySubscriptions, "too many subscriptions");
+                return Ok(graphql::refuse(status::TooManyRequests, e, locale));
+            }
+        };
+
+        let stream = Stream {
+            root: self.root.clone(),
+            config: config,
+            templates: templates,
+            payments: payments,
+            database: database,
+            session: session,
+            locale: locale,
+            query: query,
+            operation_name: body.operation_name.clone(),
+            variables: variables,
+            changes: changes
+        };
+
+        let mime = "text/event-stream".parse::<Mime>().unwrap();
+        let mut res = Response::with((mime, status::Ok, Box::new(stream) as Box<WriteBody>));
+        res.headers.set(CacheControl(vec![CacheDirective::NoCache]));
+        Ok(res)
+    }
+}
+
+struct Stream {
+    root: Arc<SubscriptionRoot>,
+    config: Arc<Config>,
+    templates: Arc<Templates>,
+    payments: Arc<Payments>,
+    database: Database,
+    session: Option<Cookie>,
+    locale: &'static str,
+    query: String,
+    operation_name: Option<String>,
+    variables: Variables,
+    changes: Changes
+}
+
+impl WriteBody for Stream {
+    fn write_body(&mut self, res: &mut Write) -> io::Result<()> {
+        loop {
+            let change = match self.changes.changes.recv_timeout(Duration::from_secs(KEEPALIVE)) {
+                Ok(change) => change,
+                Err(RecvTimeoutError::Timeout) => {
+                    // Fails once the subscriber's gone, which ends the stream.
+                    res.write_all(b": keepalive\n\n")?;
+                    res.flush()?;
+                    continue;
+                },
+                Err(RecvTimeoutError::Disconnected) => return Ok(())
+            };
+
+            // Rather than carry on as nobody, make them subscribe again.
+            if self.session.map_or(false, |x| !x.valid()) {
+                res.write_all(b"event: complete\ndata: {}\n\n")?;
+                return res.flush();
+            }
+
+            let database = match self.database.get() {
+                Ok(database) => database,
+                Err(e) => {
+                    error!("Could not get a connection for a subscription: {}.", e);
+                    continue;
+                }
+            };
+
+            let context = Context::new(
+                self.config.clone(),
+                self.templates.clone(),
+                self.payments.clone(),
+                database,
+                self.session,
+                Locale::Known(self.locale),
+                Some(change)
+            );
+
+            let result = juniper::execute(
+                &self.query,
+                self.operation_name.as_ref().map(|x| x.as_str()),
+                &*self.root,
+                &self.variables,
+                &context
+            );
+
+            let payload = match result {
+                Ok((ref data, ref errors)) if errors.is_empty() => {
+                    if nothing(data) {
+                        continue;
+                    }
+                    json!({ "data": data })
+                },
+                Ok((data, errors)) => {
+                    let errors = error::describe(&errors, self.locale);
+                    json!({ "data": data, "errors": errors })
+                },
+                Err(e) => json!({ "errors": e })
+            };
+
+            write!(res, "event: next\ndata: {}\n\n", serde_json::to_string(&payload).unwrap())?;
+            res.flush()?;
+        }
+    }
+}
+
+/// Whether none of the subscription's fields had anything to say.
+fn nothing(data: &Value) -> bool {
+    data.as_object_value()
+        .map_or(false, |fields| fields.values().all(Value::is_null))
+}
+
+/// The document with its subscriptions turned into queries, so juniper
+/// will run them, or None if it has any other kind of operation.
+fn as_query(document: &str) -> Option<String> {
+    let mut query = String::with_capacity(document.len());
+    let mut copied = 0;
+    let mut depth = 0;
+    let mut start = true; // Whether the next token starts a definition.
+
+    for token in Lexer::new(document) {
+        let token = match token {
+            Ok(token) => token,
+            // Let juniper describe the syntax error.
+            Err(_) => return Some(document.to_owned())
+        };
+
+        let definition = depth == 0 && start;
+        match token.item {
+            Token::EndOfFile => break,
+            Token::Name("subscription") if definition => {
+                query.push_str(&document[copied..token.start.index()]);
+                query.push_str("query");
+                copied = token.end.index();
+            },
+            Token::Name("fragment") if definition => {},
+            _ if definition => return None,
+            Token::CurlyOpen | Token::ParenOpen | Token::BracketOpen => depth += 1,
+            Token::CurlyClose | Token::ParenClose | Token::BracketClose => depth -= 1,
+            _ => {}
+        }
+
+        start = depth == 0 && token.item == Token::CurlyClose;
+    }
+
+    query.push_str(&document[copied..]);
+    Some(query)
+}
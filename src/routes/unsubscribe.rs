@@ -0,0 +1,123 @@
+use auth;
+use config::Config;
+use database;
+use email::Category;
+use email::preferences;
+use iron::mime::Mime;
+use iron::prelude::*;
+use iron::status;
+use persistent::Read;
+use std::collections::HashMap;
+
+/// GET /unsubscribe
+/// Query:
+///     The same as for POST /unsubscribe.
+/// Response:
+///     A page asking whether to unsubscribe, with a button that posts back
+///     here. Nothing changes yet, since link scanners follow links in
+///     emails without anyone clicking them.
+/// Status Codes:
+///     200: Here's the page.
+///     400: Something's missing from the query.
+///     422: The code was invalid.
+pub fn confirm(req: &mut Request) -> IronResult<Response> {
+    let (user, category) = match verify(req) {
+        Ok(found) => found,
+        Err(res) => return Ok(res)
+    };
+
+    let config = req.extensions.get::<Read<Config>>().unwrap();
+    let link = preferences::unsubscribe_link(config, user, category);
+
+    let mime = "text/html; charset=utf-8".parse::<Mime>().unwrap();
+    let page = format!(
+        "<!DOCTYPE html>\n\
+         <title>Unsubscribe</title>\n\
+         <form method=\"post\" action=\"{}\">\n\
+         <p>Stop sending you {} emails?</p>\n\
+         <button type=\"submit\">Unsubscribe</button>\n\
+         </form>\n",
+        link.replace('&', "&amp;"),
+        category.as_str()
+    );
+
+    Ok(Response::with((mime, status::Ok, page)))
+}
+
+/// POST /unsubscribe
+/// Query:
+///     user: The user's numeric ID.
+///     category: The kind of email to stop sending, such as `announcements`.
+///     code: The hexadecimal code from the link in the email.
+/// Response:
+///     A short note saying it worked.
+/// Status Codes:
+///     200: The user won't be sent emails in the category any more.
+///     400: Something's missing from the query.
+///     422: The code was invalid.
+///     500: The preference couldn't be saved.
+///     503: There wasn't a database connection to spare.
+pub fn unsubscribe(req: &mut Request) -> IronResult<Response> {
+    let (user, category) = match verify(req) {
+        Ok(found) => found,
+        Err(res) => return Ok(res)
+    };
+
+    let db = match database::connection(req) {
+        Ok(db) => db,
+        Err(res) => return Ok(res)
+    };
+    match preferences::set(&db, user, category, false) {
+        Ok(()) => Ok(Response::with((
+            status::Ok,
+            format!("You won't get any more {} emails.", category.as_str())
+        ))),
+        Err(e) => {
+            error!("Could not unsubscribe user {}: {}.", user, e);
+            Ok(Response::with(status::InternalServerError))
+        }
+    }
+}
+
+/// The user and category from the query, if its code is right.
+fn verify(req: &Request) -> Result<(i64, Category), Response> {
+    let config = req.extensions.get::<Read<Config>>().unwrap();
+
+    // Nothing here ever needs decoding.
+    let query = req.url.query().unwrap_or("")
+        .split('&')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => Some((key, value)),
+                _ => None
+            }
+        })
+        .collect::<HashMap<_, _>>();
+
+    let user = query.get("user").and_then(|x| x.parse::<i64>().ok());
+    let category = query.get("category").and_then(|x| Category::from_str(x));
+    let code = query.get("code").and_then(|x| hex(x));
+    let (user, category, code) = match (user, category, code) {
+        (Some(user), Some(category), Some(code)) => (user, category, code),
+        _ => return Err(Response::with(status::BadRequest))
+    };
+
+    if !auth::verify_unsubscribe(config.secret, user, category.as_str(), code) {
+        return Err(Response::with(status::UnprocessableEntity));
+    }
+
+    Ok((user, category))
+}
+
+fn hex(code: &str) -> Option<[u8; 32]> {
+    if code.len() != 64 {
+        return None;
+    }
+
+    let mut res = [0; 32];
+    for (i, n) in res.iter_mut().enumerate() {
+        *n = u8::from_str_radix(code.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(res)
+}
@@ -10,6 +10,10 @@ table! {
         password -> Binary,
         training_location -> Nullable<BigInt>,
         role -> BigInt,
+        belt -> BigInt,
+        registered -> Timestamptz,
+        uuid -> Uuid,
+        locale -> Text,
     }
 }
 
@@ -20,6 +24,9 @@ table! {
         address -> Text,
         lat -> Double,
         lng -> Double,
+        public -> Bool,
+        uuid -> Uuid,
+        time_zone -> Text,
     }
 }
 
@@ -28,6 +35,178 @@ table! {
         id -> BigInt,
         instructor_id -> BigInt,
         location_id -> BigInt,
+        valid_from -> Timestamptz,
+        valid_until -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    role_grants {
+        id -> BigInt,
+        user_id -> BigInt,
+        role -> BigInt,
+        valid_from -> Timestamptz,
+        valid_until -> Timestamptz,
+        uuid -> Uuid,
+    }
+}
+
+table! {
+    impersonation_log {
+        id -> BigInt,
+        admin_id -> BigInt,
+        user_id -> BigInt,
+        query -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    persisted_queries (hash) {
+        hash -> Text,
+        query -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    outbox {
+        id -> BigInt,
+        recipient -> Text,
+        subject -> Text,
+        html -> Text,
+        text -> Text,
+        status -> Text,
+        attempts -> Integer,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamptz,
+        next_attempt -> Timestamptz,
+        sent_at -> Nullable<Timestamptz>,
+        unsubscribe -> Nullable<Text>,
+        uuid -> Uuid,
+    }
+}
+
+table! {
+    announcements {
+        id -> BigInt,
+        author_id -> Nullable<BigInt>,
+        title -> Text,
+        body -> Text,
+        location_id -> Nullable<BigInt>,
+        role -> Nullable<BigInt>,
+        created_at -> Timestamptz,
+        uuid -> Uuid,
+    }
+}
+
+table! {
+    notification_preferences (user_id, category) {
+        user_id -> BigInt,
+        category -> Text,
+        enabled -> Bool,
+    }
+}
+
+table! {
+    classes {
+        id -> BigInt,
+        location_id -> BigInt,
+        instructor_id -> Nullable<BigInt>,
+        title -> Text,
+        level -> Text,
+        weekday -> Integer,
+        starts_at -> Time,
+        duration -> Integer,
+        valid_from -> Date,
+        valid_until -> Nullable<Date>,
+        uuid -> Uuid,
+    }
+}
+
+table! {
+    class_cancellations (class_id, date) {
+        class_id -> BigInt,
+        date -> Date,
+        reason -> Nullable<Text>,
+    }
+}
+
+table! {
+    attendance (class_id, date, user_id) {
+        class_id -> BigInt,
+        date -> Date,
+        user_id -> BigInt,
+        marked_by -> Nullable<BigInt>,
+        marked_at -> Timestamptz,
+    }
+}
+
+table! {
+    membership_plans {
+        id -> BigInt,
+        name -> Text,
+        kind -> Text,
+        price -> BigInt,
+        currency -> Text,
+        months -> Integer,
+        max_members -> Integer,
+        active -> Bool,
+        uuid -> Uuid,
+    }
+}
+
+table! {
+    memberships {
+        id -> BigInt,
+        plan_id -> BigInt,
+        payer_id -> BigInt,
+        starts_on -> Date,
+        ends_on -> Nullable<Date>,
+        uuid -> Uuid,
+    }
+}
+
+table! {
+    membership_members (membership_id, user_id) {
+        membership_id -> BigInt,
+        user_id -> BigInt,
+    }
+}
+
+table! {
+    invoices {
+        id -> BigInt,
+        membership_id -> BigInt,
+        amount -> BigInt,
+        currency -> Text,
+        period_start -> Date,
+        period_end -> Date,
+        due_on -> Date,
+        created_at -> Timestamptz,
+        paid_at -> Nullable<Timestamptz>,
+        uuid -> Uuid,
+    }
+}
+
+table! {
+    payments {
+        id -> BigInt,
+        invoice_id -> BigInt,
+        amount -> BigInt,
+        provider -> Nullable<Text>,
+        reference -> Nullable<Text>,
+        recorded_by -> Nullable<BigInt>,
+        paid_at -> Timestamptz,
+        uuid -> Uuid,
+    }
+}
+
+table! {
+    calendar_tokens (user_id) {
+        user_id -> BigInt,
+        token -> Binary,
+        created_at -> Timestamptz,
     }
 }
 
@@ -36,3 +215,21 @@ sql_function!(
     LowerT,
     (a: Text) -> Text
 );
+
+sql_function!(
+    timezone,
+    TimezoneT,
+    (zone: Text, time: Timestamp) -> Timestamptz
+);
+
+sql_function!(
+    in_time_zone,
+    InTimeZoneT,
+    (zone: Text, times: Array<Timestamp>) -> Array<Timestamptz>
+);
+
+sql_function!(
+    set_config,
+    SetConfigT,
+    (setting: Text, value: Text, local: Bool) -> Text
+);